@@ -0,0 +1,25 @@
+/*
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// Submit/complete round trip overhead, using NOPs
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use iouring::io_uring;
+
+const ITERS: u32 = 1024;
+
+fn roundtrip(c: &mut Criterion) {
+    let mut group = c.benchmark_group("roundtrip");
+    group.throughput(Throughput::Elements(ITERS as u64));
+    for &entries in &[1, 8, 64] {
+        let mut ior = io_uring::IoUring::init(entries).unwrap();
+        group.bench_function(format!("nop/{}", entries), |b| {
+            b.iter(|| ior.bench_roundtrip(ITERS).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, roundtrip);
+criterion_main!(benches);
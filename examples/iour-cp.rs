@@ -6,14 +6,14 @@
 
 // cp using io_uring, following liburing/examples/io_uring-cp.c
 
-use libc;
 use iouring::io_uring;
 
 // use std::ops::Deref;
 use std::convert::TryInto;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 const QD : usize = 64;
-const BS : usize = (32*1024);
+const BS : usize = 32*1024;
 
 // rust uses IoSlice for write_vectored and IoSliceMut for read_vectored.
 // _Both_ are guaranteed to be ABI compatible with iovec.
@@ -32,24 +32,43 @@ const BS : usize = (32*1024);
 
 /// Buffer for performing IO
 struct IoBuff {
+    read: bool,
     off: usize,
-    size: usize,
     buff: Vec<u8>,
     iov: libc::iovec,
 }
 
 impl IoBuff {
 
-    pub fn new(size: usize, off: usize) -> IoBuff {
-        IoBuff {
-            off: off,
-            size: size,
-            buff: Vec::with_capacity(size),
-            iov: libc::iovec {
-                iov_base: buff.as_mut_ptr() as *mut libc::c_void,
-                iov_len: size,
-            },
-        }
+    pub fn new(size: usize, off: usize) -> Box<IoBuff> {
+        let mut buff = vec![0; size];
+        let iov = libc::iovec {
+            iov_base: buff.as_mut_ptr() as *mut libc::c_void,
+            iov_len: size,
+        };
+        // NB: moving the Vec does not move its heap buffer, so iov remains valid
+        Box::new(IoBuff {
+            read: true,
+            off,
+            buff,
+            iov,
+        })
+    }
+
+    /// skip the first n bytes of the remaining IO (used for short reads/writes)
+    pub fn advance(&mut self, n: usize) {
+        self.iov.iov_base = unsafe { (self.iov.iov_base as *mut u8).add(n) } as *mut libc::c_void;
+        self.iov.iov_len -= n;
+        self.off += n;
+    }
+
+    /// turn a completed read into a write of the same data
+    pub fn set_write(&mut self) {
+        let len = self.buff.len();
+        self.read = false;
+        self.off -= len;
+        self.iov.iov_base = self.buff.as_mut_ptr() as *mut libc::c_void;
+        self.iov.iov_len = len;
     }
 }
 
@@ -68,7 +87,6 @@ fn get_file_size(f: &std::fs::File) -> std::io::Result<usize>  {
         (m & libc::S_IFMT) == libc::S_IFBLK
     };
 
-    use std::os::unix::io::AsRawFd;
     let fd = f.as_raw_fd();
 
     let st: libc::stat  = unsafe {
@@ -81,7 +99,7 @@ fn get_file_size(f: &std::fs::File) -> std::io::Result<usize>  {
     };
 
     if s_isreg(st.st_mode) {
-       return Ok(st.st_size as usize)
+        Ok(st.st_size as usize)
     } else if s_isblk(st.st_mode) {
         let mut bytes: libc::c_ulonglong = 0;
         let err = unsafe { libc::ioctl(fd, IOC_BLKGETSIZE64, &mut bytes) };
@@ -91,53 +109,85 @@ fn get_file_size(f: &std::fs::File) -> std::io::Result<usize>  {
             Err(std::io::Error::last_os_error())
         }
     } else {
-        Err(std::io::Error::new(std::io::ErrorKind::Other, "Cannot determine file size"))
+        Err(std::io::Error::other("Cannot determine file size"))
     }
 }
 
-fn queue_read(ior: &mut io_uring::IoUring, fd: RawFd, size: usize, off: usize) -> Option<()> {
+/// queue the IO described by iodata. Ownership of iodata is passed to the ring via user_data.
+fn queue_rw(ior: &mut io_uring::IoUring, fd: RawFd, iodata: Box<IoBuff>) -> Option<()> {
 
     // allocate entry in the submission queue
-    let mut sqe = match ior.get_sqe() {
-        Some(x) => x,
-        None => return None,
-    };
+    let mut sqe = ior.get_sqe()?;
 
-    let iodata = Box::new(IoBuff::new(size, off));
-    sqe.prep_readv(fd, &iodata.iov, 1, off.try_into().unwrap());
+    let off = iodata.off.try_into().unwrap();
+    if iodata.read {
+        sqe.prep_readv(fd, &iodata.iov, 1, off);
+    } else {
+        sqe.prep_writev(fd, &iodata.iov, 1, off);
+    }
     let iodata_ptr = Box::into_raw(iodata) as u64;
     sqe.set_data(iodata_ptr);
     Some(())
 }
 
-fn copy_file(ior: &io_uring::IoUring, infd: RawFd, insize: usize, outfd: RawFd) -> std::io::Result<()> {
+fn copy_file(ior: &mut io_uring::IoUring, infd: RawFd, insize: usize, outfd: RawFd) -> std::io::Result<()> {
     let mut rd_issued: usize = 0;
-    let mut rd_done: usize = 0;
-    let mut wr_issued: usize = 0;
     let mut wr_done: usize = 0;
+    let mut inflight: usize = 0;
 
     while wr_done < insize {
 
         // queue as many read requests as possible
-        let mut rd_queued = 0;
-        while rd_issued < insize {
+        while rd_issued < insize && inflight < QD {
             let rd_size = std::cmp::min(insize - rd_issued, BS);
             let rd_off = rd_issued;
-            match queue_read(ior, infd, rd_size, rd_off) {
+            match queue_rw(ior, infd, IoBuff::new(rd_size, rd_off)) {
                 None => break,
                 Some(()) => {
                     rd_issued += rd_size;
-                    rd_queued++;
+                    inflight += 1;
                 },
             }
         }
 
-        // submit the read requests enqueued (if any)
-        if rd_queued > 0 {
-            ior.submit()?
+        // submit the requests enqueued (if any)
+        ior.submit()?;
+
+        // NB: we cannot queue new requests from within the callback, since it borrows the ring.
+        let mut done = vec![];
+        ior.for_each_completion(|cqe| {
+            let iodata = unsafe { Box::from_raw(cqe.user_data() as *mut IoBuff) };
+            done.push((iodata, cqe.res()));
+        });
+
+        for (mut iodata, res) in done {
+            inflight -= 1;
+            if res < 0 {
+                return Err(std::io::Error::from_raw_os_error(-res));
+            } else if res == 0 {
+                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Short copy"));
+            }
+
+            let res = res as usize;
+            if res < iodata.iov.iov_len {
+                // short read/write: queue the remaining IO
+                iodata.advance(res);
+            } else if iodata.read {
+                iodata.advance(res);
+                iodata.set_write();
+            } else {
+                wr_done += iodata.buff.len();
+                continue;
+            }
+
+            let fd = if iodata.read { infd } else { outfd };
+            // NB: we never have more than QD requests in flight, so there is always an sqe
+            queue_rw(ior, fd, iodata).unwrap();
+            inflight += 1;
         }
     }
 
+    Ok(())
 }
 
 pub fn main() {
@@ -174,7 +224,7 @@ pub fn main() {
     };
 
 
-    let iour = match io_uring::IoUring::init(QD) {
+    let mut iour = match io_uring::IoUring::init(QD.try_into().unwrap()) {
         Ok(x) => x,
         Err(e) => {
             eprintln!("Failed to initialize io_uring: {}", e);
@@ -190,6 +240,8 @@ pub fn main() {
         }
     };
 
-    println!("insize={}", insize);
-    println!("iodata::capacity={}", IoData::MAX_SIZE);
+    if let Err(e) = copy_file(&mut iour, fin.as_raw_fd(), insize, fout.as_raw_fd()) {
+        eprintln!("Copy failed: {}", e);
+        std::process::exit(-1);
+    }
 }
@@ -9,9 +9,7 @@
 // git://git.kernel.dk/liburing
 //
 // TODO:
-//  - do the cp example
 //  - port all io_uring_prep functions from liburing.h
-//  - a configuration to pass to init()
 //
 
 use libc;
@@ -23,7 +21,7 @@ use std::convert::{TryFrom,TryInto};
 
 use backtrace::Backtrace;
 
-/**
+/*
  * io_uring ABI
  */
 
@@ -44,6 +42,45 @@ const IORING_OFF_SQ_RING: i64 = 0;
 const IORING_OFF_CQ_RING: i64 = 0x08000000;
 const IORING_OFF_SQES:    i64 = 0x10000000;
 
+/*
+ * io_uring_register opcodes
+ */
+pub const IORING_REGISTER_BUFFERS       : libc::c_uint = 0;
+pub const IORING_UNREGISTER_BUFFERS     : libc::c_uint = 1;
+pub const IORING_REGISTER_FILES         : libc::c_uint = 2;
+pub const IORING_UNREGISTER_FILES       : libc::c_uint = 3;
+pub const IORING_REGISTER_EVENTFD       : libc::c_uint = 4;
+pub const IORING_UNREGISTER_EVENTFD     : libc::c_uint = 5;
+pub const IORING_REGISTER_FILES_UPDATE  : libc::c_uint = 6;
+pub const IORING_REGISTER_EVENTFD_ASYNC : libc::c_uint = 7;
+pub const IORING_REGISTER_PROBE         : libc::c_uint = 8;
+pub const IORING_REGISTER_PERSONALITY   : libc::c_uint = 9;
+pub const IORING_UNREGISTER_PERSONALITY : libc::c_uint = 10;
+pub const IORING_REGISTER_RESTRICTIONS  : libc::c_uint = 11;
+pub const IORING_REGISTER_ENABLE_RINGS  : libc::c_uint = 12;
+pub const IORING_REGISTER_FILES2        : libc::c_uint = 13;
+pub const IORING_REGISTER_FILES_UPDATE2 : libc::c_uint = 14;
+pub const IORING_REGISTER_BUFFERS2      : libc::c_uint = 15;
+pub const IORING_REGISTER_BUFFERS_UPDATE: libc::c_uint = 16;
+pub const IORING_REGISTER_IOWQ_AFF      : libc::c_uint = 17;
+pub const IORING_UNREGISTER_IOWQ_AFF    : libc::c_uint = 18;
+pub const IORING_REGISTER_IOWQ_MAX_WORKERS: libc::c_uint = 19;
+pub const IORING_REGISTER_RING_FDS      : libc::c_uint = 20;
+pub const IORING_UNREGISTER_RING_FDS    : libc::c_uint = 21;
+pub const IORING_REGISTER_PBUF_RING     : libc::c_uint = 22;
+pub const IORING_UNREGISTER_PBUF_RING   : libc::c_uint = 23;
+pub const IORING_REGISTER_SYNC_CANCEL   : libc::c_uint = 24;
+pub const IORING_REGISTER_FILE_ALLOC_RANGE: libc::c_uint = 25;
+pub const IORING_REGISTER_PBUF_STATUS   : libc::c_uint = 26;
+pub const IORING_REGISTER_NAPI          : libc::c_uint = 27;
+pub const IORING_UNREGISTER_NAPI        : libc::c_uint = 28;
+pub const IORING_REGISTER_CLOCK         : libc::c_uint = 29;
+pub const IORING_REGISTER_CLONE_BUFFERS : libc::c_uint = 30;
+pub const IORING_REGISTER_SEND_MSG_RING : libc::c_uint = 31;
+pub const IORING_REGISTER_ZCRX_IFQ      : libc::c_uint = 32;
+pub const IORING_REGISTER_RESIZE_RINGS  : libc::c_uint = 33;
+pub const IORING_REGISTER_MEM_REGION    : libc::c_uint = 34;
+
 
 type KernelRwf = libc::c_int;
 
@@ -57,33 +94,233 @@ union io_uring_sqe_args {
     rw_flags: KernelRwf,
     fsync_flags: u32,
     poll_events: u16,
+    poll32_events: u32,
     sync_range_flags: u32,
+    install_fd_flags: u32,
+    accept_flags: u32,
+    open_flags: u32,
+    timeout_flags: u32,
+    cancel_flags: u32,
+    msg_flags: u32,
+    splice_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct io_uring_sqe_idx_fields {
+    buf_index: u16,     /* index into fixed buffers, if used */
+    personality: u16,   /* personality to use, if used */
+    file_index: u32,    /* also used for splice_fd_in and waitid options */
+}
+
+/// Maximum size of the command data of IORING_OP_URING_CMD (for 64-byte sqes)
+pub const URING_CMD_MAX: usize = 16;
+/// Maximum size of the command data of IORING_OP_URING_CMD, for 128-byte sqes (see
+/// Builder::sqe128())
+pub const URING_CMD128_MAX: usize = URING_CMD_MAX + 64;
+/// Offset of the command data in the sqe
+const URING_CMD_OFF: usize = 48;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct io_uring_sqe_idx_cmd {
+    fields: io_uring_sqe_idx_fields,
+    cmd: [u8; URING_CMD_MAX],     /* command data for IORING_OP_URING_CMD */
 }
 
 #[repr(C)]
 union io_uring_sqe_idx {
     buf_index: u16,
+    fields: io_uring_sqe_idx_fields,
+    cmd: io_uring_sqe_idx_cmd,
     __pad2: [u64; 3],
 }
 
-const IORING_OP_NOP             : u8 = 0;
-const IORING_OP_READV           : u8 = 1;
-const IORING_OP_WRITEV          : u8 = 2;
-const IORING_OP_FSYNC           : u8 = 3;
-const IORING_OP_READ_FIXED      : u8 = 4;
-const IORING_OP_WRITE_FIXED     : u8 = 5;
-const IORING_OP_POLL_ADD        : u8 = 6;
-const IORING_OP_POLL_REMOVE     : u8 = 7;
-const IORING_OP_SYNC_FILE_RANGE : u8 = 8;
-const IORING_OP_SENDMSG         : u8 = 9;
-const IORING_OP_RECVMSG         : u8 = 10;
+pub const IORING_OP_NOP             : u8 = 0;
+pub const IORING_OP_READV           : u8 = 1;
+pub const IORING_OP_WRITEV          : u8 = 2;
+pub const IORING_OP_FSYNC           : u8 = 3;
+pub const IORING_OP_READ_FIXED      : u8 = 4;
+pub const IORING_OP_WRITE_FIXED     : u8 = 5;
+pub const IORING_OP_POLL_ADD        : u8 = 6;
+pub const IORING_OP_POLL_REMOVE     : u8 = 7;
+pub const IORING_OP_SYNC_FILE_RANGE : u8 = 8;
+pub const IORING_OP_SENDMSG         : u8 = 9;
+pub const IORING_OP_RECVMSG         : u8 = 10;
+pub const IORING_OP_TIMEOUT         : u8 = 11;
+pub const IORING_OP_TIMEOUT_REMOVE  : u8 = 12;
+pub const IORING_OP_ACCEPT          : u8 = 13;
+pub const IORING_OP_ASYNC_CANCEL    : u8 = 14;
+pub const IORING_OP_LINK_TIMEOUT    : u8 = 15;
+pub const IORING_OP_CONNECT         : u8 = 16;
+pub const IORING_OP_FALLOCATE       : u8 = 17;
+pub const IORING_OP_OPENAT          : u8 = 18;
+pub const IORING_OP_CLOSE           : u8 = 19;
+pub const IORING_OP_FILES_UPDATE    : u8 = 20;
+pub const IORING_OP_STATX           : u8 = 21;
+pub const IORING_OP_READ            : u8 = 22;
+pub const IORING_OP_WRITE           : u8 = 23;
+pub const IORING_OP_FADVISE         : u8 = 24;
+pub const IORING_OP_MADVISE         : u8 = 25;
+pub const IORING_OP_SEND            : u8 = 26;
+pub const IORING_OP_RECV            : u8 = 27;
+pub const IORING_OP_OPENAT2         : u8 = 28;
+pub const IORING_OP_EPOLL_CTL       : u8 = 29;
+pub const IORING_OP_SPLICE          : u8 = 30;
+pub const IORING_OP_PROVIDE_BUFFERS : u8 = 31;
+pub const IORING_OP_REMOVE_BUFFERS  : u8 = 32;
+pub const IORING_OP_TEE             : u8 = 33;
+pub const IORING_OP_SHUTDOWN        : u8 = 34;
+pub const IORING_OP_RENAMEAT        : u8 = 35;
+pub const IORING_OP_UNLINKAT        : u8 = 36;
+pub const IORING_OP_MKDIRAT         : u8 = 37;
+pub const IORING_OP_SYMLINKAT       : u8 = 38;
+pub const IORING_OP_LINKAT          : u8 = 39;
+pub const IORING_OP_MSG_RING        : u8 = 40;
+pub const IORING_OP_FSETXATTR       : u8 = 41;
+pub const IORING_OP_SETXATTR        : u8 = 42;
+pub const IORING_OP_FGETXATTR       : u8 = 43;
+pub const IORING_OP_GETXATTR        : u8 = 44;
+pub const IORING_OP_SOCKET          : u8 = 45;
+pub const IORING_OP_URING_CMD       : u8 = 46;
+pub const IORING_OP_SEND_ZC         : u8 = 47;
+pub const IORING_OP_SENDMSG_ZC      : u8 = 48;
+pub const IORING_OP_READ_MULTISHOT  : u8 = 49;
+pub const IORING_OP_WAITID          : u8 = 50;
+pub const IORING_OP_FUTEX_WAIT      : u8 = 51;
+pub const IORING_OP_FUTEX_WAKE      : u8 = 52;
+pub const IORING_OP_FUTEX_WAITV     : u8 = 53;
+pub const IORING_OP_FIXED_FD_INSTALL: u8 = 54;
+pub const IORING_OP_FTRUNCATE       : u8 = 55;
+pub const IORING_OP_BIND            : u8 = 56;
+pub const IORING_OP_LISTEN          : u8 = 57;
+pub const IORING_OP_RECV_ZC         : u8 = 58;
+pub const IORING_OP_EPOLL_WAIT      : u8 = 59;
+pub const IORING_OP_READV_FIXED     : u8 = 60;
+pub const IORING_OP_WRITEV_FIXED    : u8 = 61;
+pub const IORING_OP_PIPE            : u8 = 62;
 const IORING_OP_INVALID         : u8 = 250; // Not part of the ABI, used internally
 
+const OPCODE_NAMES: [&str; IORING_OP_PIPE as usize + 1] = [
+    "NOP", "READV", "WRITEV", "FSYNC", "READ_FIXED", "WRITE_FIXED", "POLL_ADD", "POLL_REMOVE",
+    "SYNC_FILE_RANGE", "SENDMSG", "RECVMSG", "TIMEOUT", "TIMEOUT_REMOVE", "ACCEPT",
+    "ASYNC_CANCEL", "LINK_TIMEOUT", "CONNECT", "FALLOCATE", "OPENAT", "CLOSE", "FILES_UPDATE",
+    "STATX", "READ", "WRITE", "FADVISE", "MADVISE", "SEND", "RECV", "OPENAT2", "EPOLL_CTL",
+    "SPLICE", "PROVIDE_BUFFERS", "REMOVE_BUFFERS", "TEE", "SHUTDOWN", "RENAMEAT", "UNLINKAT",
+    "MKDIRAT", "SYMLINKAT", "LINKAT", "MSG_RING", "FSETXATTR", "SETXATTR", "FGETXATTR",
+    "GETXATTR", "SOCKET", "URING_CMD", "SEND_ZC", "SENDMSG_ZC", "READ_MULTISHOT", "WAITID",
+    "FUTEX_WAIT", "FUTEX_WAKE", "FUTEX_WAITV", "FIXED_FD_INSTALL", "FTRUNCATE", "BIND", "LISTEN",
+    "RECV_ZC", "EPOLL_WAIT", "READV_FIXED", "WRITEV_FIXED", "PIPE",
+];
+
+/// Name of operation op (IORING_OP_*), e.g., "READV", for diagnostics
+///
+/// Unknown operations are named "UNKNOWN(op)".
+pub fn opcode_name(op: u8) -> &'static str {
+    static UNKNOWN: std::sync::OnceLock<Vec<String>> = std::sync::OnceLock::new();
+    match OPCODE_NAMES.get(op as usize) {
+        Some(name) => name,
+        None => {
+            let unknown = UNKNOWN.get_or_init(|| (0..=u8::MAX).map(|op| format!("UNKNOWN({})", op)).collect());
+            &unknown[op as usize]
+        }
+    }
+}
+
+/// Flags for prep_poll_update()
+pub const IORING_POLL_ADD_MULTI       : u32 = 1 << 0; // multishot poll
+pub const IORING_POLL_UPDATE_EVENTS   : u32 = 1 << 1; // update the event mask
+pub const IORING_POLL_UPDATE_USER_DATA: u32 = 1 << 2; // update the user data
+pub const IORING_POLL_ADD_LEVEL       : u32 = 1 << 3; // level triggered poll
+
+/// Flag for prep_timeout() and prep_link_timeout(): ts is an absolute time, instead of relative
+/// to submission
+pub const IORING_TIMEOUT_ABS: u32 = 1 << 0;
+/// Timeout clock flags (see TimeoutClock)
+pub const IORING_TIMEOUT_BOOTTIME: u32 = 1 << 2;
+pub const IORING_TIMEOUT_REALTIME: u32 = 1 << 3;
+
+/// Clock of a timeout (see SQEntry::prep_timeout())
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutClock {
+    /// CLOCK_MONOTONIC (the default)
+    Monotonic,
+    /// CLOCK_BOOTTIME, which includes time spent suspended (5.15+)
+    Boottime,
+    /// CLOCK_REALTIME, for wall-clock absolute deadlines (5.15+)
+    Realtime,
+}
+
+/// user_data of the link timeouts of IoUring::submit_with_deadline()
+pub const DEADLINE_USER_DATA: u64 = u64::MAX;
+
+/// user_data of the cancellation requests of IoUring::cancel_owned()
+pub const CANCEL_USER_DATA: u64 = u64::MAX - 1;
+
+/// user_data of the timeouts of IoUring::wait_cqe_timeout(), on kernels without EXT_ARG
+pub const TIMEOUT_USER_DATA: u64 = u64::MAX - 2;
+
+/// Lowest user_data reserved for the sqes the ring prepares itself (see SQEntry::set_data())
+pub const RESERVED_USER_DATA: u64 = TIMEOUT_USER_DATA;
+
+/// Flag for prep_splice(): fd_in is a registered (fixed) file
+pub const SPLICE_F_FD_IN_FIXED: u32 = 1 << 31;
+
+/// Offset for prep_splice(), for pipes and for using the file position
+pub const SPLICE_OFF_NONE: i64 = -1;
+
+/// Flag for prep_fsync(): only sync data, like fdatasync(2)
+const IORING_FSYNC_DATASYNC: u32 = 1 << 0;
+
+/// Maximum number of registered (fixed) files
+pub const IORING_MAX_FIXED_FILES: usize = 1 << 20;
+
+/// File index for prep_accept_direct(): allocate a free slot in the fixed file table (5.19+)
+pub const IORING_FILE_INDEX_ALLOC: u32 = !0;
+
+/// Flag for prep_fixed_fd_install(): do not set O_CLOEXEC on the installed fd
+pub const IORING_FIXED_FD_NO_CLOEXEC: u32 = 1 << 0;
+
+/// ioprio flag for prep_multishot_accept(): post a cqe for every accepted connection
+const IORING_ACCEPT_MULTISHOT: u16 = 1 << 0;
+
+/// ioprio flag for prep_recv_bundle(): consume multiple provided buffers in one recv (6.10+)
+const IORING_RECVSEND_BUNDLE: u16 = 1 << 4;
+
+/// Offset for read/write operations that means: use (and update) the current file position
+///
+/// Supported since 5.6 (IORING_FEAT_RW_CUR_POS). For non-seekable files (e.g., pipes or sockets)
+/// the offset is ignored, and this value is not needed.
+pub const OFF_CURRENT_POS: u64 = u64::MAX;
+
+/// cqe flags (see io_uring_cqe::flags())
+pub const IORING_CQE_F_BUFFER       : u32 = 1 << 0; // upper 16 bits are the buffer id
+pub const IORING_CQE_F_MORE         : u32 = 1 << 1; // more cqes will be posted for this sqe
+pub const IORING_CQE_F_SOCK_NONEMPTY: u32 = 1 << 2; // more data to read on the socket
+pub const IORING_CQE_F_NOTIF        : u32 = 1 << 3; // zero-copy send notification
+pub const IORING_CQE_F_BUF_MORE     : u32 = 1 << 4; // the buffer will be used by more cqes
+
+/// io_uring_enter() flags, for IoUring::enter()
+pub const IORING_ENTER_GETEVENTS: u32 = 1 << 0; // wait for min_complete cqes
+pub const IORING_ENTER_SQ_WAKEUP: u32 = 1 << 1; // wake up the SQPOLL thread
+pub const IORING_ENTER_SQ_WAIT  : u32 = 1 << 2; // wait for SQ space (SQPOLL)
+
+/// IO priority classes for SQEntry::set_ioprio() (see ioprio_set(2))
+pub const IOPRIO_CLASS_NONE: u8 = 0;
+pub const IOPRIO_CLASS_RT   : u8 = 1; // requires CAP_SYS_ADMIN (or CAP_SYS_NICE)
+pub const IOPRIO_CLASS_BE   : u8 = 2;
+pub const IOPRIO_CLASS_IDLE : u8 = 3;
+const IOPRIO_CLASS_SHIFT    : u16 = 13;
+
 bitflags::bitflags!{
     struct SqeFlags: u8 {
         const FIXED_FILE    = 1 << 0; // use fixed fileset
         const IO_DRAIN      = 1 << 1; // issue after inflight IO
         const IO_LINK       = 1 << 2; // links next sqe
+        const IO_HARDLINK   = 1 << 3; // like LINK, but stronger
+        const ASYNC         = 1 << 4; // always go async
+        const BUFFER_SELECT = 1 << 5; // select buffer from buffer group
+        const CQE_SKIP_SUCCESS = 1 << 6; // don't post a cqe if the request succeeded
     }
 }
 
@@ -93,12 +330,27 @@ bitflags::bitflags!{
         const SQPOLL = 1 << 1; // SQ poll thread
         const SQ_AFF = 1 << 2; // sq_thread_cpu is valid
         const CQSIZE = 1 << 3; // app defined CQ size
+        const CLAMP  = 1 << 4; // clamp SQ/CQ ring sizes
+        const ATTACH_WQ  = 1 << 5; // attach to existing wq
+        const R_DISABLED = 1 << 6; // start with ring disabled
+        const SUBMIT_ALL = 1 << 7; // continue submit on error
+        const COOP_TASKRUN  = 1 << 8; // no IPI for task work, run it on transitions
+        const TASKRUN_FLAG  = 1 << 9; // set IORING_SQ_TASKRUN when task work is pending
+        const SQE128        = 1 << 10; // 128-byte sqes
+        const CQE32         = 1 << 11; // 32-byte cqes
+        const SINGLE_ISSUER = 1 << 12; // only one task submits requests
+        const DEFER_TASKRUN = 1 << 13; // defer task work until GETEVENTS
+        const NO_MMAP       = 1 << 14; // application provides the ring memory
+        const REGISTERED_FD_ONLY = 1 << 15; // return a registered ring fd
+        const NO_SQARRAY    = 1 << 16; // no SQ index array
     }
 }
 
 bitflags::bitflags!{
     struct SQFlags: u32 {
         const NEED_WAKEUP = 1 << 0; // needs io_uring_enter wakeup
+        const CQ_OVERFLOW = 1 << 1; // CQ ring is overflown
+        const TASKRUN     = 1 << 2; // task should enter the kernel
     }
 }
 
@@ -106,11 +358,41 @@ bitflags::bitflags!{
     struct EnterFlags: libc::c_uint {
         const GETEVENTS = 1<<0;
         const SQ_WAKEUP = 1<<1;
+        const SQ_WAIT   = 1<<2;
+        const EXT_ARG   = 1<<3; // the argument is an io_uring_getevents_arg
+        const REGISTERED_RING = 1<<4; // the fd is a registered ring index
+    }
+}
+
+bitflags::bitflags!{
+    // features reported by the kernel in io_uring_params
+    struct FeatureFlags: u32 {
+        const SINGLE_MMAP     = 1<<0;
+        const NODROP          = 1<<1;
+        const SUBMIT_STABLE   = 1<<2;
+        const RW_CUR_POS      = 1<<3;
+        const CUR_PERSONALITY = 1<<4;
+        const FAST_POLL       = 1<<5;
+        const POLL_32BITS     = 1<<6;
+        const SQPOLL_NONFIXED = 1<<7;
+        const EXT_ARG         = 1<<8;
+        const NATIVE_WORKERS  = 1<<9;
+        const RSRC_TAGS       = 1<<10;
+        const CQE_SKIP        = 1<<11;
+        const LINKED_FILE     = 1<<12;
+        const REG_REG_RING    = 1<<13;
+        const RECVSEND_BUNDLE = 1<<14;
+        const MIN_TIMEOUT     = 1<<15;
+        const RW_ATTR         = 1<<16;
+        const NO_IOWAIT       = 1<<17;
     }
 }
 
+/// A submission queue entry, e.g., for building sqes for IoUring::push_raw()
+///
+/// Fields are set with the with_*() builder methods, or all at once with from_bytes().
 #[repr(C)]
-struct io_uring_sqe {
+pub struct io_uring_sqe {
     opcode: u8,                /* type of operation for this sqe */
     flags: u8,                 /* IOSQE_ flags */
     ioprio: u16,               /* ioprio for the request */
@@ -123,7 +405,7 @@ struct io_uring_sqe {
     idx: io_uring_sqe_idx,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 #[repr(C)]
 pub struct io_uring_cqe {
     user_data: u64,   /* sqe->data submission passed back */
@@ -132,43 +414,199 @@ pub struct io_uring_cqe {
 }
 
 #[repr(C)]
-struct io_sqring_offsets {
-    head: u32,
-    tail: u32,
-    ring_mask: u32,
-    ring_entries: u32,
-    flags: u32,
-    dropped: u32,
-    array: u32,
+#[derive(Default)]
+struct io_uring_getevents_arg {
+    sigmask: u64,
+    sigmask_sz: u32,
+    min_wait_usec: u32,
+    ts: u64,
+}
+
+/// Argument of timed waits (IORING_ENTER_EXT_ARG, or the timeout sqe without it), kept in the
+/// ring and filled in place
+#[derive(Default)]
+struct WaitArg {
+    arg: io_uring_getevents_arg,
+    ts: KernelTimespec,
+}
+
+/// Offsets of the SQ ring fields in the SQ mapping (see Params::sq_off())
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct io_sqring_offsets {
+    pub head: u32,
+    pub tail: u32,
+    pub ring_mask: u32,
+    pub ring_entries: u32,
+    pub flags: u32,
+    pub dropped: u32,
+    pub array: u32,
     resv1: u32,
     resv2: u64,
 }
 
+/// Offsets of the CQ ring fields in the CQ mapping (see Params::cq_off())
 #[repr(C)]
-struct io_cqring_offsets {
-    head: u32,
-    tail: u32,
-    ring_mask: u32,
-    ring_entries: u32,
-    overflow: u32,
-    cqes: u32,
+#[derive(Debug, Clone, Copy)]
+pub struct io_cqring_offsets {
+    pub head: u32,
+    pub tail: u32,
+    pub ring_mask: u32,
+    pub ring_entries: u32,
+    pub overflow: u32,
+    pub cqes: u32,
     resv: [u64; 2],
 }
 
 
+const IO_URING_OP_SUPPORTED: u16 = 1 << 0;
+
 #[repr(C)]
-struct io_uring_params {
-    sq_entries: u32,
-    cq_entries: u32,
+#[derive(Clone, Copy)]
+struct io_uring_probe_op {
+    op: u8,
+    resv: u8,
+    flags: u16,         /* IO_URING_OP_* flags */
+    resv2: u32,
+}
+
+// NB: The kernel struct ends with a flexible array member. We always probe for all possible
+// opcodes, so use a fixed size one.
+const PROBE_OPS_NR: usize = 256;
+
+#[repr(C)]
+struct io_uring_probe {
+    last_op: u8,        /* last opcode supported */
+    ops_len: u8,        /* length of ops[] array below */
+    resv: u16,
+    resv2: [u32; 3],
+    ops: [io_uring_probe_op; PROBE_OPS_NR],
+}
+
+/// Timespec, as used by the kernel io_uring interface
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct KernelTimespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+bitflags::bitflags!{
+    struct AsyncCancelFlags: u32 {
+        const ALL      = 1 << 0; // cancel all requests that match
+        const FD       = 1 << 1; // match by fd
+        const ANY      = 1 << 2; // match any request
+        const FD_FIXED = 1 << 3; // fd is a fixed file
+        const USERDATA = 1 << 4; // match by user_data (the default)
+        const OP       = 1 << 5; // match by opcode
+    }
+}
+
+const IORING_RESTRICTION_REGISTER_OP        : u16 = 0;
+const IORING_RESTRICTION_SQE_OP             : u16 = 1;
+const IORING_RESTRICTION_SQE_FLAGS_ALLOWED  : u16 = 2;
+const IORING_RESTRICTION_SQE_FLAGS_REQUIRED : u16 = 3;
+
+/// Argument of IORING_REGISTER_RESTRICTIONS (one per restriction)
+#[repr(C)]
+#[derive(Default)]
+struct io_uring_restriction {
+    opcode: u16,
+    arg: u8, /* register_op, sqe_op, or sqe_flags, depending on opcode */
+    resv: u8,
+    resv2: [u32; 3],
+}
+
+/// Argument of IORING_REGISTER_RING_FDS and IORING_UNREGISTER_RING_FDS (and, with data
+/// pointing to the fds, of IORING_REGISTER_FILES_UPDATE)
+#[repr(C)]
+#[derive(Default)]
+struct io_uring_rsrc_update {
+    offset: u32,
+    resv: u32,
+    data: u64,
+}
+
+/// Entry of a provided buffer ring
+///
+/// NB: the ring tail (u16) overlays the resv field of the first entry.
+#[repr(C)]
+struct io_uring_buf {
+    addr: u64,
+    len: u32,
+    bid: u16,
+    resv: u16,
+}
+
+/// Argument of IORING_REGISTER_PBUF_RING and IORING_UNREGISTER_PBUF_RING
+#[repr(C)]
+#[derive(Default)]
+struct io_uring_buf_reg {
+    ring_addr: u64,
+    ring_entries: u32,
+    bgid: u16,
+    flags: u16,
+    resv: [u64; 3],
+}
+
+#[repr(C)]
+struct io_uring_sync_cancel_reg {
+    addr: u64,
+    fd: i32,
     flags: u32,
-    sq_thread_cpu: u32,
-    sq_thread_idle: u32,
-    resv: [u32; 5],
-    sq_off: io_sqring_offsets,
-    cq_off: io_cqring_offsets,
+    timeout: KernelTimespec,
+    opcode: u8,
+    pad: [u8; 7],
+    pad2: [u64; 3],
+}
+
+/// Argument of io_uring_setup(), filled in by the kernel (see Syscalls::setup())
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct io_uring_params {
+    pub sq_entries: u32,
+    pub cq_entries: u32,
+    pub flags: u32,
+    pub sq_thread_cpu: u32,
+    pub sq_thread_idle: u32,
+    pub features: u32,
+    pub wq_fd: u32,
+    resv: [u32; 3],
+    pub sq_off: io_sqring_offsets,
+    pub cq_off: io_cqring_offsets,
 }
 
-/**
+// NB: The structures above are shared with the kernel, so check their layout against the uapi
+// at compile time.
+const _: () = assert!(mem::size_of::<io_uring_sqe>() == 64);
+const _: () = assert!(mem::offset_of!(io_uring_sqe, off) == 8);
+const _: () = assert!(mem::offset_of!(io_uring_sqe, addr) == 16);
+const _: () = assert!(mem::offset_of!(io_uring_sqe, len) == 24);
+const _: () = assert!(mem::offset_of!(io_uring_sqe, args) == 28);
+const _: () = assert!(mem::offset_of!(io_uring_sqe, user_data) == 32);
+const _: () = assert!(mem::offset_of!(io_uring_sqe, idx) == 40);
+const _: () = assert!(mem::size_of::<io_uring_sqe_args>() == 4);
+const _: () = assert!(mem::size_of::<io_uring_sqe_idx>() == 24);
+const _: () = assert!(mem::offset_of!(io_uring_sqe_idx_fields, personality) == 2);
+const _: () = assert!(mem::offset_of!(io_uring_sqe_idx_fields, file_index) == 4);
+const _: () = assert!(mem::offset_of!(io_uring_sqe, idx) + mem::offset_of!(io_uring_sqe_idx_cmd, cmd) == URING_CMD_OFF);
+const _: () = assert!(mem::size_of::<io_uring_cqe>() == 16);
+const _: () = assert!(mem::size_of::<io_sqring_offsets>() == 40);
+const _: () = assert!(mem::size_of::<io_cqring_offsets>() == 40);
+const _: () = assert!(mem::size_of::<io_uring_params>() == 120);
+const _: () = assert!(mem::offset_of!(io_uring_params, sq_off) == 40);
+const _: () = assert!(mem::size_of::<io_uring_probe_op>() == 8);
+const _: () = assert!(mem::offset_of!(io_uring_probe, ops) == 16);
+const _: () = assert!(mem::size_of::<KernelTimespec>() == 16);
+const _: () = assert!(mem::size_of::<io_uring_getevents_arg>() == 24);
+const _: () = assert!(mem::size_of::<io_uring_rsrc_update>() == 16);
+const _: () = assert!(mem::size_of::<io_uring_restriction>() == 16);
+const _: () = assert!(mem::size_of::<io_uring_sync_cancel_reg>() == 64);
+const _: () = assert!(mem::size_of::<io_uring_buf>() == 16);
+const _: () = assert!(mem::offset_of!(io_uring_buf, resv) == 14);
+const _: () = assert!(mem::size_of::<io_uring_buf_reg>() == 40);
+
+/*
  * Library structures
  */
 
@@ -185,6 +623,7 @@ struct SQ {
     array: *mut u32,
 
     sqes: *mut io_uring_sqe,
+    sqe_shift: u32, // 1 for 128-byte sqes (IORING_SETUP_SQE128), 0 otherwise
     // NB: the ring depends on wrapping behavior for working correctly.
     sqe_head: std::num::Wrapping<u32>,
     sqe_tail: std::num::Wrapping<u32>,
@@ -194,6 +633,7 @@ struct SQ {
 }
 
 /// Completion queue
+#[derive(Clone, Copy)]
 struct CQ {
     khead: *mut u32,
     ktail: *mut u32,
@@ -202,6 +642,7 @@ struct CQ {
     overflow: *mut u32,
 
     cqes: *mut io_uring_cqe,
+    cqe_shift: u32, // 1 for 32-byte cqes (IORING_SETUP_CQE32), 0 otherwise
 
     ring_sz: libc::size_t,
     ring_ptr: *mut libc::c_void,
@@ -212,6 +653,29 @@ pub struct CqIter<'a> {
     cq: &'a CQ,
 }
 
+/// A cqe borrowed in place from the CQ, which is consumed when the guard is dropped
+///
+/// See IoUring::next_cqe().
+pub struct CqeGuard<'a> {
+    ior: &'a mut IoUring,
+    cqe: *const io_uring_cqe,
+}
+
+/// The submission half of a split ring (see IoUring::split())
+pub struct SubmissionQueue<'a> {
+    ior: &'a mut IoUring,
+}
+
+/// The completion half of a split ring (see IoUring::split()), which can be sent to another
+/// thread
+pub struct CompletionQueue<'a> {
+    cq: CQ, // NB: a copy, pointing to the same mapping
+    fd: libc::c_int,
+    sys: std::sync::Arc<dyn Syscalls>,
+    consumed: &'a mut u32, // cqes consumed, to account for them in the ring when the split ends
+    disabled: bool, // NB: the ring cannot be enabled while it is split
+}
+
 
 /// io uring descriptor
 pub struct IoUring {
@@ -219,12 +683,274 @@ pub struct IoUring {
     sq: SQ,
     cq: CQ,
     flags: SetupFlags,
+    sq_thread_idle: u32,
+    sq_thread_cpu: u32,
+    features: FeatureFlags,
+    in_flight: u32, // sqes submitted, minus cqes consumed
+    params: Params,
+    probe: Option<Probe>, // cached probe results
+    owned_iovecs: std::collections::HashMap<u64, OwnedIovecs>, // see submit_readv_owned()
+    registered_index: Option<u32>, // see register_ring_fd()
+    wait_arg: WaitArg, // see wait_cqe_timeout()
+    fixed_bufs: Vec<(usize, usize)>, // (address, length) of the registered buffers
+    fixed_files: Vec<libc::c_int>, // see registered_files()
+    handlers: std::collections::HashMap<u16, CompletionHandler>, // see on_completion()
+    sys: std::sync::Arc<dyn Syscalls>, // see Builder::syscalls()
+    autosubmit: u32, // see set_autosubmit(), 0 means disabled
+    disabled: bool, // created with R_DISABLED, and not enabled yet (see enable_rings())
+}
+
+/// Handler of the completions with a given tag (see IoUring::on_completion())
+pub type CompletionHandler = Box<dyn FnMut(&io_uring_cqe)>;
+
+/// Buffers of a vectored operation, kept alive by the ring until the operation completes
+struct OwnedIovecs {
+    bufs: Vec<Vec<u8>>,
+    _iovecs: Vec<libc::iovec>,
+}
+
+/// An iovec array for vectored operations, borrowing the buffers it points to
+///
+/// Built from shared slices (for writev) with IoVecs::new(), or from mutable slices (for readv
+/// and writev) with IoVecs::new_mut().
+pub struct IoVecs<'a> {
+    iovecs: Vec<libc::iovec>,
+    writable: bool, // whether the buffers can be written to (i.e., read into)
+    _p: std::marker::PhantomData<&'a mut [u8]>,
+}
+
+/// user_data packing a type tag (bits 48-63), a generation (bits 32-47), and an index (bits 0-31)
+///
+/// The generation allows detecting stale completions, i.e., completions for an index (e.g., of a
+/// slab) that has been reused since the request was submitted (see Generations).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserData(pub u64);
+
+/// Current generation of each index, for detecting stale completions (see UserData)
+#[derive(Debug, Clone)]
+pub struct Generations(Vec<u16>);
+
+/// An errno value (positive)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub i32);
+
+/// Result of a completion, interpreted based on the operation (see io_uring_cqe::interpret())
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CqeResult {
+    /// a new file descriptor (e.g., accept, openat)
+    Fd(i32),
+    /// number of bytes transferred (e.g., read, write). Used for all other operations as well.
+    Bytes(usize),
+    /// mask of returned events (poll), 32 bits wide for prep_poll_add32()
+    Events(u32),
+    /// the operation failed
+    Error(Errno),
+}
+
+/// A completion of a multishot read (see io_uring_cqe::multishot_read())
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultishotRead {
+    /// len bytes were read into the provided buffer bid. If more is false, the read is
+    /// terminated and needs to be re-armed.
+    Data { bid: u16, len: usize, more: bool },
+    /// the buffer group is exhausted (-ENOBUFS) and the read is terminated: recycle or provide
+    /// buffers, and re-arm it
+    NeedBuffers,
+    /// end of file: the read is terminated
+    Eof,
+    /// the read failed, and is terminated
+    Error(Errno),
+}
+
+/// Snapshot of the ring indices, for debugging (see IoUring::dump())
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RingDump {
+    pub fd: libc::c_int,
+    pub sq_head: u32,
+    pub sq_tail: u32,
+    pub sq_mask: u32,
+    pub sq_entries: u32,
+    pub sq_flags: u32,
+    pub sq_dropped: u32,
+    /// local head of sqes acquired via get_sqe() (not yet flushed)
+    pub sqe_head: u32,
+    /// local tail of sqes acquired via get_sqe()
+    pub sqe_tail: u32,
+    pub cq_head: u32,
+    pub cq_tail: u32,
+    pub cq_mask: u32,
+    pub cq_entries: u32,
+    pub cq_overflow: u32,
+}
+
+/// What a restricted ring allows (see IoUring::register_restrictions())
+///
+/// Once restrictions are registered, only the allowed operations are permitted: the rest fail
+/// with EACCES.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restriction {
+    /// allow io_uring_register() opcode (IORING_REGISTER_*)
+    RegisterOp(u8),
+    /// allow operation (IORING_OP_*)
+    SqeOp(u8),
+    /// allow sqe flags (IOSQE_*) to be set
+    SqeFlagsAllowed(u8),
+    /// require sqe flags (IOSQE_*) to be set on every sqe
+    SqeFlagsRequired(u8),
+}
+
+/// Which in-flight requests to cancel (see IoUring::sync_cancel())
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelCriteria {
+    /// requests with the given user data
+    UserData(u64),
+    /// requests on the given fd
+    Fd(libc::c_int),
+    /// all requests
+    All,
+}
+
+/// Spin iterations of WaitStrategy::Spin on rings that run task work only in the kernel
+pub const SPIN_TASKRUN_ITERS: u32 = 1 << 12;
+
+/// How to wait for a completion (see IoUring::wait_cqe_with())
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitStrategy {
+    /// Block in io_uring_enter() until a completion is available
+    Block,
+    /// Busy-spin on the CQ for spin_iters iterations, and then block
+    SpinThenBlock { spin_iters: u32 },
+    /// Busy-spin on the CQ until a completion is available, never entering the kernel
+    ///
+    /// Rings with COOP_TASKRUN or DEFER_TASKRUN only post cqes when entering the kernel, so
+    /// for them this is SpinThenBlock { spin_iters: SPIN_TASKRUN_ITERS }.
+    Spin,
 }
 
-pub struct SQEntry(*mut io_uring_sqe);
+/// Parameters of a ring, as negotiated with the kernel at setup (see IoUring::params())
+#[derive(Clone, Copy)]
+pub struct Params(io_uring_params);
 
+/// Operations supported by the kernel (see IoUring::probe())
+pub struct Probe(Box<io_uring_probe>);
 
-/**
+/// A submission queue entry, acquired via IoUring::get_sqe()
+///
+/// The entry borrows the ring, so that it cannot be accessed after it is submitted (at which
+/// point the kernel may consume it, and the slot may be reused). The expected borrow error is
+/// checked by tests/ui/sqe_after_submit.rs:
+///
+/// ```compile_fail
+/// let mut ior = iouring::io_uring::IoUring::init(4).unwrap();
+/// let mut sqe = ior.get_sqe().unwrap();
+/// ior.submit().unwrap();
+/// sqe.prep_nop();
+/// ```
+pub struct SQEntry<'a>(*mut io_uring_sqe, std::marker::PhantomData<&'a mut IoUring>, bool /* 128-byte sqe */);
+
+/// Page-aligned buffers registered as fixed buffers, owned by the pool
+///
+/// Buffers are handed out as FixedBuf tokens (see FixedBufferPool::acquire()), which cannot
+/// outlive the pool. Dropping the pool unregisters the buffers, and then frees them.
+pub struct FixedBufferPool {
+    ring_fd: libc::c_int, // dup()ed ring fd, so that the buffers can be unregistered on drop
+    base: *mut u8,
+    buf_size: usize,
+    free: std::cell::RefCell<Vec<u16>>,
+}
+
+/// A buffer of a FixedBufferPool, which is returned to the pool when dropped
+pub struct FixedBuf<'a> {
+    pool: &'a FixedBufferPool,
+    index: u16,
+}
+
+/// A ring of provided buffers (5.19+), from which the kernel picks buffers for requests
+///
+/// Requests select a buffer from the group bgid() (e.g., prep_recv_bundle()), and report the
+/// buffer id in their cqe (see io_uring_cqe::buffer_id()). A buffer belongs to the application
+/// until it is handed back to the kernel with recycle(), and can only be read (see buf())
+/// while it does. Dropping the ring unregisters it, and
+/// then frees the buffers.
+pub struct BufRing {
+    ring_fd: libc::c_int, // dup()ed ring fd, so that the buffer ring can be unregistered on drop
+    ring: *mut io_uring_buf,
+    entries: u16,
+    bgid: u16,
+    bufs: *mut u8,
+    buf_size: usize,
+    tail: std::cell::Cell<u16>,
+}
+
+/// A socket address (sockaddr_storage and its length), for prep_connect_addr() and
+/// prep_accept_addr()
+///
+/// Converts from and to std::net::SocketAddr, handling both IPv4 and IPv6.
+#[derive(Clone, Copy)]
+pub struct SockAddr {
+    storage: libc::sockaddr_storage,
+    len: libc::socklen_t,
+}
+
+/// Accepts connections on a listening socket, using a multishot accept (see next_conn())
+///
+/// The acceptor borrows the ring, and expects to be its only user: cqes of other requests are
+/// discarded. Dropping it cancels the accept.
+pub struct Acceptor<'a> {
+    ring: &'a mut IoUring,
+    fd: libc::c_int,
+    user_data: u64,
+    armed: bool, // the multishot accept is in flight
+}
+
+/// Errors of the io_uring system calls
+///
+/// Errors of individual operations are reported via io_uring_cqe::res().
+#[derive(Debug)]
+pub enum IoUringError {
+    /// Setting up the ring (io_uring_setup() or mapping the queues), or the memory and fds of a
+    /// FixedBufferPool or BufRing, failed
+    Setup(io::Error),
+    /// io_uring_enter() failed
+    Submit(io::Error),
+    /// io_uring_register() failed
+    Register(io::Error),
+    /// The operation (IORING_OP_*) is not supported by the kernel
+    Unsupported(u8),
+    /// The ring was created disabled (see Builder::disabled()), and has not been enabled yet
+    RingDisabled,
+    /// An argument is invalid (checked by the library, without calling into the kernel)
+    InvalidInput(String),
+    /// The call requires an idle ring (no queued sqes or ready cqes)
+    NotIdle,
+    /// There are no free sqes: submit the queued sqes (and consume cqes) first
+    SqFull,
+}
+
+/// A common io_uring_setup() failure, annotated with its likely cause
+///
+/// IoUringError::Setup wraps this (as the inner error of an io::Error of the same kind) for
+/// ENOSYS, EPERM, and ENOMEM. Use io::Error::get_ref() and downcast_ref() to get it.
+#[derive(Debug)]
+pub struct SetupError {
+    errno: i32,
+    hint: &'static str,
+}
+
+/// io uring configuration, used to initialize an IoUring
+pub struct Builder {
+    nentries: libc::c_uint,
+    lock_memory: bool,
+    flags: SetupFlags,
+    sq_thread_idle: u32,
+    sq_thread_cpu: u32,
+    cq_entries: u32,
+    numa_node: Option<u32>,
+    sys: std::sync::Arc<dyn Syscalls>,
+}
+
+
+/*
  * Syscall wrappers
  */
 
@@ -245,8 +971,9 @@ unsafe fn io_uring_setup(entries: libc::c_uint, params: *mut io_uring_params)
     libc::c_int::try_from(ret).unwrap_or(-1)
 }
 
-/// io_uring_enter syscall wrapper
+/// io_uring_enter syscall wrapper, using sys for the system call
 unsafe fn io_uring_enter(
+    sys: &dyn Syscalls,
     fd: libc::c_int,
     to_submit: libc::c_uint,
     min_complete: libc::c_uint,
@@ -272,22 +999,285 @@ unsafe fn io_uring_enter(
     //
     const NSIG_: libc::c_uint = 65;
     let sigset_size: libc::c_uint = NSIG_ / 8;
-    libc::syscall(SYS_io_uring_enter, fd, to_submit, min_complete, flags, sigset, sigset_size)
+    sys.enter(fd, to_submit, min_complete, flags, sigset as *const libc::c_void, sigset_size as libc::size_t)
+}
+
+/// io_uring_enter syscall wrapper, with an explicit argument and argument size
+///
+/// With IORING_ENTER_EXT_ARG, arg is an io_uring_getevents_arg, instead of a sigset.
+unsafe fn io_uring_enter2(
+    fd: libc::c_int,
+    to_submit: libc::c_uint,
+    min_complete: libc::c_uint,
+    flags: libc::c_uint,
+    arg: *const libc::c_void,
+    argsz: libc::size_t)
+-> libc::c_long {
+    libc::syscall(SYS_io_uring_enter, fd, to_submit, min_complete, flags, arg, argsz)
+}
+
+/// The system calls that IoUring uses to talk to the kernel (advanced)
+///
+/// The default is LibcSyscalls. Builder::syscalls() replaces them, e.g., to inject failures
+/// by wrapping LibcSyscalls, or to simulate the kernel side of the ring in user memory. Each
+/// method has the contract of the system call it is named after: values are returned, and
+/// errors reported via errno, as the kernel does. The ring trusts these results, e.g., it
+/// accesses the queues at the offsets that setup() writes to params.
+///
+/// # Safety
+///
+/// The methods are called with the arguments of the actual system calls, and the pointers
+/// are only valid for the duration of the call. Implementations must behave as the kernel
+/// would: the mappings returned by mmap() need to be large enough for the offsets (and
+/// entries) that setup() reports.
+pub unsafe trait Syscalls: Send + Sync {
+    /// io_uring_setup(): create a ring of entries entries, and fill in params
+    ///
+    /// # Safety
+    ///
+    /// params must point to a valid io_uring_params.
+    unsafe fn setup(&self, entries: libc::c_uint, params: *mut io_uring_params) -> libc::c_int;
+    /// io_uring_enter() (with the extended argument of IORING_ENTER_EXT_ARG, if any)
+    ///
+    /// # Safety
+    ///
+    /// arg must be null, or point to argsz bytes of the argument that flags call for.
+    unsafe fn enter(&self, fd: libc::c_int, to_submit: libc::c_uint, min_complete: libc::c_uint,
+                    flags: libc::c_uint, arg: *const libc::c_void, argsz: libc::size_t) -> libc::c_long;
+    /// io_uring_register()
+    ///
+    /// # Safety
+    ///
+    /// arg must point to the nr_args arguments of opcode (or be null, if it takes none).
+    unsafe fn register(&self, fd: libc::c_int, opcode: libc::c_uint, arg: *mut libc::c_void,
+                       nr_args: libc::c_uint) -> libc::c_long;
+    /// Map the part of the ring at offset off (IORING_OFF_*) of fd, with MAP_LOCKED if locked
+    ///
+    /// # Safety
+    ///
+    /// The mapping must only be accessed within len bytes, and until it is unmapped.
+    unsafe fn mmap(&self, len: libc::size_t, fd: libc::c_int, off: libc::off_t, locked: bool) -> *mut libc::c_void;
+    /// Unmap a mapping returned by mmap()
+    ///
+    /// # Safety
+    ///
+    /// addr and len must be those of an mmap() mapping, which is not accessed afterwards.
+    unsafe fn munmap(&self, addr: *mut libc::c_void, len: libc::size_t) -> libc::c_int;
+}
+
+/// The actual system calls
+pub struct LibcSyscalls;
+
+unsafe impl Syscalls for LibcSyscalls {
+    unsafe fn setup(&self, entries: libc::c_uint, params: *mut io_uring_params) -> libc::c_int {
+        io_uring_setup(entries, params)
+    }
+
+    unsafe fn enter(&self, fd: libc::c_int, to_submit: libc::c_uint, min_complete: libc::c_uint,
+                    flags: libc::c_uint, arg: *const libc::c_void, argsz: libc::size_t) -> libc::c_long {
+        io_uring_enter2(fd, to_submit, min_complete, flags, arg, argsz)
+    }
+
+    unsafe fn register(&self, fd: libc::c_int, opcode: libc::c_uint, arg: *mut libc::c_void,
+                       nr_args: libc::c_uint) -> libc::c_long {
+        io_uring_register(fd, opcode, arg, nr_args)
+    }
+
+    unsafe fn mmap(&self, len: libc::size_t, fd: libc::c_int, off: libc::off_t, locked: bool) -> *mut libc::c_void {
+        mmap(len, fd, off, locked)
+    }
+
+    unsafe fn munmap(&self, addr: *mut libc::c_void, len: libc::size_t) -> libc::c_int {
+        munmap(addr, len)
+    }
 }
 
 
-/**
+/*
  * Misc helpers
  */
 
 /// mmap helper, using the default protection and flags
-unsafe fn mmap(len: libc::size_t, fd: libc::c_int, off: libc::off_t) -> *mut libc::c_void {
+///
+/// If locked is true, MAP_LOCKED is added to the flags.
+unsafe fn mmap(len: libc::size_t, fd: libc::c_int, off: libc::off_t, locked: bool) -> *mut libc::c_void {
     let prot  = libc::PROT_READ | libc::PROT_WRITE;
-    let flags = libc::MAP_SHARED | libc::MAP_POPULATE;
-    let null = 0 as *mut libc::c_void;
+    let mut flags = libc::MAP_SHARED | libc::MAP_POPULATE;
+    if locked {
+        flags |= libc::MAP_LOCKED;
+    }
+    let null = std::ptr::null_mut::<libc::c_void>();
     libc::mmap(null, len, prot, flags, fd, off)
 }
 
+const MPOL_BIND: libc::c_int = 2;
+/// Number of nodes in the node masks passed to the kernel
+const MAX_NUMNODES: usize = 1024;
+const NODEMASK_LONGS: usize = MAX_NUMNODES / (8 * mem::size_of::<libc::c_ulong>());
+
+/// Call f with the memory policy of the calling thread bound to node
+///
+/// The previous policy is restored before returning. If restoring it fails, the result of f is
+/// dropped and the error is returned, since the thread is left with the bound policy.
+fn with_numa_node<T, F: FnOnce() -> T>(node: u32, f: F) -> io::Result<T> {
+    if node as usize >= MAX_NUMNODES {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid NUMA node"));
+    }
+    // NB: the kernel ignores the last bit of maxnode
+    let maxnode = (MAX_NUMNODES + 1) as libc::c_ulong;
+
+    let mut old_mode: libc::c_int = 0;
+    let mut old_mask: [libc::c_ulong; NODEMASK_LONGS] = [0; NODEMASK_LONGS];
+    let ret = unsafe {
+        libc::syscall(libc::SYS_get_mempolicy, &mut old_mode, old_mask.as_mut_ptr(), maxnode, 0usize, 0usize)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let bits = 8 * mem::size_of::<libc::c_ulong>();
+    let mut mask: [libc::c_ulong; NODEMASK_LONGS] = [0; NODEMASK_LONGS];
+    mask[node as usize / bits] = 1 << (node as usize % bits);
+    let ret = unsafe { libc::syscall(libc::SYS_set_mempolicy, MPOL_BIND, mask.as_ptr(), maxnode) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let ret = f();
+
+    let err = unsafe { libc::syscall(libc::SYS_set_mempolicy, old_mode, old_mask.as_ptr(), maxnode) };
+    if err < 0 {
+        let err = io::Error::last_os_error();
+        return Err(io::Error::new(err.kind(), format!("restoring the memory policy failed: {}", err)));
+    }
+    Ok(ret)
+}
+
+impl SetupError {
+    /// Annotate an io_uring_setup() error, if it is one of the common cases
+    fn annotate(err: io::Error) -> io::Error {
+        let hint = match err.raw_os_error() {
+            Some(libc::ENOSYS) => "io_uring is not supported by the kernel (CONFIG_IO_URING is not set, or the kernel is older than 5.1)",
+            Some(libc::EPERM) => "io_uring is not permitted; check /proc/sys/kernel/io_uring_disabled and io_uring_group, and seccomp filters (e.g., of container runtimes)",
+            Some(libc::ENOMEM) => "not enough memory for the rings; on kernels older than 5.12 the rings count against RLIMIT_MEMLOCK (ulimit -l)",
+            _ => return err,
+        };
+        let errno = err.raw_os_error().unwrap();
+        io::Error::new(err.kind(), SetupError { errno, hint })
+    }
+
+    /// The errno of the failed io_uring_setup()
+    pub fn errno(&self) -> i32 {
+        self.errno
+    }
+
+    /// Likely cause of the failure
+    pub fn hint(&self) -> &'static str {
+        self.hint
+    }
+}
+
+impl std::fmt::Display for SetupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({})", io::Error::from_raw_os_error(self.errno), self.hint)
+    }
+}
+
+impl std::error::Error for SetupError {}
+
+/// Copy a cqe out of the (kernel-shared) CQ ring
+///
+/// The read is volatile, so that the compiler cannot assume that the shared memory is stable and
+/// elide or reorder the reads of the cqe fields.
+unsafe fn read_cqe_volatile(ptr: *const io_uring_cqe) -> io_uring_cqe {
+    io_uring_cqe {
+        user_data: std::ptr::read_volatile(&(*ptr).user_data),
+        res: std::ptr::read_volatile(&(*ptr).res),
+        flags: std::ptr::read_volatile(&(*ptr).flags),
+    }
+}
+
+/// The running kernel's (major, minor) version, from uname()
+fn kernel_version() -> Option<(u32, u32)> {
+    let mut uts: libc::utsname = unsafe { mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return None
+    }
+    let release = unsafe { std::ffi::CStr::from_ptr(uts.release.as_ptr()) }.to_str().ok()?;
+    let mut ver = release.split(|c: char| !c.is_ascii_digit()).map(|x| x.parse::<u32>().ok());
+    Some((ver.next()??, ver.next()??))
+}
+
+/// Minimum kernel version of each io_uring_register() opcode, for kernels without restrictions
+///
+/// NB: restrictions were added in 5.10 (with IORING_REGISTER_RESTRICTIONS), so the later
+/// opcodes are never supported by such kernels.
+const REGISTER_OP_VERSIONS: [(u32, u32); IORING_REGISTER_RESTRICTIONS as usize] = [
+    (5, 1), // IORING_REGISTER_BUFFERS
+    (5, 1), // IORING_UNREGISTER_BUFFERS
+    (5, 1), // IORING_REGISTER_FILES
+    (5, 1), // IORING_UNREGISTER_FILES
+    (5, 2), // IORING_REGISTER_EVENTFD
+    (5, 2), // IORING_UNREGISTER_EVENTFD
+    (5, 5), // IORING_REGISTER_FILES_UPDATE
+    (5, 6), // IORING_REGISTER_EVENTFD_ASYNC
+    (5, 6), // IORING_REGISTER_PROBE
+    (5, 6), // IORING_REGISTER_PERSONALITY
+    (5, 6), // IORING_UNREGISTER_PERSONALITY
+];
+
+/// Cached register_ops_nr(): the kernel is the same for all the rings of the process
+static REGISTER_OPS: std::sync::OnceLock<u32> = std::sync::OnceLock::new();
+
+/// Number of io_uring_register() opcodes the kernel supports (they are numbered from 0)
+///
+/// The kernel accepts a restriction for exactly the opcodes below the number of opcodes it
+/// knows, so this searches for it by registering restrictions on temporary disabled rings.
+/// Kernels that do not support disabled rings (EINVAL, before 5.10) do not support
+/// restrictions either, and REGISTER_OP_VERSIONS is used for them. Returns 0 if neither works.
+fn register_ops_nr() -> u32 {
+    let accepts = |op: u8| -> Result<bool, IoUringError> {
+        let mut ior = Builder::new(1).disabled(true).build()?;
+        Ok(ior.register_restrictions(&[Restriction::RegisterOp(op)]).is_ok())
+    };
+    match accepts(0) {
+        Ok(true) => (),
+        Ok(false) => return 0,
+        Err(IoUringError::Setup(e)) if e.raw_os_error() == Some(libc::EINVAL) => {
+            let version = match kernel_version() {
+                Some(x) => x,
+                None => return 0,
+            };
+            return REGISTER_OP_VERSIONS.iter().take_while(|&&min| version >= min).count() as u32
+        },
+        Err(_) => return 0,
+    }
+
+    // NB: opcodes below lo are accepted, opcodes from hi on are not
+    let (mut lo, mut hi) = (1u32, 1u32 << 8);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if accepts(mid as u8).unwrap_or(false) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
+/// Get the error of a failed mmap() call
+///
+/// A failed MAP_LOCKED mapping most likely means that RLIMIT_MEMLOCK was exceeded, so say that.
+fn mmap_error(locked: bool) -> io::Error {
+    let err = io::Error::last_os_error();
+    if locked && err.raw_os_error() == Some(libc::EAGAIN) {
+        let msg = format!("mmap() with MAP_LOCKED failed (RLIMIT_MEMLOCK exceeded?): {}", err);
+        return io::Error::new(err.kind(), msg);
+    }
+    err
+}
+
 /// munmap helper
 ///
 /// Prints a message at stder if munmap() returns an error.
@@ -326,45 +1316,471 @@ unsafe fn close(fd: libc::c_int) -> libc::c_int {
         err
 }
 
-/**
+/*
  * Main implementation
  */
 
-impl SQEntry {
-    fn reset(&mut self) {
-        let ptr = self.0;
-        unsafe { *ptr =  mem::zeroed() };
+impl IoUringError {
+    /// The underlying io::Error, if there is one
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            IoUringError::Setup(e) => Some(e),
+            IoUringError::Submit(e) => Some(e),
+            IoUringError::Register(e) => Some(e),
+            IoUringError::Unsupported(_) => None,
+            IoUringError::RingDisabled => None,
+            IoUringError::InvalidInput(_) => None,
+            IoUringError::NotIdle => None,
+            IoUringError::SqFull => None,
+        }
     }
+}
 
-    fn prep_rw(&mut self, op: u8, fd: libc::c_int, addr: *const libc::c_void, len: u32, off: u64) {
-        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
-        *sqe = io_uring_sqe {
-            opcode: op,
-            flags: 0,
+impl std::fmt::Display for IoUringError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IoUringError::Setup(e) => write!(f, "io_uring setup failed: {}", e),
+            IoUringError::Submit(e) => write!(f, "io_uring submit failed: {}", e),
+            IoUringError::Register(e) => write!(f, "io_uring register failed: {}", e),
+            IoUringError::Unsupported(op) => write!(f, "io_uring operation {} is not supported", opcode_name(*op)),
+            IoUringError::RingDisabled => write!(f, "io_uring ring is disabled (see IoUring::enable_rings())"),
+            IoUringError::InvalidInput(msg) => write!(f, "invalid input: {}", msg),
+            IoUringError::NotIdle => write!(f, "io_uring ring is not idle (it has queued sqes or ready cqes)"),
+            IoUringError::SqFull => write!(f, "io_uring submission queue is full"),
+        }
+    }
+}
+
+impl std::error::Error for IoUringError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.io_error().map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl From<IoUringError> for io::Error {
+    fn from(err: IoUringError) -> io::Error {
+        match err {
+            IoUringError::Setup(e) => e,
+            IoUringError::Submit(e) => e,
+            IoUringError::Register(e) => e,
+            IoUringError::Unsupported(_) => io::Error::new(io::ErrorKind::Unsupported, err.to_string()),
+            IoUringError::RingDisabled => io::Error::other(err.to_string()),
+            IoUringError::InvalidInput(_) => io::Error::new(io::ErrorKind::InvalidInput, err.to_string()),
+            IoUringError::NotIdle => io::Error::new(io::ErrorKind::ResourceBusy, err.to_string()),
+            IoUringError::SqFull => io::Error::new(io::ErrorKind::WouldBlock, err.to_string()),
+        }
+    }
+}
+
+impl SQEntry<'_> {
+    fn reset(&mut self) {
+        let ptr = self.0;
+        unsafe { *ptr =  mem::zeroed() };
+    }
+
+    fn prep_rw(&mut self, op: u8, fd: libc::c_int, addr: *const libc::c_void, len: u32, off: u64) {
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        *sqe = io_uring_sqe {
+            opcode: op,
+            flags: 0,
             ioprio: 0,
-            fd: fd,
-            off: off,
+            fd,
+            off,
             addr: addr as u64,
             args: io_uring_sqe_args { rw_flags: 0 },
             user_data: 0,
-            len: len,
+            len,
             idx: io_uring_sqe_idx { __pad2: [0; 3] },
         };
     }
 
+    pub fn prep_nop(&mut self) {
+        self.prep_rw(IORING_OP_NOP, -1, std::ptr::null(), 0, 0)
+    }
+
+    /// Read into a registered (fixed) buffer
+    ///
+    /// buf..buf+nbytes needs to be within the buffer registered at buf_index.
+    pub fn prep_read_fixed(&mut self, fd: libc::c_int, buf: *mut libc::c_void, nbytes: u32, off: u64, buf_index: u16) {
+        self.prep_rw(IORING_OP_READ_FIXED, fd, buf, nbytes, off);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.idx.buf_index = buf_index;
+    }
+
+    /// Write from a registered (fixed) buffer
+    ///
+    /// buf..buf+nbytes needs to be within the buffer registered at buf_index.
+    pub fn prep_write_fixed(&mut self, fd: libc::c_int, buf: *const libc::c_void, nbytes: u32, off: u64, buf_index: u16) {
+        self.prep_rw(IORING_OP_WRITE_FIXED, fd, buf, nbytes, off);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.idx.buf_index = buf_index;
+    }
+
+    /// Read into the fixed buffer buf (see FixedBufferPool)
+    ///
+    /// buf needs to remain valid until the operation completes.
+    pub fn prep_read_fixed_buf(&mut self, fd: libc::c_int, buf: &mut FixedBuf, nbytes: u32, off: u64) {
+        assert!(nbytes as usize <= buf.len());
+        self.prep_read_fixed(fd, buf.as_mut_ptr() as *mut libc::c_void, nbytes, off, buf.index());
+    }
+
+    /// Write from the fixed buffer buf (see FixedBufferPool)
+    ///
+    /// buf needs to remain valid until the operation completes.
+    pub fn prep_write_fixed_buf(&mut self, fd: libc::c_int, buf: &FixedBuf, nbytes: u32, off: u64) {
+        assert!(nbytes as usize <= buf.len());
+        self.prep_write_fixed(fd, buf.as_ptr() as *const libc::c_void, nbytes, off, buf.index());
+    }
+
+    /// Poll fd for the events in poll_mask (POLL*)
+    ///
+    /// The cqe result is the mask of returned events.
+    pub fn prep_poll_add(&mut self, fd: libc::c_int, poll_mask: u16) {
+        // NB: prep_rw() zeroes off, addr, and len, which poll does not use
+        self.prep_rw(IORING_OP_POLL_ADD, fd, std::ptr::null(), 0, 0);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.args.poll_events = poll_mask;
+    }
+
+    /// Like prep_poll_add(), but with a 32-bit mask, so that EPOLL* flags above the lower 16
+    /// bits (e.g., EPOLLEXCLUSIVE) can be used (5.9+, IORING_FEAT_POLL_32BITS).
+    ///
+    /// Older kernels only look at the lower 16 bits.
+    // liburing: io_uring_prep_poll_add()
+    pub fn prep_poll_add32(&mut self, fd: libc::c_int, poll_mask: u32) {
+        self.prep_rw(IORING_OP_POLL_ADD, fd, std::ptr::null(), 0, 0);
+        // NB: the kernel swaps the 16-bit halves on big-endian, for compatibility with the
+        // 16-bit field
+        #[cfg(target_endian = "big")]
+        let poll_mask = poll_mask.rotate_left(16);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.args.poll32_events = poll_mask;
+    }
+
+    /// fsync(2), or fdatasync(2) if datasync is true
+    pub fn prep_fsync(&mut self, fd: libc::c_int, datasync: bool) {
+        self.prep_rw(IORING_OP_FSYNC, fd, std::ptr::null(), 0, 0);
+        if datasync {
+            let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+            sqe.args.fsync_flags = IORING_FSYNC_DATASYNC;
+        }
+    }
+
+    /// sync_file_range(2) of len bytes at offset off, with SYNC_FILE_RANGE_* flags (5.2+)
+    ///
+    /// A len of 0 syncs until the end of the file.
+    pub fn prep_sync_file_range(&mut self, fd: libc::c_int, off: u64, len: u32, flags: u32) {
+        self.prep_rw(IORING_OP_SYNC_FILE_RANGE, fd, std::ptr::null(), len, off);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.args.sync_range_flags = flags;
+    }
+
+    /// Accept a connection on socket fd
+    ///
+    /// addr and addrlen can be null. The cqe result is the new fd.
+    pub fn prep_accept(&mut self, fd: libc::c_int, addr: *mut libc::sockaddr, addrlen: *mut libc::socklen_t, flags: u32) {
+        self.prep_rw(IORING_OP_ACCEPT, fd, addr as *const libc::c_void, 0, addrlen as u64);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.args.accept_flags = flags;
+    }
+
+    /// prep_accept(), storing the peer address in addr
+    ///
+    /// addr needs to remain valid until the operation completes.
+    pub fn prep_accept_addr(&mut self, fd: libc::c_int, addr: &mut SockAddr, flags: u32) {
+        addr.len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let (ptr, len) = (addr.as_mut_ptr(), &mut addr.len as *mut libc::socklen_t);
+        self.prep_accept(fd, ptr, len, flags)
+    }
+
+    /// Accept connections on socket fd, posting a cqe for each one (5.19+)
+    ///
+    /// The cqe result is the new fd. The request remains active as long as its cqes have
+    /// IORING_CQE_F_MORE set, so it needs to be re-armed after a cqe without it.
+    pub fn prep_multishot_accept(&mut self, fd: libc::c_int, flags: u32) {
+        self.prep_accept(fd, std::ptr::null_mut(), std::ptr::null_mut(), flags);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.ioprio |= IORING_ACCEPT_MULTISHOT;
+    }
+
+    /// prep_connect(), for a SockAddr. addr needs to remain valid until the sqe is submitted.
+    pub fn prep_connect_addr(&mut self, fd: libc::c_int, addr: &SockAddr) {
+        self.prep_connect(fd, addr.as_ptr(), addr.len())
+    }
+
+    /// Update the events (poll_mask) and/or user data of the poll with old_user_data (5.13+)
+    ///
+    /// flags (IORING_POLL_UPDATE_*) specify what is updated. The cqe result of the update is 0,
+    /// or -ENOENT if the poll was not found.
+    // liburing: io_uring_prep_poll_update()
+    pub fn prep_poll_update(&mut self, old_user_data: u64, new_user_data: u64, poll_mask: u16, flags: u32) {
+        self.prep_rw(IORING_OP_POLL_REMOVE, -1, std::ptr::null(), flags, new_user_data);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.addr = old_user_data;
+        sqe.args.poll_events = poll_mask;
+    }
+
+    /// Accept a connection on socket fd directly into the fixed file table (5.15+)
+    ///
+    /// The new file is installed at file_index, or at a free slot if file_index is
+    /// IORING_FILE_INDEX_ALLOC, in which case the cqe result is the allocated index. No
+    /// process fd is created.
+    // liburing: io_uring_prep_accept_direct()
+    pub fn prep_accept_direct(&mut self, fd: libc::c_int, addr: *mut libc::sockaddr, addrlen: *mut libc::socklen_t, flags: u32, file_index: u32) {
+        self.prep_accept(fd, addr, addrlen, flags);
+        self.set_target_fixed_file(file_index);
+    }
+
+    /// Connect socket fd to addr
+    pub fn prep_connect(&mut self, fd: libc::c_int, addr: *const libc::sockaddr, addrlen: libc::socklen_t) {
+        self.prep_rw(IORING_OP_CONNECT, fd, addr as *const libc::c_void, 0, addrlen as u64);
+    }
+
+    /// openat(2). The cqe result is the new fd.
+    ///
+    /// path needs to remain valid until the sqe is submitted.
+    pub fn prep_openat(&mut self, dfd: libc::c_int, path: *const libc::c_char, flags: libc::c_int, mode: libc::mode_t) {
+        self.prep_rw(IORING_OP_OPENAT, dfd, path as *const libc::c_void, mode, 0);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.args.open_flags = flags as u32;
+    }
+
+    /// Issue the driver-specific command cmd_op on fd (e.g., NVMe passthrough) (5.19+)
+    ///
+    /// cmd_data is copied to the command area of the sqe, and can be at most URING_CMD_MAX
+    /// bytes, or URING_CMD128_MAX bytes for rings with 128-byte sqes (see Builder::sqe128()).
+    pub fn prep_uring_cmd(&mut self, fd: libc::c_int, cmd_op: u32, cmd_data: &[u8]) {
+        let max = if self.2 { URING_CMD128_MAX } else { URING_CMD_MAX };
+        assert!(cmd_data.len() <= max, "uring_cmd data too large: {} bytes", cmd_data.len());
+        // NB: cmd_op shares the space of off
+        self.prep_rw(IORING_OP_URING_CMD, fd, std::ptr::null(), 0, cmd_op as u64);
+        // NB: for 128-byte sqes, the command area extends past io_uring_sqe
+        unsafe {
+            let cmd = (self.0 as *mut u8).add(URING_CMD_OFF);
+            std::ptr::write_bytes(cmd, 0, max);
+            std::ptr::copy_nonoverlapping(cmd_data.as_ptr(), cmd, cmd_data.len());
+        }
+    }
+
+    /// Close fd
+    pub fn prep_close(&mut self, fd: libc::c_int) {
+        self.prep_rw(IORING_OP_CLOSE, fd, std::ptr::null(), 0, 0);
+    }
+
+    /// Close the file at fixed_index in the fixed file table, freeing the slot (5.15+)
+    // liburing: io_uring_prep_close_direct()
+    pub fn prep_close_direct(&mut self, fixed_index: u32) {
+        // NB: the kernel requires fd to be 0 when closing a fixed file
+        self.prep_close(0);
+        self.set_target_fixed_file(fixed_index);
+    }
+
+    // liburing: __io_uring_set_target_fixed_file()
+    fn set_target_fixed_file(&mut self, file_index: u32) {
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        // NB: 0 means a regular fd, so the kernel expects the index + 1
+        let file_index = if file_index == IORING_FILE_INDEX_ALLOC { file_index } else { file_index + 1 };
+        sqe.idx.fields.file_index = file_index;
+    }
+
+    /// Wait for a child process to change state, like waitid(2) (6.7+)
+    ///
+    /// infop needs to remain valid until the operation completes.
+    //
+    // NB: Unlike wait4(2), the kernel does not provide resource usage (rusage) information for
+    // this operation.
+    pub fn prep_waitid(&mut self, idtype: libc::idtype_t, id: libc::id_t, infop: *mut libc::siginfo_t, options: libc::c_int) {
+        self.prep_rw(IORING_OP_WAITID, id as libc::c_int, std::ptr::null(), idtype, 0);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        // NB: the kernel uses off as addr2 for this op
+        sqe.off = infop as u64;
+        sqe.idx.fields = io_uring_sqe_idx_fields {
+            buf_index: 0,
+            personality: 0,
+            file_index: options as u32,
+        };
+    }
+
+    /// Install the registered (fixed) file at fixed_index as a regular process fd (6.8+)
+    ///
+    /// The cqe result is the new fd. By default, the new fd is O_CLOEXEC. flags can be
+    /// IORING_FIXED_FD_NO_CLOEXEC to change that.
+    pub fn prep_fixed_fd_install(&mut self, fixed_index: u32, flags: u32) {
+        self.prep_rw(IORING_OP_FIXED_FD_INSTALL, fixed_index as libc::c_int, std::ptr::null(), 0, 0);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.flags = SqeFlags::FIXED_FILE.bits();
+        sqe.args.install_fd_flags = flags;
+    }
+
+    /// Move nbytes from fd_in to fd_out, one of which needs to be a pipe, like splice(2) (5.7+)
+    ///
+    /// The offsets are SPLICE_OFF_NONE for pipes, or for using (and updating) the file
+    /// position. flags are SPLICE_F_* flags, including SPLICE_F_FD_IN_FIXED if fd_in is a fixed
+    /// file. The cqe result is the number of bytes moved.
+    pub fn prep_splice(&mut self, fd_in: libc::c_int, off_in: i64, fd_out: libc::c_int, off_out: i64, nbytes: u32, flags: u32) {
+        // NB: the kernel uses addr for off_in (splice_off_in)
+        self.prep_rw(IORING_OP_SPLICE, fd_out, off_in as u64 as *const libc::c_void, nbytes, off_out as u64);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.args.splice_flags = flags;
+        sqe.idx.fields = io_uring_sqe_idx_fields {
+            buf_index: 0,
+            personality: 0,
+            file_index: fd_in as u32, // splice_fd_in
+        };
+    }
+
+    /// Receive up to len bytes from socket fd into buf, with recv(2) flags (MSG_*) (5.6+)
+    pub fn prep_recv(&mut self, fd: libc::c_int, buf: *mut libc::c_void, len: u32, flags: libc::c_int) {
+        self.prep_rw(IORING_OP_RECV, fd, buf, len, 0);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.args.msg_flags = flags as u32;
+    }
+
+    /// Receive from socket fd into one or more buffers of the buffer ring bgid (6.10+)
+    ///
+    /// A bundle recv fills consecutive buffers of the ring (see BufRing), and reports them in
+    /// a single cqe: res is the total number of bytes received, and buffer_id() is the id of the
+    /// first buffer. Each buffer is filled completely before the next one is used, so the cqe
+    /// covers res / buf_size buffers, rounded up. Needs IORING_FEAT_RECVSEND_BUNDLE.
+    pub fn prep_recv_bundle(&mut self, fd: libc::c_int, bgid: u16) {
+        self.prep_recv(fd, std::ptr::null_mut(), 0, 0);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.flags |= SqeFlags::BUFFER_SELECT.bits();
+        sqe.ioprio |= IORING_RECVSEND_BUNDLE;
+        sqe.idx.buf_index = bgid; // buf_group
+    }
+
+    /// Read from fd into buffers of the buffer ring bgid, posting a cqe per read (6.7+)
+    ///
+    /// The read stays armed, and reads again whenever data is available, as long as the cqes
+    /// have IORING_CQE_F_MORE set. Each cqe has the bytes read and the buffer used (see
+    /// io_uring_cqe::multishot_read()). When the group runs out of buffers, the read is
+    /// terminated with -ENOBUFS. fd needs to be pollable (e.g., a pipe or a socket).
+    pub fn prep_read_multishot(&mut self, fd: libc::c_int, bgid: u16, off: u64) {
+        self.prep_rw(IORING_OP_READ_MULTISHOT, fd, std::ptr::null_mut(), 0, off);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.flags |= SqeFlags::BUFFER_SELECT.bits();
+        sqe.idx.buf_index = bgid; // buf_group
+    }
+
+    /// Cancel the previous sqe if it does not complete within ts (5.5+)
+    ///
+    /// This needs to directly follow the sqe it applies to, which needs to be linked to it with
+    /// set_link(). If the timeout expires, the operation is cancelled (its cqe reports
+    /// -ECANCELED) and the timeout cqe reports -ETIME. Otherwise, the timeout cqe reports
+    /// -ECANCELED (or -EALREADY, if the operation could not be cancelled in time). ts needs to
+    /// remain valid until the sqe is submitted. flags can be IORING_TIMEOUT_ABS, and ts is
+    /// measured with clock.
+    pub fn prep_link_timeout(&mut self, ts: &KernelTimespec, flags: u32, clock: TimeoutClock) {
+        let ptr = ts as *const KernelTimespec as *const libc::c_void;
+        self.prep_rw(IORING_OP_LINK_TIMEOUT, -1, ptr, 1, 0);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.args.timeout_flags = flags | clock.flags();
+    }
+
+    /// Timeout that completes when ts expires, or after count other completions (5.4+)
+    ///
+    /// A count of 0 means only the time matters. The cqe result is -ETIME if ts expired, and
+    /// 0 if count completions happened first. ts needs to remain valid until the sqe is
+    /// submitted. flags can be IORING_TIMEOUT_ABS, and ts is measured with clock.
+    pub fn prep_timeout(&mut self, ts: &KernelTimespec, count: u32, flags: u32, clock: TimeoutClock) {
+        let ptr = ts as *const KernelTimespec as *const libc::c_void;
+        self.prep_rw(IORING_OP_TIMEOUT, -1, ptr, 1, count as u64);
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.args.timeout_flags = flags | clock.flags();
+    }
+
+    /// Cancel the request with user_data (5.5+)
+    ///
+    /// The cqe result is 0 if the request was cancelled, -ENOENT if it was not found (e.g.,
+    /// because it already completed), and -EALREADY if it is running and cannot be cancelled.
+    /// The cancelled request posts its own cqe (typically, with -ECANCELED).
+    pub fn prep_cancel(&mut self, user_data: u64) {
+        self.prep_rw(IORING_OP_ASYNC_CANCEL, -1, user_data as *const libc::c_void, 0, 0);
+    }
+
+    /// Link the next sqe to this one (IOSQE_IO_LINK): it is only issued after this one
+    /// completes successfully, and is cancelled otherwise.
+    ///
+    /// Needs to be called after prep_*(), which reset the sqe flags.
+    pub fn set_link(&mut self) {
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.flags |= SqeFlags::IO_LINK.bits();
+    }
+
+    /// The fd of the sqe is an index into the registered files (IOSQE_FIXED_FILE)
+    ///
+    /// Needs to be called after prep_*(), which reset the sqe flags.
+    pub fn set_fixed_file(&mut self) {
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.flags |= SqeFlags::FIXED_FILE.bits();
+    }
+
+    /// Run the request with the credentials of a registered personality (see
+    /// IoUring::register_personality()), or with the submitter's credentials if None
+    ///
+    /// Needs to be called after prep_*(), which reset the personality.
+    pub fn set_personality(&mut self, personality: Option<u16>) {
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.idx.fields.personality = personality.unwrap_or(0);
+    }
+
+    /// Set the user_data, which is passed back in the request's cqe(s)
+    ///
+    /// NB: user_data from RESERVED_USER_DATA up is used for the sqes that the ring prepares
+    /// itself (DEADLINE_USER_DATA, CANCEL_USER_DATA, TIMEOUT_USER_DATA). It is only rejected
+    /// by the helpers that prepare such sqes (e.g., submit_with_deadline()), but the cqes of
+    /// other requests using it cannot be told apart from the ring's own.
     pub fn set_data(&mut self, data: u64) {
         let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
         sqe.user_data = data
     }
 
+    /// Set the IO priority (class: IOPRIO_CLASS_*, level: 0 (highest) to 7) of a read/write
+    ///
+    /// Needs to be called after prep_*(), which reset the priority. The kernel fails the
+    /// request if the class is not permitted (e.g., IOPRIO_CLASS_RT without CAP_SYS_ADMIN).
+    pub fn set_ioprio(&mut self, class: u8, level: u8) {
+        let sqe: &mut io_uring_sqe = unsafe { &mut *self.0 };
+        sqe.ioprio = ((class as u16) << IOPRIO_CLASS_SHIFT) | level as u16;
+    }
+
+    /// Vectored read at offset off, or at the current file position if off is OFF_CURRENT_POS
     pub fn prep_readv(&mut self, fd: libc::c_int, iovecs: *const libc::iovec, nr_vecs: u32, off: u64) {
         let ptr = iovecs as *const libc::c_void;
         self.prep_rw(IORING_OP_READV, fd, ptr, nr_vecs, off)
     }
 
+    /// Vectored read into the buffers of iovecs (see prep_readv())
+    ///
+    /// iovecs needs to be created with IoVecs::new_mut(), and remain valid until the operation
+    /// completes.
+    pub fn prep_readv_iovecs(&mut self, fd: libc::c_int, iovecs: &mut IoVecs, off: u64) {
+        assert!(iovecs.writable, "readv into read-only iovecs");
+        self.prep_readv(fd, iovecs.as_ptr(), iovecs.len() as u32, off)
+    }
+
+    /// Vectored write from the buffers of iovecs (see prep_writev())
+    ///
+    /// iovecs needs to remain valid until the operation completes.
+    pub fn prep_writev_iovecs(&mut self, fd: libc::c_int, iovecs: &IoVecs, off: u64) {
+        self.prep_writev(fd, iovecs.as_ptr(), iovecs.len() as u32, off)
+    }
+
+    /// Vectored write at offset off, or at the current file position if off is OFF_CURRENT_POS
     pub fn prep_writev(&mut self, fd: libc::c_int, iovecs: *const libc::iovec, nr_vecs: u32, off: u64) {
         let ptr = iovecs as *const libc::c_void;
-        self.prep_rw(IORING_OP_READV, fd, ptr, nr_vecs, off)
+        self.prep_rw(IORING_OP_WRITEV, fd, ptr, nr_vecs, off)
+    }
+
+    /// Read at offset off, or at the current file position if off is OFF_CURRENT_POS (5.6+)
+    pub fn prep_read(&mut self, fd: libc::c_int, buf: *mut libc::c_void, nbytes: u32, off: u64) {
+        self.prep_rw(IORING_OP_READ, fd, buf, nbytes, off)
+    }
+
+    /// Write at offset off, or at the current file position if off is OFF_CURRENT_POS (5.6+)
+    pub fn prep_write(&mut self, fd: libc::c_int, buf: *const libc::c_void, nbytes: u32, off: u64) {
+        self.prep_rw(IORING_OP_WRITE, fd, buf, nbytes, off)
     }
 
     /// This uses IoSlice, which is the buffer type ised in Write::write_vectored, and "is
@@ -395,34 +1811,353 @@ impl SQEntry {
 
 }
 
-/// setup functions
-impl IoUring {
+/// Variants of the prep_* functions that take nix types
+#[cfg(feature = "nix")]
+impl SQEntry<'_> {
+    /// prep_openat(), using nix flags. path needs to remain valid until the sqe is submitted.
+    pub fn prep_openat_nix(&mut self, dfd: libc::c_int, path: &std::ffi::CStr, flags: nix::fcntl::OFlag, mode: nix::sys::stat::Mode) {
+        self.prep_openat(dfd, path.as_ptr(), flags.bits(), mode.bits());
+    }
+
+    /// prep_connect(), using a nix address. addr needs to remain valid until the sqe is submitted.
+    pub fn prep_connect_nix<A: nix::sys::socket::SockaddrLike>(&mut self, fd: libc::c_int, addr: &A) {
+        self.prep_connect(fd, addr.as_ptr(), addr.len());
+    }
+}
+
+impl Builder {
+    /// new configuration for an io uring with nentries submission queue entries
+    ///
+    /// The kernel rounds nentries up to a power of two (use IoUring::sq_entries() for the final
+    /// size), and the CQ gets twice as many entries. build() fails with EINVAL if nentries is 0
+    /// or larger than the kernel maximum (32768).
+    pub fn new(nentries: libc::c_uint) -> Builder {
+        Builder {
+            nentries,
+            lock_memory: false,
+            flags: SetupFlags::empty(),
+            sq_thread_idle: 0,
+            sq_thread_cpu: 0,
+            cq_entries: 0,
+            numa_node: None,
+            sys: std::sync::Arc::new(LibcSyscalls),
+        }
+    }
+
+    /// Use a kernel thread to poll the submission queue (SQPOLL).
+    ///
+    /// The thread goes to sleep after idle_ms milliseconds without work, and then needs to be
+    /// woken up by io_uring_enter(), which submit() does when needed. If idle_ms is 0, the kernel
+    /// default (1s) is used. The idle timeout cannot be changed after the ring is created.
+    pub fn sqpoll(mut self, idle_ms: u32) -> Builder {
+        self.flags.insert(SetupFlags::SQPOLL);
+        self.sq_thread_idle = idle_ms;
+        self
+    }
+
+    /// Lock the ring memory (MAP_LOCKED), so that accessing it never causes a page fault.
+    ///
+    /// The locked memory counts against RLIMIT_MEMLOCK. Default: false.
+    //
+    // NB: The ring memory is allocated by the kernel, and (depending on the kernel version) mapped
+    // with VM_PFNMAP or VM_MIXEDMAP. mlock() does not apply to such mappings, so
+    // /proc/self/smaps will report them as not locked, but their pages are never swapped out
+    // anyway.
+    pub fn lock_memory(mut self, lock: bool) -> Builder {
+        self.lock_memory = lock;
+        self
+    }
+
+    /// Pin the SQPOLL thread to cpu (requires sqpoll())
+    ///
+    /// This sets IORING_SETUP_SQ_AFF, without which the kernel ignores the cpu.
+    pub fn sqpoll_cpu(mut self, cpu: u32) -> Builder {
+        self.flags.insert(SetupFlags::SQ_AFF);
+        self.sq_thread_cpu = cpu;
+        self
+    }
+
+    /// Size the CQ for (at least) entries cqes, instead of twice the SQ entries (5.5+)
+    ///
+    /// This sets IORING_SETUP_CQSIZE. The kernel rounds entries up to a power of two, and
+    /// fails the setup if it is smaller than the SQ entries.
+    pub fn cq_entries(mut self, entries: u32) -> Builder {
+        self.flags.insert(SetupFlags::CQSIZE);
+        self.cq_entries = entries;
+        self
+    }
+
+    /// Keep submitting the sqes of a batch even if one of them fails (5.18+).
+    ///
+    /// By default, the kernel stops submitting at the first sqe that fails (the error is
+    /// reported in its cqe), and the rest of the batch remains in the SQ. Default: false.
+    //
+    // NB: This is a setup flag (IORING_SETUP_SUBMIT_ALL), so it applies to every submission.
+    pub fn submit_all(mut self, submit_all: bool) -> Builder {
+        self.flags.set(SetupFlags::SUBMIT_ALL, submit_all);
+        self
+    }
+
+    /// Do not use the SQ index array (6.6+).
+    ///
+    /// sqes are submitted in the order they appear in the SQ, which saves a write per
+    /// submitted sqe. Default: false.
+    pub fn no_sqarray(mut self, no_sqarray: bool) -> Builder {
+        self.flags.set(SetupFlags::NO_SQARRAY, no_sqarray);
+        self
+    }
+
+    /// Use 128-byte sqes (IORING_SETUP_SQE128, 5.19+).
+    ///
+    /// Big sqes are needed for passthrough commands with more than URING_CMD_MAX bytes of
+    /// command data (e.g., NVMe, see SQEntry::prep_uring_cmd()). All other operations work as
+    /// with 64-byte sqes, but the sqe array takes twice the memory. Default: false.
+    pub fn sqe128(mut self, sqe128: bool) -> Builder {
+        self.flags.set(SetupFlags::SQE128, sqe128);
+        self
+    }
+
+    /// Use 32-byte cqes (IORING_SETUP_CQE32, 5.19+).
+    ///
+    /// The extra 16 bytes carry additional results of passthrough commands (see
+    /// CqeGuard::big_cqe()). Default: false.
+    pub fn cqe32(mut self, cqe32: bool) -> Builder {
+        self.flags.set(SetupFlags::CQE32, cqe32);
+        self
+    }
+
+    /// Allocate the ring memory on the given NUMA node.
+    ///
+    /// The kernel allocates the rings and sqes in io_uring_setup(), so the memory policy of the
+    /// calling thread is bound to node (MPOL_BIND) for the duration of the call. build() fails
+    /// if the kernel does not support NUMA policies, if the node is invalid, or if restoring the
+    /// previous policy fails.
+    //
+    // NB: The ring memory is mapped with VM_PFNMAP or VM_MIXEDMAP, so mbind() on the mappings
+    // would not have any effect.
+    pub fn numa_node(mut self, node: u32) -> Builder {
+        self.numa_node = Some(node);
+        self
+    }
+
+    /// Defer running completion work until the application waits for completions (6.1+).
+    ///
+    /// Completion work (task work) is not run asynchronously (e.g., via interrupts), but only
+    /// when entering the kernel to get events (submit_and_wait(), wait_only(), wait_cqe()). This
+    /// reduces latency spikes, but cqes are not posted until then. This also sets
+    /// IORING_SETUP_SINGLE_ISSUER: only the thread that built the ring may submit. Default:
+    /// false.
+    pub fn defer_taskrun(mut self, defer: bool) -> Builder {
+        self.flags.set(SetupFlags::SINGLE_ISSUER | SetupFlags::DEFER_TASKRUN, defer);
+        self
+    }
+
+    /// Create the ring disabled (IORING_SETUP_R_DISABLED, 5.10+)
+    ///
+    /// A disabled ring cannot be submitted to (io_uring_enter() fails with EBADFD) until it is
+    /// enabled with IoUring::enable_rings(). Restrictions can only be registered (with
+    /// IoUring::register_restrictions()) while the ring is disabled. Default: false.
+    pub fn disabled(mut self, disabled: bool) -> Builder {
+        self.flags.set(SetupFlags::R_DISABLED, disabled);
+        self
+    }
+
+    /// Use sys for the system calls, instead of the actual ones (advanced)
+    ///
+    /// This is meant for testing, e.g., to inject errors into the ring's system calls (see
+    /// Syscalls). Default: LibcSyscalls.
+    pub fn syscalls(mut self, sys: std::sync::Arc<dyn Syscalls>) -> Builder {
+        self.sys = sys;
+        self
+    }
+
+    /// initialize an io uring using this configuration
+    pub fn build(self) -> Result<IoUring, IoUringError> {
+        if self.flags.contains(SetupFlags::SQ_AFF) && !self.flags.contains(SetupFlags::SQPOLL) {
+            return Err(IoUringError::InvalidInput("sqpoll_cpu() requires sqpoll()".into()))
+        }
 
-    /// initialize an io uring
-    pub fn init(nentries: libc::c_uint) -> io::Result<IoUring> {
         let mut params: io_uring_params = unsafe { std::mem::zeroed() };
+        params.flags = self.flags.bits();
+        params.sq_thread_idle = self.sq_thread_idle;
+        params.sq_thread_cpu = self.sq_thread_cpu;
+        params.cq_entries = self.cq_entries;
         let params_p = &mut params as *mut io_uring_params;
-        let fd = unsafe { io_uring_setup(nentries, params_p) };
-        if fd < 0 {
-            return Err(io::Error::last_os_error())
-        }
+        // NB: the fd is owned until the ring is built, so that it is closed if restoring the
+        // memory policy fails
+        let setup = || {
+            let fd = unsafe { self.sys.setup(self.nentries, params_p) };
+            if fd < 0 {
+                return Err(SetupError::annotate(io::Error::last_os_error()))
+            }
+            Ok(unsafe { std::os::unix::io::FromRawFd::from_raw_fd(fd) })
+        };
+        let fd: std::os::unix::io::OwnedFd = match self.numa_node {
+            None => setup(),
+            Some(node) => with_numa_node(node, setup).and_then(|r| r),
+        }.map_err(IoUringError::Setup)?;
+        let fd = std::os::unix::io::IntoRawFd::into_raw_fd(fd);
 
         let mut ret : IoUring = IoUring {
-            fd: fd,
+            fd,
             sq: unsafe { std::mem::zeroed() },
             cq: unsafe { std::mem::zeroed() },
-            // NB: SetupFlags should be given by the user as an argument
             flags: SetupFlags::from_bits(params.flags).unwrap(),
+            sq_thread_idle: params.sq_thread_idle,
+            sq_thread_cpu: params.sq_thread_cpu,
+            features: FeatureFlags::from_bits_truncate(params.features),
+            in_flight: 0,
+            params: Params(params),
+            probe: None,
+            owned_iovecs: std::collections::HashMap::new(),
+            registered_index: None,
+            wait_arg: WaitArg::default(),
+            fixed_bufs: Vec::new(),
+            fixed_files: Vec::new(),
+            handlers: std::collections::HashMap::new(),
+            sys: self.sys.clone(),
+            autosubmit: 0,
+            disabled: self.flags.contains(SetupFlags::R_DISABLED),
         };
 
-        let err = ret.queue_mmap(&mut params);
-        if err.is_err() {
-            unsafe { close(ret.fd); }
+        // NB: on failure, queue_mmap() unmaps what it mapped, and Drop closes the fd
+        if let Err(e) = ret.queue_mmap(&mut params, self.lock_memory) {
+            return Err(IoUringError::Setup(e))
         }
         Ok(ret)
     }
+}
+
+/// setup functions
+impl IoUring {
+
+    /// initialize an io uring, using the default configuration
+    ///
+    /// See Builder::new() for how nentries is handled.
+    pub fn init(nentries: libc::c_uint) -> Result<IoUring, IoUringError> {
+        Builder::new(nentries).build()
+    }
+
+    /// Number of submission queue entries
+    ///
+    /// The kernel rounds up the requested number of entries to a power of two.
+    pub fn sq_entries(&self) -> u32 {
+        unsafe { *self.sq.kring_entries }
+    }
+
+    /// Number of completion queue entries
+    pub fn cq_entries(&self) -> u32 {
+        unsafe { *self.cq.kring_entries }
+    }
 
-    fn queue_mmap(&mut self, p: &mut io_uring_params) -> io::Result<()> {
+    /// Size of the sqes in bytes: 128 with Builder::sqe128(), 64 otherwise
+    pub fn sqe_size(&self) -> usize {
+        mem::size_of::<io_uring_sqe>() << self.sq.sqe_shift
+    }
+
+    /// Size of the cqes in bytes: 32 with Builder::cqe32(), 16 otherwise
+    pub fn cqe_size(&self) -> usize {
+        mem::size_of::<io_uring_cqe>() << self.cq.cqe_shift
+    }
+
+    /// Parameters negotiated with the kernel at setup
+    ///
+    /// These are the same for rings created with the same configuration (on the same kernel).
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    /// Reset the queues to a clean state, so that the ring can be reused (e.g., from a pool)
+    ///
+    /// sqes that were acquired via get_sqe() but not consumed by the kernel are discarded, and
+    /// so are the ready cqes. This is only safe when the ring is quiescent: no requests may be
+    /// in flight, since their completions would be posted after the reset, and (for SQPOLL)
+    /// the kernel thread must not be consuming sqes.
+    pub fn reset(&mut self) {
+        let khead = self.sq_khead();
+        let ktail_p = self.sq.ktail as *mut std::sync::atomic::AtomicU32;
+        unsafe { (&*ktail_p).store(khead.0, std::sync::atomic::Ordering::Release) };
+        self.sq.sqe_head = khead;
+        self.sq.sqe_tail = khead;
+
+        let ready = self.cq_ready();
+        self.cq_advance(ready);
+        self.in_flight = 0;
+    }
+
+    /// Cancel all in-flight requests, wait for their completions, and close the ring
+    ///
+    /// sqes that were acquired via get_sqe() but not submitted are discarded. Once this returns,
+    /// the kernel no longer references any buffers of the ring's requests. Cancellation uses
+    /// sync_cancel() (6.0+), or an IORING_OP_ASYNC_CANCEL matching any request (with
+    /// CANCEL_USER_DATA, 5.19+) on older kernels. The remaining cqes are consumed. The ring is
+    /// closed even if cancelling fails.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        // discard the sqes not yet handed to the kernel
+        self.sq.sqe_tail = self.sq.sqe_head;
+        if self.disabled {
+            // NB: nothing can have been submitted
+            return Ok(())
+        }
+
+        // NB: in_flight() does not account for multishot or CQE_SKIP_SUCCESS requests, so
+        // cancel unconditionally, and wait for cqes based on what the CQ says instead.
+        let mut cancelled = match self.sync_cancel(CancelCriteria::All, None) {
+            Ok(_) => true,
+            Err(IoUringError::Register(e)) if e.raw_os_error() == Some(libc::EINVAL) => {
+                self.submit_cancel_any()?;
+                false
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        // wait for the cancel's cqe (if async), and the final cqes of multishot requests
+        let mut more = std::collections::HashSet::new();
+        let mut cancel_res = 0;
+        loop {
+            // NB: entering with GETEVENTS also runs pending task work and flushes overflowed
+            // cqes, so the cqes of cancelled requests are in the CQ afterwards.
+            self.wait_only(0)?;
+            while let Some(cqe) = self.next_cqe() {
+                if !cancelled && cqe.user_data() == CANCEL_USER_DATA {
+                    cancelled = true;
+                    cancel_res = cqe.res();
+                } else if cqe.flags() & IORING_CQE_F_MORE != 0 {
+                    more.insert(cqe.user_data());
+                } else {
+                    more.remove(&cqe.user_data());
+                }
+            }
+            if cancelled && more.is_empty() {
+                break;
+            }
+            self.wait_only(1)?;
+        }
+
+        match cancel_res {
+            r if r < 0 => Err(io::Error::from_raw_os_error(-r)),
+            _ => Ok(()),
+        }
+    }
+
+    // submit an IORING_OP_ASYNC_CANCEL for all requests, for kernels without sync_cancel()
+    fn submit_cancel_any(&mut self) -> io::Result<()> {
+        if self.sq_space_left() == 0 {
+            self.submit()?;
+        }
+        let mut sqe = match self.get_sqe() {
+            Some(x) => x,
+            None => return Err(IoUringError::SqFull.into()),
+        };
+        sqe.prep_cancel(0);
+        let raw: &mut io_uring_sqe = unsafe { &mut *sqe.0 };
+        raw.args.cancel_flags = (AsyncCancelFlags::ALL | AsyncCancelFlags::ANY).bits();
+        sqe.set_data(CANCEL_USER_DATA);
+        self.submit()?;
+        Ok(())
+    }
+
+    fn queue_mmap(&mut self, p: &mut io_uring_params, locked: bool) -> io::Result<()> {
 
         // convinience function for computing pointer offsets
         let ptr_off = |p: *const libc::c_void, off: u32| -> *mut libc::c_uint {
@@ -434,38 +2169,54 @@ impl IoUring {
         /*
          * mmap submission queue
          */
+        // NB: these shadow the mmap()/munmap() helpers, so that the mappings go through sys
+        let sys = self.sys.clone();
+        let mmap = |len, fd, off, locked| unsafe { sys.mmap(len, fd, off, locked) };
+        let munmap = |addr, len| unsafe { sys.munmap(addr, len) };
         let sq = &mut self.sq;
 
         // From io_uring_setup(2):
         // The addition of sq_off.array to the length of the region accounts for the fact that the
         // ring located at the end of the data structure.
-        let sq_ring_sz  = {
-            let s1 = libc::size_t::try_from(p.sq_off.array).unwrap();
+        //
+        // Without the array, the SQ fields are still part of the same structure, which ends with
+        // the cqes (this is what liburing does).
+        let setup_flags = SetupFlags::from_bits_truncate(p.flags);
+        let no_sqarray = setup_flags.contains(SetupFlags::NO_SQARRAY);
+        let sqe_shift = setup_flags.contains(SetupFlags::SQE128) as u32;
+        let cqe_shift = setup_flags.contains(SetupFlags::CQE32) as u32;
+        let sq_ring_sz  = if no_sqarray {
+            let s1 = libc::size_t::try_from(p.cq_off.cqes).unwrap();
+            let s2 = libc::size_t::try_from(p.cq_entries).unwrap() * (mem::size_of::<io_uring_cqe>() << cqe_shift);
+            s1 + s2
+        } else {
+            let s1 = libc::size_t::try_from(p.sq_off.array).unwrap();
             let s2 = libc::size_t::try_from(p.sq_entries).unwrap() * mem::size_of::<u32>();
             s1 + s2
         };
 
         // mmap the submission queue structure
         let sq_ring_ptr = {
-            let ptr = unsafe { mmap(sq_ring_sz, self.fd, IORING_OFF_SQ_RING) };
+            let ptr = mmap(sq_ring_sz, self.fd, IORING_OFF_SQ_RING, locked);
             if ptr == libc::MAP_FAILED {
-                return Err(io::Error::last_os_error())
+                return Err(mmap_error(locked))
             }
             ptr
         };
 
         let sqes_size = {
             let nentries = libc::size_t::try_from(p.sq_entries).unwrap();
-            let esz = libc::size_t::try_from(mem::size_of::<io_uring_sqe>()).unwrap();
+            let esz = libc::size_t::try_from(mem::size_of::<io_uring_sqe>() << sqe_shift).unwrap();
             nentries*esz
         };
 
         // mmap the submission queue entries array
         let sqes_ptr = {
-            let sqp = unsafe { mmap(sqes_size, self.fd, IORING_OFF_SQES) };
+            let sqp = mmap(sqes_size, self.fd, IORING_OFF_SQES, locked);
             if sqp == libc::MAP_FAILED {
-                unsafe { munmap(sq_ring_ptr, sq_ring_sz) };
-                return Err(io::Error::last_os_error());
+                let err = mmap_error(locked);
+                munmap(sq_ring_ptr, sq_ring_sz);
+                return Err(err);
             }
             sqp as *mut io_uring_sqe
         };
@@ -482,8 +2233,9 @@ impl IoUring {
                 kring_entries : ptr_off(ptr, off.ring_entries),
                 kflags        : ptr_off(ptr, off.flags),
                 kdropped      : ptr_off(ptr, off.dropped),
-                array         : ptr_off(ptr, off.array),
+                array         : if no_sqarray { std::ptr::null_mut() } else { ptr_off(ptr, off.array) },
                 sqes          : sqes_ptr,
+                sqe_shift,
                 sqe_head      : std::num::Wrapping(0),
                 sqe_tail      : std::num::Wrapping(0),
                 ring_sz       : sq_ring_sz,
@@ -501,18 +2253,18 @@ impl IoUring {
 
         let cq_ring_sz = {
             let s1 = libc::size_t::try_from(p.cq_off.cqes).unwrap();
-            let s2 = libc::size_t::try_from(p.cq_entries).unwrap() * mem::size_of::<io_uring_cqe>();
+            let s2 = libc::size_t::try_from(p.cq_entries).unwrap() * (mem::size_of::<io_uring_cqe>() << cqe_shift);
             s1 + s2
         };
 
         let cq_ring_ptr  = {
-            let ptr = unsafe { mmap(cq_ring_sz, self.fd, IORING_OFF_CQ_RING) };
+            let ptr = mmap(cq_ring_sz, self.fd, IORING_OFF_CQ_RING, locked);
             if ptr == libc::MAP_FAILED {
-                unsafe {
-                    munmap(sq_ring_ptr, sq_ring_sz);
-                    munmap(sqes_ptr as *mut libc::c_void, sqes_size);
-                }
-                return Err(io::Error::last_os_error())
+                let err = mmap_error(locked);
+                munmap(sq_ring_ptr, sq_ring_sz);
+                munmap(sqes_ptr as *mut libc::c_void, sqes_size);
+                self.sq = unsafe { mem::zeroed() };
+                return Err(err)
             }
             ptr
         };
@@ -527,6 +2279,7 @@ impl IoUring {
                 kring_entries: ptr_off(ptr, off.ring_entries),
                 overflow: ptr_off(ptr, off.overflow),
                 cqes: ptr_off(ptr, off.cqes) as *mut io_uring_cqe,
+                cqe_shift,
                 ring_sz: cq_ring_sz,
                 ring_ptr: ptr
             }
@@ -535,18 +2288,46 @@ impl IoUring {
         Ok(())
     }
 
+    /// Unmap the queues
+    ///
+    /// NB: the queues of a ring whose queue_mmap() failed are zeroed, so this skips them.
     fn queue_unmap(&mut self) {
-        let sqes_size = {
-            let nentries_ = unsafe { *self.sq.kring_entries };
-            let nentries = libc::size_t::try_from(nentries_).unwrap();
-            let esz = libc::size_t::try_from(mem::size_of::<io_uring_sqe>()).unwrap();
-            nentries*esz
-        };
-        unsafe {
-            munmap(self.sq.ring_ptr, self.sq.ring_sz);
-            munmap(self.sq.sqes as *mut libc::c_void, sqes_size);
-            munmap(self.cq.ring_ptr, self.cq.ring_sz);
+        if !self.sq.ring_ptr.is_null() {
+            let sqes_size = {
+                let nentries_ = unsafe { *self.sq.kring_entries };
+                let nentries = libc::size_t::try_from(nentries_).unwrap();
+                let esz = libc::size_t::try_from(self.sqe_size()).unwrap();
+                nentries*esz
+            };
+            unsafe {
+                self.sys.munmap(self.sq.ring_ptr, self.sq.ring_sz);
+                self.sys.munmap(self.sq.sqes as *mut libc::c_void, sqes_size);
+            }
         }
+        if !self.cq.ring_ptr.is_null() {
+            unsafe { self.sys.munmap(self.cq.ring_ptr, self.cq.ring_sz) };
+        }
+    }
+
+    /// Fault in every page of the SQ, CQ, and sqe mappings
+    ///
+    /// The rings are mapped with MAP_POPULATE, but (e.g., after the pages were reclaimed, or
+    /// with a kernel that does not populate them) the first access can still page fault. This
+    /// is for latency-critical applications that want to take these faults upfront.
+    pub fn warm(&mut self) {
+        // NB: the kernel concurrently updates the rings, so read and write each page with an
+        // atomic no-op, which does not change its contents.
+        let touch = |ptr: *mut libc::c_void, len: libc::size_t| {
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+            for off in (0..len).step_by(page_size) {
+                let p = (ptr as *mut u8).wrapping_add(off) as *const std::sync::atomic::AtomicU8;
+                unsafe { (*p).fetch_or(0, std::sync::atomic::Ordering::Relaxed) };
+            }
+        };
+        let sqes_size = unsafe { *self.sq.kring_entries } as usize * self.sqe_size();
+        touch(self.sq.ring_ptr, self.sq.ring_sz);
+        touch(self.sq.sqes as *mut libc::c_void, sqes_size);
+        touch(self.cq.ring_ptr, self.cq.ring_sz);
     }
 
 
@@ -554,11 +2335,35 @@ impl IoUring {
 
 impl Drop for IoUring {
     fn drop(&mut self) {
+        self.release_owned();
         self.queue_unmap();
         unsafe { close(self.fd) };
     }
 }
 
+/// The ring, as a mio event source: it is readable when there are cqes in the CQ
+///
+/// This registers the ring fd itself, which the kernel marks readable whenever a cqe is
+/// posted. mio is edge-triggered, so all cqes need to be consumed (e.g., with
+/// for_each_completion()) on every readable event. Rings with DEFER_TASKRUN only post cqes
+/// when the submitter enters the kernel, so they are not useful as sources.
+#[cfg(feature = "mio")]
+impl mio::event::Source for IoUring {
+    fn register(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest)
+    -> io::Result<()> {
+        mio::unix::SourceFd(&self.fd).register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest)
+    -> io::Result<()> {
+        mio::unix::SourceFd(&self.fd).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        mio::unix::SourceFd(&self.fd).deregister(registry)
+    }
+}
+
 
 // queue functions: SQ
 impl IoUring {
@@ -566,25 +2371,132 @@ impl IoUring {
     /// Get a new submission queue entry (sqe)
     ///
     /// If queue is full, return None
-    pub fn get_sqe(&mut self) -> Option<SQEntry> {
+    pub fn get_sqe(&mut self) -> Option<SQEntry<'_>> {
+        // NB: submit errors cannot be reported here. The sqes remain queued, so the error is
+        // reported by the next explicit submit.
+        if self.autosubmit > 0 && self.sq_ready() >= self.autosubmit {
+            let _ = self.submit();
+        }
+
+        // NB: compare against the kernel head, not sqe_head: flushed sqes still occupy their
+        // slots until the kernel consumes them (e.g., with SQPOLL, or after a short submit).
+        let khead = self.sq_khead();
         let sq = &mut self.sq;
         let next = sq.sqe_tail + std::num::Wrapping(1);
         let nentries: u32 = unsafe { *sq.kring_entries };
-        if (next - sq.sqe_head).0 > nentries {
+        if (next - khead).0 > nentries {
             return None
         }
 
         let mask = unsafe { *sq.kring_mask };
         let idx = sq.sqe_tail.0 & mask;
-        let sqe_p = unsafe { sq.sqes.offset(idx as isize) };
+        let sqe_p = unsafe { sq.sqes.add((idx << sq.sqe_shift) as usize) };
+        // NB: the prep_*() functions overwrite the whole sqe, but zero it anyway, so that stale
+        // bytes of the previous use of the slot (e.g., a buf_index in the idx union) never
+        // reach the kernel, even for sqes that are prepared field by field. For 128-byte sqes,
+        // this also clears the second half, which prep_*() do not touch.
+        unsafe { std::ptr::write_bytes(sqe_p, 0, 1 << sq.sqe_shift) };
 
         sq.sqe_tail = next;
-        Some(SQEntry(sqe_p))
+        Some(SQEntry(sqe_p, std::marker::PhantomData, sq.sqe_shift == 1))
+    }
+
+    /// Submit automatically when threshold sqes are queued
+    ///
+    /// With a non-zero threshold, get_sqe() submits the queued sqes before handing out a new
+    /// one if threshold (or more) are queued, so callers can keep preparing sqes without
+    /// overflowing the SQ. A threshold of 0 (the default) disables automatic submission.
+    pub fn set_autosubmit(&mut self, threshold: u32) {
+        self.autosubmit = threshold;
+    }
+
+    /// Copy sqe into the next free SQ slot, for operations without a prep_*() function
+    ///
+    /// If the SQ is full, sqe is returned back.
+    pub fn push_raw(&mut self, sqe: io_uring_sqe) -> Result<(), io_uring_sqe> {
+        match self.get_sqe() {
+            Some(entry) => {
+                unsafe { *entry.0 = sqe };
+                Ok(())
+            },
+            None => Err(sqe),
+        }
+    }
+
+    /// Maximum number of requests that try_get_sqe() allows in flight
+    ///
+    /// Without IORING_FEAT_NODROP (5.5+), the kernel drops completions that do not fit in the
+    /// CQ, so this is the CQ size. With it, the kernel keeps overflowed completions until there
+    /// is space in the CQ, so there is no limit.
+    pub fn max_in_flight(&self) -> u32 {
+        if self.features.contains(FeatureFlags::NODROP) {
+            u32::MAX
+        } else {
+            self.cq_entries()
+        }
+    }
+
+    /// Like get_sqe(), but also return None if another request would exceed max_in_flight()
+    ///
+    /// Counts both the in-flight requests and the sqes that are ready to be submitted.
+    pub fn try_get_sqe(&mut self) -> Option<SQEntry<'_>> {
+        let pending = self.in_flight as u64 + self.sq_ready() as u64;
+        if pending >= self.max_in_flight() as u64 {
+            return None
+        }
+        self.get_sqe()
     }
 
-    /// Returns: sqes submited
+    /// Load the kernel SQ head
+    fn sq_khead(&self) -> std::num::Wrapping<u32> {
+        let khead_p = self.sq.khead as *mut std::sync::atomic::AtomicU32;
+        let khead = unsafe { (&*khead_p).load(std::sync::atomic::Ordering::Acquire) };
+        std::num::Wrapping(khead)
+    }
+
+    /// Number of sqes acquired via get_sqe() that the kernel has not consumed yet
+    // liburing: io_uring_sq_ready()
+    pub fn sq_ready(&self) -> u32 {
+        (self.sq.sqe_tail - self.sq_khead()).0
+    }
+
+    /// Number of sqes that can be acquired via get_sqe() before the SQ is full
+    // liburing: io_uring_sq_space_left()
+    pub fn sq_space_left(&self) -> u32 {
+        self.sq_entries() - self.sq_ready()
+    }
+
+    /// Flush the sqes acquired via get_sqe() to the kernel SQ, without entering the kernel
+    ///
+    /// This publishes the new SQ tail, which an awake SQPOLL thread picks up on its own
+    /// (otherwise, see kick_sqpoll()). Without SQPOLL, the flushed sqes are submitted by the
+    /// next submit(). Returns the number of sqes flushed.
+    pub fn flush_sq(&mut self) -> u32 {
+        let flushed = self.flush_sq_entries();
+        if self.flags.contains(SetupFlags::SQPOLL) {
+            self.in_flight += flushed;
+        }
+        flushed
+    }
+
+    /// Flush the sqes acquired via get_sqe() to the kernel SQ, for submitting them
+    ///
+    /// Returns: number of sqes to submit. For SQPOLL, these are the sqes flushed now. Otherwise,
+    /// these are all the sqes in the kernel SQ: if a previous io_uring_enter() submitted fewer
+    /// sqes than requested (e.g., because an sqe failed), the rest remain in the SQ, and are
+    /// submitted again.
     // liburing: __io_uring_flush_sq()
-    fn flush_sq(&mut self) -> u32 {
+    fn flush_sq_to_submit(&mut self) -> u32 {
+        let flushed = self.flush_sq_entries();
+        if self.flags.contains(SetupFlags::SQPOLL) {
+            return flushed;
+        }
+        let ktail = std::num::Wrapping(unsafe { *self.sq.ktail });
+        (ktail - self.sq_khead()).0
+    }
+
+    /// Returns: number of sqes flushed
+    fn flush_sq_entries(&mut self) -> u32 {
         let sq = &mut self.sq;
 
         // NB: This works even if there is an overflow on sqe_{tail,head}
@@ -594,13 +2506,18 @@ impl IoUring {
         }
 
         let mask = unsafe { *sq.kring_mask };
-        let mut ktail = std::num::Wrapping( {unsafe { *sq.ktail }} );
+        let mut ktail = std::num::Wrapping(unsafe { *sq.ktail });
         let mut submitted = 0;
+        // NB: without the array, the kernel uses sqes in order. This matches how get_sqe()
+        // hands them out, since sqe_head always equals the kernel tail after a flush.
+        let no_sqarray = self.flags.contains(SetupFlags::NO_SQARRAY);
         loop  {
-            // I don't see how this can overflow isize, so skip the runtime test
-            let aoff = (ktail.0 & mask) as isize;
-            unsafe {
-                *sq.array.offset(aoff) = sq.sqe_head.0 & mask;
+            if !no_sqarray {
+                // I don't see how this can overflow isize, so skip the runtime test
+                let aoff = (ktail.0 & mask) as isize;
+                unsafe {
+                    *sq.array.offset(aoff) = sq.sqe_head.0 & mask;
+                }
             }
             sq.sqe_head += std::num::Wrapping(1);
             ktail += std::num::Wrapping(1);
@@ -635,6 +2552,13 @@ impl IoUring {
             return Some(EnterFlags::empty())
         }
 
+        // If the SQPOLL thread is awake, there is no need for a system call: it will pick up the
+        // new tail on its own.
+        //
+        // NB: The tail store in flush_sq_entries() needs to be ordered before reading the flags.
+        // Otherwise, we might read a stale NEED_WAKEUP while the thread goes to sleep without
+        // seeing the new tail (see io_uring_smp_mb() in liburing).
+        std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
         let need_wakeup = unsafe {
             let flags = std::ptr::read_volatile(self.sq.kflags);
             SQFlags::from_bits_unchecked(flags).contains(SQFlags::NEED_WAKEUP)
@@ -646,17 +2570,28 @@ impl IoUring {
         None
     }
 
+    /// The fd and flags to use for io_uring_enter(): the registered ring index, if any
+    fn enter_target(&self, flags: EnterFlags) -> (libc::c_int, EnterFlags) {
+        match self.registered_index {
+            Some(idx) => (idx as libc::c_int, flags | EnterFlags::REGISTERED_RING),
+            None => (self.fd, flags),
+        }
+    }
+
     // liburing: __io_uring_submit()
-    fn do_submit(&mut self, submitted: u32, mut wait_nr: u32) -> std::io::Result<u32> {
+    fn do_submit(&mut self, submitted: u32, mut wait_nr: u32) -> Result<u32, IoUringError> {
 
-        let flags = match (wait_nr, self.sq_ring_needs_enter()) {
-            (0, None) => {
+        // NB: entering with GETEVENTS flushes overflowed cqes into the CQ ring
+        let getevents = wait_nr > 0 || self.cq_overflow_pending();
+        let flags = match (getevents, self.sq_ring_needs_enter()) {
+            (false, None) => {
                 // No need to issue system call, just return
+                self.in_flight += submitted;
                 return Ok(submitted);
             },
-            (0, Some(x)) => x,
-            (_, None) => EnterFlags::GETEVENTS,
-            (_, Some(mut x)) => {
+            (false, Some(x)) => x,
+            (true, None) => EnterFlags::GETEVENTS,
+            (true, Some(mut x)) => {
                 x.set(EnterFlags::GETEVENTS, true);
                 x
             }
@@ -669,99 +2604,3383 @@ impl IoUring {
             wait_nr = submitted;
         }
 
-        let null = 0 as *mut libc::sigset_t;
-        let ret = unsafe {
-            io_uring_enter(self.fd, submitted, wait_nr, flags.bits(), null)
+        let null = std::ptr::null_mut::<libc::sigset_t>();
+        let (fd, flags) = self.enter_target(flags);
+        let enter = || -> Result<libc::c_long, io::Error> {
+            let ret = unsafe { io_uring_enter(&*self.sys, fd, submitted, wait_nr, flags.bits(), null) };
+            if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(ret) }
         };
 
-        if ret < 0 {
+        let ret = match enter() {
+            // NB: EBUSY means that the CQ has overflowed, and the kernel will not accept new
+            // submissions until the overflowed cqes are flushed into the CQ ring. Entering
+            // with GETEVENTS (and nothing to submit or wait for) flushes them, so do that and
+            // retry once.
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                let (fd, getevents) = self.enter_target(EnterFlags::GETEVENTS);
+                match unsafe { io_uring_enter(&*self.sys, fd, 0, 0, getevents.bits(), null) } {
+                    r if r < 0 => Err(e),
+                    _ => enter(),
+                }
+            },
+            r => r,
+        };
+
+        match ret {
             // wrap errno
-            Err(std::io::Error::last_os_error())
-        } else {
-            Ok(ret as u32)
+            Err(e) => Err(IoUringError::Submit(e)),
+            Ok(ret) => {
+                self.in_flight += ret as u32;
+                Ok(ret as u32)
+            }
         }
     }
 
+    /// Are there overflowed cqes, which did not fit in the CQ ring? (5.19+)
+    ///
+    /// This reads IORING_SQ_CQ_OVERFLOW from the SQ flags, which is cheaper than checking the
+    /// overflow counter. Submitting flushes them into the CQ ring, as space permits.
+    // liburing: cq_ring_needs_flush()
+    pub fn cq_overflow_pending(&self) -> bool {
+        let flags = unsafe { std::ptr::read_volatile(self.sq.kflags) };
+        SQFlags::from_bits_truncate(flags).contains(SQFlags::CQ_OVERFLOW)
+    }
+
     // liburing: __io_uring_submit_and_wait
-    fn do_submit_and_wait(&mut self, wait_nr: u32) -> std::io::Result<u32> {
-        let submitted = self.flush_sq();
+    fn do_submit_and_wait(&mut self, wait_nr: u32) -> Result<u32, IoUringError> {
+        // NB: the kernel would fail with EBADFD, which is not very telling
+        if self.disabled {
+            return Err(IoUringError::RingDisabled)
+        }
+        let submitted = self.flush_sq_to_submit();
         if submitted > 0 {
             return self.do_submit(submitted, wait_nr)
         }
         Ok(0)
     }
 
+    /// Idle timeout (in ms) of the SQPOLL thread, as configured with Builder::sqpoll()
+    pub fn sq_thread_idle(&self) -> u32 {
+        self.sq_thread_idle
+    }
+
+    /// Idle timeout (in ms) that the SQPOLL thread actually uses, or 0 without SQPOLL
+    ///
+    /// The kernel does not report the value back, but it replaces a 0 idle timeout with one
+    /// second, which this accounts for. (It also rounds the timeout up to scheduler ticks.) The
+    /// timeout cannot be changed after setup.
+    pub fn sqpoll_idle_ms(&self) -> u32 {
+        match (self.flags.contains(SetupFlags::SQPOLL), self.sq_thread_idle) {
+            (false, _) => 0,
+            (true, 0) => 1000,
+            (true, idle) => idle,
+        }
+    }
+
+    /// Can SQPOLL be used without privileges (CAP_SYS_ADMIN)?
+    ///
+    /// Unprivileged SQPOLL was added in 5.11, together with IORING_FEAT_SQPOLL_NONFIXED (SQPOLL
+    /// without fixed files), which is what this checks.
+    pub fn sqpoll_available(&self) -> bool {
+        self.features.contains(FeatureFlags::SQPOLL_NONFIXED)
+    }
+
+    /// The cpu that the SQPOLL thread is pinned to, if any (see Builder::sqpoll_cpu())
+    pub fn sqpoll_cpu(&self) -> Option<u32> {
+        if self.flags.contains(SetupFlags::SQ_AFF) {
+            Some(self.sq_thread_cpu)
+        } else {
+            None
+        }
+    }
+
+    /// Wake up the SQPOLL thread, so that it processes queued sqes.
+    ///
+    /// submit() wakes up the thread when needed, so this is only useful for controlling when
+    /// the thread is woken up explicitly. Returns IoUringError::InvalidInput for non-SQPOLL rings.
+    pub fn kick_sqpoll(&self) -> Result<(), IoUringError> {
+        if !self.flags.contains(SetupFlags::SQPOLL) {
+            return Err(IoUringError::InvalidInput("ring does not use SQPOLL".into()))
+        }
+        if self.disabled {
+            return Err(IoUringError::RingDisabled)
+        }
+
+        let null = std::ptr::null_mut::<libc::sigset_t>();
+        let (fd, flags) = self.enter_target(EnterFlags::SQ_WAKEUP);
+        let ret = unsafe { io_uring_enter(&*self.sys, fd, 0, 0, flags.bits(), null) };
+        if ret < 0 {
+            return Err(IoUringError::Submit(std::io::Error::last_os_error()))
+        }
+        Ok(())
+    }
+
+    /// Advanced: call io_uring_enter() directly, with the given arguments
+    ///
+    /// The sqes acquired via get_sqe() are flushed to the kernel SQ first (see flush_sq()), and
+    /// the kernel submits up to to_submit of them. With IORING_ENTER_GETEVENTS, it also waits
+    /// for min_complete cqes. The registered ring index is used if there is one, but nothing
+    /// else is done on top of the system call: there is no EBUSY retry, and wait_nr is not
+    /// capped as in submit_and_wait(). Returns the number of sqes submitted.
+    pub fn enter(&mut self, to_submit: u32, min_complete: u32, flags: u32) -> io::Result<u32> {
+        if self.disabled {
+            return Err(IoUringError::RingDisabled.into())
+        }
+        self.flush_sq();
+
+        let null = std::ptr::null_mut::<libc::sigset_t>();
+        let (fd, target) = self.enter_target(EnterFlags::empty());
+        let ret = unsafe { io_uring_enter(&*self.sys, fd, to_submit, min_complete, flags | target.bits(), null) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        // NB: flush_sq() accounted for the sqes already with SQPOLL
+        if !self.flags.contains(SetupFlags::SQPOLL) {
+            self.in_flight += ret as u32;
+        }
+        Ok(ret as u32)
+    }
+
     /// Submit sqes acquired via get_sqe() to the kernel.
     ///
-    /// Returns number of sqes submitted, or error if io_uring_enter() failed.
-    pub fn submit(&mut self) -> std::io::Result<u32> {
+    /// Returns number of sqes submitted, or error if io_uring_enter() failed. If the kernel
+    /// refuses the submission with EBUSY because the CQ has overflowed, the overflowed cqes are
+    /// flushed into the CQ ring and the submission is retried once. This only helps if there is
+    /// space in the CQ ring, so the caller still needs to consume cqes to avoid EBUSY errors.
+    pub fn submit(&mut self) -> Result<u32, IoUringError> {
         self.do_submit_and_wait(0)
     }
-}
 
-// queue functions: CQ
-impl IoUring {
-    pub fn cq_iter(&self) -> CqIter {
-        let cq_head = unsafe { *self.sq.khead };
-        CqIter {
-            curr: std::num::Wrapping(cq_head),
-            cq: &self.cq,
+    /// Submit queued sqes without waiting: same as submit(), named for event loops.
+    pub fn kick(&mut self) -> Result<u32, IoUringError> {
+        self.submit()
+    }
+
+    /// Submit queued sqes, and call f on every ready cqe without waiting (see
+    /// for_each_completion())
+    ///
+    /// This is the step that an event loop repeats. Returns the number of cqes processed.
+    pub fn pump<F: FnMut(&io_uring_cqe)>(&mut self, f: F) -> Result<u32, IoUringError> {
+        self.submit()?;
+        Ok(self.for_each_completion(f))
+    }
+
+    /// Submit sqes acquired via get_sqe() to the kernel, and wait for wait_nr completions.
+    ///
+    /// Returns number of sqes submitted, or error if io_uring_enter() failed. Waiting for more
+    /// completions than the CQ can hold is rejected with IoUringError::InvalidInput (and nothing
+    /// is submitted).
+    pub fn submit_and_wait(&mut self, wait_nr: u32) -> Result<u32, IoUringError> {
+        if wait_nr > self.cq_entries() {
+            let msg = format!("wait_nr ({}) exceeds CQ entries ({})", wait_nr, self.cq_entries());
+            return Err(IoUringError::InvalidInput(msg))
         }
+        self.do_submit_and_wait(wait_nr)
     }
-}
 
-impl<'a> Iterator for CqIter<'a> {
-    type Item = io_uring_cqe;
+    /// Queue an fsync of fd that is issued only after all previously queued sqes complete
+    ///
+    /// This uses IOSQE_IO_DRAIN, which serializes the whole ring at this point: sqes queued
+    /// after the barrier are not issued until the fsync completes either. Returns None if the
+    /// SQ is full.
+    pub fn fsync_barrier(&mut self, fd: libc::c_int, datasync: bool, user_data: u64) -> Option<()> {
+        let mut sqe = self.get_sqe()?;
+        sqe.prep_fsync(fd, datasync);
+        sqe.set_data(user_data);
+        unsafe { (*sqe.0).flags |= SqeFlags::IO_DRAIN.bits() };
+        Some(())
+    }
 
-    fn next(&mut self) -> Option<io_uring_cqe> {
-        let ktail_p = self.cq.ktail as *mut std::sync::atomic::AtomicU32;
-        let tail_ = unsafe { (&*ktail_p).load(std::sync::atomic::Ordering::Acquire) };
-        let tail = std::num::Wrapping(tail_);
-        if self.curr == tail {
-            return None
+    /// Create an io-wq worker thread for the ring up front
+    ///
+    /// Blocking operations are executed by worker threads, which the kernel creates on demand.
+    /// This submits a nop that is forced to a worker (IOSQE_ASYNC) and waits for it, so that
+    /// the first blocking operation does not pay for creating the worker. Workers exit after
+    /// being idle for a while, though.
+    ///
+    /// NB: Like submit_read_full(), this requires an idle ring.
+    pub fn prime_workers(&mut self) -> Result<(), IoUringError> {
+        if self.sq_ready() > 0 || self.cq_ready() > 0 {
+            return Err(IoUringError::NotIdle)
         }
 
-        let mask = unsafe { *self.cq.kring_mask };
-        let idx = self.curr.0 & mask;
-        let cqe: io_uring_cqe = unsafe {
-            *self.cq.cqes.offset(idx as isize)
+        // NB: the ring is idle, so there is always an sqe
+        let mut sqe = self.get_sqe().unwrap();
+        sqe.prep_nop();
+        unsafe { (*sqe.0).flags |= SqeFlags::ASYNC.bits() };
+        self.submit_and_wait(1)?;
+        self.wait_cqe()?;
+        Ok(())
+    }
+
+    /// Number of live io-wq workers of the calling thread
+    ///
+    /// Workers are per task, not per ring: this counts the workers created for the requests
+    /// that the calling thread submitted, to any ring. The workers of other threads (or, with
+    /// SQPOLL, of the SQ thread) are not counted, even for requests of this ring. Workers show
+    /// up as threads named iou-wrk-<tid>, which this counts. With tids of more than 7 digits
+    /// (pid_max above the default), the name is truncated, and workers of threads whose tids
+    /// share the same prefix are counted too.
+    ///
+    /// The kernel does not report whether a worker is bounded or unbounded (neither in /proc,
+    /// nor via IORING_REGISTER_IOWQ_MAX_WORKERS, which only returns the limits), so this is
+    /// the total: see iowq_max_workers() for the limits.
+    pub fn iowq_workers(&self) -> io::Result<u32> {
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+        // NB: comm is truncated to TASK_COMM_LEN - 1 bytes
+        const COMM_MAX: usize = 15;
+        let mut name = format!("iou-wrk-{}", tid);
+        name.truncate(COMM_MAX);
+        let mut nr = 0;
+        for entry in std::fs::read_dir("/proc/self/task")? {
+            let comm = match std::fs::read_to_string(entry?.path().join("comm")) {
+                Ok(x) => x,
+                // the thread exited
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if comm.strip_suffix('\n') == Some(name.as_str()) {
+                nr += 1;
+            }
+        }
+        Ok(nr)
+    }
+
+    /// Read buf from fd at offset off, re-submitting on short reads until buf is full or EOF.
+    ///
+    /// This is a convenience: it waits for the read, and returns a single cqe with the given
+    /// user_data, and the total number of bytes read (or -errno) as the result. Use
+    /// SQEntry::prep_read() for the exact kernel semantics.
+    ///
+    /// NB: The ring must have no other requests in flight, since their completions would be
+    /// consumed. IoUringError::NotIdle is returned if there are queued sqes or ready cqes. On
+    /// errors, the read is neither left queued nor in flight, since it points to buf.
+    pub fn submit_read_full(&mut self, fd: libc::c_int, buf: &mut [u8], off: u64, user_data: u64)
+    -> Result<io_uring_cqe, IoUringError> {
+        let ptr = buf.as_mut_ptr() as *const libc::c_void;
+        self.submit_rw_full(IORING_OP_READ, fd, ptr, buf.len(), off, user_data)
+    }
+
+    /// Write buf to fd at offset off, re-submitting on short writes until all of buf is written.
+    ///
+    /// See submit_read_full().
+    pub fn submit_write_full(&mut self, fd: libc::c_int, buf: &[u8], off: u64, user_data: u64)
+    -> Result<io_uring_cqe, IoUringError> {
+        let ptr = buf.as_ptr() as *const libc::c_void;
+        self.submit_rw_full(IORING_OP_WRITE, fd, ptr, buf.len(), off, user_data)
+    }
+
+    fn submit_rw_full(&mut self, op: u8, fd: libc::c_int, buf: *const libc::c_void, len: usize, off: u64, user_data: u64)
+    -> Result<io_uring_cqe, IoUringError> {
+        if self.sq_ready() > 0 || self.cq_ready() > 0 {
+            return Err(IoUringError::NotIdle)
+        }
+
+        let mut done: usize = 0;
+        let res = loop {
+            if done == len {
+                break done as i32;
+            }
+            let remaining = (len - done).try_into().unwrap_or(u32::MAX);
+            // NB: OFF_CURRENT_POS: the kernel updates the file position
+            let curr_off = if off == OFF_CURRENT_POS { off } else { off + done as u64 };
+            let addr = unsafe { (buf as *const u8).add(done) } as *const libc::c_void;
+            // NB: the ring is idle, so there is always an sqe
+            let mut sqe = self.get_sqe().unwrap();
+            sqe.prep_rw(op, fd, addr, remaining, curr_off);
+            sqe.set_data(user_data);
+
+            let cqe = self.submit_borrowed(user_data)?;
+            debug_assert_eq!(cqe.user_data(), user_data);
+            match cqe.res() {
+                r if r < 0 => break r,
+                0 => break done as i32, // EOF
+                r => done += r as usize,
+            }
         };
-        self.curr += std::num::Wrapping(1);
-        Some(cqe)
 
+        Ok(io_uring_cqe { user_data, res, flags: 0 })
     }
-}
 
-impl IoUring {
-    // /// Fill the next SQEntry in the queue via the provided function.
-    // ///
-    // /// Returns:
-    // ///  None: queue is full (fill function was not executed)
-    // ///  Some(Err(x)): fill function returned Err(x), queue was not updated
-    // ///  Some(Err(x)): fill function returned Ok(x), queue was updated
-    // pub fn fill_next_sqe<F, E, R>(&mut self, fill: F) -> Option<Result<E,R>>
-    // where F: FnOnce(&mut SQEntry) -> Result<E,R> {
-    //     let sq = &mut self.sq;
-    //     let next: u32 = sq.sqe_tail + 1;
-    //     let nentries: u32 = unsafe { *sq.kring_entries };
-    //     if next - sq.sqe_head > nentries {
-    //         return None
-    //     }
+    /// Submit the queued sqe (with user_data) of an otherwise idle ring, and wait for its cqe
+    ///
+    /// The sqe may point to memory that the caller only borrows, so it is never left behind
+    /// when this fails: an sqe that the kernel has not consumed is dropped from the SQ, and a
+    /// request that is in flight is cancelled, and its cqe waited for, before returning.
+    fn submit_borrowed(&mut self, user_data: u64) -> Result<io_uring_cqe, IoUringError> {
+        if let Err(e) = self.submit_and_wait(1) {
+            if !self.unsubmit() {
+                self.cancel_borrowed(user_data);
+            }
+            return Err(e)
+        }
+        self.wait_cqe().inspect_err(|_| self.cancel_borrowed(user_data))
+    }
 
-    //     let mut sqe = {
-    //         let mask = unsafe { *sq.kring_mask };
-    //         let idx = sq.sqe_tail & mask;
-    //         let sqe_p = unsafe { sq.sqes.offset(idx as isize) };
-    //         SQEntry(sqe_p)
-    //     };
-    //     sqe.reset();
+    // Drop the sqes that the kernel has not consumed. Returns false if there are none, or if
+    // they cannot be dropped because the SQPOLL thread may be consuming them.
+    fn unsubmit(&mut self) -> bool {
+        let khead = self.sq_khead();
+        let ktail = std::num::Wrapping(unsafe { *self.sq.ktail });
+        if ktail != khead && self.flags.contains(SetupFlags::SQPOLL) {
+            return false
+        }
+        if ktail == khead && self.sq.sqe_head == self.sq.sqe_tail {
+            return false
+        }
+        let ktail_p = self.sq.ktail as *mut std::sync::atomic::AtomicU32;
+        unsafe { (&*ktail_p).store(khead.0, std::sync::atomic::Ordering::Release) };
+        self.sq.sqe_head = khead;
+        self.sq.sqe_tail = khead;
+        true
+    }
 
-    //     let fret = fill(&mut sqe);
-    //     if fret.is_ok() {
-    //         // update tail to commit new entry
-    //         sq.sqe_tail = next;
-    //     }
+    // Cancel the request with user_data, and wait for its cqe (see submit_borrowed())
+    //
+    // NB: returning while the kernel may still access the caller's memory would be unsound,
+    // so this aborts if the request cannot be reaped.
+    fn cancel_borrowed(&mut self, user_data: u64) {
+        if let Err(e) = self.try_cancel_borrowed(user_data) {
+            eprintln!("iouring: cannot reap request {:#x} that uses borrowed memory: {}", user_data, e);
+            std::process::abort();
+        }
+    }
 
-    //     Some(fret)
-    // }
+    fn try_cancel_borrowed(&mut self, user_data: u64) -> Result<(), IoUringError> {
+        loop {
+            let mut sqe = self.get_sqe().ok_or(IoUringError::SqFull)?;
+            sqe.prep_cancel(user_data);
+            sqe.set_data(CANCEL_USER_DATA);
+            self.submit()?;
+            loop {
+                let cqe = match self.wait_cqe() {
+                    Err(IoUringError::Submit(ref e)) if e.raw_os_error() == Some(libc::EINTR) => continue,
+                    r => r?,
+                };
+                if cqe.user_data() == user_data {
+                    return Ok(())
+                }
+                // NB: with SQPOLL, the request may not have been consumed yet, so try again
+                if cqe.user_data() == CANCEL_USER_DATA && cqe.res() == -libc::ENOENT {
+                    std::thread::yield_now();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Receive from stream socket fd until buf is full, with a single MSG_WAITALL recv
+    ///
+    /// The kernel keeps receiving until all of buf is filled, so a message that arrives in
+    /// several segments is received in one operation. The result is shorter than buf.len() only
+    /// if the connection is closed (EOF) first, in which case it is the number of bytes received
+    /// until then. Like submit_read_full(), this waits for the completion, returns the cqe with
+    /// the given user_data, and requires an idle ring. If waiting fails (e.g., with EINTR), the
+    /// recv is cancelled before returning, so no data is received into buf afterwards.
+    pub fn submit_recv_all(&mut self, fd: libc::c_int, buf: &mut [u8], user_data: u64)
+    -> Result<io_uring_cqe, IoUringError> {
+        if self.sq_ready() > 0 || self.cq_ready() > 0 {
+            return Err(IoUringError::NotIdle)
+        }
+
+        let len = buf.len().try_into().unwrap_or(u32::MAX);
+        // NB: the ring is idle, so there is always an sqe
+        let mut sqe = self.get_sqe().unwrap();
+        sqe.prep_recv(fd, buf.as_mut_ptr() as *mut libc::c_void, len, libc::MSG_WAITALL);
+        sqe.set_data(user_data);
+        self.submit_borrowed(user_data)
+    }
+
+    /// Submit the operation prepared by prep, cancelling it if it does not complete by deadline
+    ///
+    /// This links an IORING_OP_LINK_TIMEOUT for the time left until deadline (zero, if it has
+    /// passed) to the operation, and submits both (see SQEntry::prep_link_timeout() for the
+    /// results). The operation's cqe has user_data, and the timeout's cqe has
+    /// DEADLINE_USER_DATA. IoUringError::SqFull is returned if there are not two free sqes, and
+    /// IoUringError::InvalidInput if user_data is reserved (see RESERVED_USER_DATA).
+    pub fn submit_with_deadline<F>(&mut self, prep: F, deadline: std::time::Instant, user_data: u64)
+    -> Result<u32, IoUringError>
+    where F: FnOnce(&mut SQEntry<'_>)
+    {
+        if user_data >= RESERVED_USER_DATA {
+            return Err(IoUringError::InvalidInput("user_data is reserved".into()))
+        }
+        if self.sq_space_left() < 2 {
+            return Err(IoUringError::SqFull)
+        }
+        let ts = KernelTimespec::from(deadline.saturating_duration_since(std::time::Instant::now()));
+
+        // NB: an automatic submit between the two sqes would break the link
+        let autosubmit = std::mem::replace(&mut self.autosubmit, 0);
+        let mut sqe = self.get_sqe().unwrap();
+        prep(&mut sqe);
+        sqe.set_link();
+        sqe.set_data(user_data);
+        let mut sqe = self.get_sqe().unwrap();
+        sqe.prep_link_timeout(&ts, 0, TimeoutClock::Monotonic);
+        sqe.set_data(DEADLINE_USER_DATA);
+        self.autosubmit = autosubmit;
+        self.submit()
+    }
+
+    /// Get an sqe for reading len bytes from fd at file_off into registered buffer buf_index,
+    /// starting buf_off bytes into the buffer
+    ///
+    /// This allows partitioning one large registered buffer among concurrent operations. An
+    /// IoUringError::InvalidInput is returned if the range does not fit in the buffer, and
+    /// IoUringError::SqFull if the SQ is full.
+    pub fn prep_read_fixed_at(&mut self, fd: libc::c_int, buf_index: u16, buf_off: usize, len: u32, file_off: u64)
+    -> Result<SQEntry<'_>, IoUringError> {
+        self.prep_fixed_at(IORING_OP_READ_FIXED, fd, buf_index, buf_off, len, file_off)
+    }
+
+    /// Like prep_read_fixed_at(), but writing len bytes of registered buffer buf_index,
+    /// starting at buf_off, to fd at file_off
+    pub fn prep_write_fixed_at(&mut self, fd: libc::c_int, buf_index: u16, buf_off: usize, len: u32, file_off: u64)
+    -> Result<SQEntry<'_>, IoUringError> {
+        self.prep_fixed_at(IORING_OP_WRITE_FIXED, fd, buf_index, buf_off, len, file_off)
+    }
+
+    fn prep_fixed_at(&mut self, op: u8, fd: libc::c_int, buf_index: u16, buf_off: usize, len: u32, file_off: u64)
+    -> Result<SQEntry<'_>, IoUringError> {
+        // NB: buffers unregistered by dropping a FixedBufferPool are still tracked here, but the
+        // kernel rejects operations on unregistered buffers.
+        let addr = match self.fixed_bufs.get(buf_index as usize) {
+            Some(&(base, size)) if buf_off.checked_add(len as usize).is_some_and(|end| end <= size) => base + buf_off,
+            _ => {
+                return Err(IoUringError::InvalidInput("range is not within the registered buffer".into()))
+            }
+        };
+        let mut sqe = match self.get_sqe() {
+            Some(x) => x,
+            None => return Err(IoUringError::SqFull),
+        };
+        let addr = addr as *mut libc::c_void;
+        if op == IORING_OP_READ_FIXED {
+            sqe.prep_read_fixed(fd, addr, len, file_off, buf_index);
+        } else {
+            sqe.prep_write_fixed(fd, addr, len, file_off, buf_index);
+        }
+        Ok(sqe)
+    }
+
+    /// Submit and reap iters NOPs, in batches of up to sq_entries(), as fast as possible
+    ///
+    /// This measures the overhead of a submit/complete round trip (see benches/roundtrip.rs).
+    /// The ring needs to be idle (no queued sqes or ready cqes), otherwise a ResourceBusy error
+    /// (IoUringError::NotIdle) is returned.
+    pub fn bench_roundtrip(&mut self, iters: u32) -> io::Result<()> {
+        if self.sq_ready() > 0 || self.cq_ready() > 0 {
+            return Err(IoUringError::NotIdle.into())
+        }
+
+        let mut completed = 0;
+        while completed < iters {
+            let batch = std::cmp::min(iters - completed, self.sq_entries());
+            for _ in 0..batch {
+                // NB: the ring is idle at the start of each batch, so there is always an sqe
+                self.get_sqe().unwrap().prep_nop();
+            }
+            self.submit_and_wait(batch)?;
+            let mut reaped = 0;
+            while reaped < batch {
+                reaped += self.for_each_completion(|cqe| debug_assert_eq!(cqe.res(), 0));
+                if reaped < batch {
+                    self.wait_only(1)?;
+                }
+            }
+            completed += batch;
+        }
+        Ok(())
+    }
+
+    /// Read from fd at offset off into buf, and wait for the read to complete
+    ///
+    /// This is the simplest way to use the ring: it prepares, submits, and reaps a single
+    /// operation. Returns the number of bytes read, which may be less than buf.len() (see
+    /// submit_read_full() for reading all of buf). The ring needs to be idle (no queued sqes or
+    /// ready cqes), otherwise a ResourceBusy error (IoUringError::NotIdle) is returned. Like
+    /// with submit_read_full(), the read is neither left queued nor in flight on errors.
+    pub fn read_blocking(&mut self, fd: libc::c_int, buf: &mut [u8], off: u64) -> io::Result<usize> {
+        let ptr = buf.as_mut_ptr() as *const libc::c_void;
+        self.rw_blocking(IORING_OP_READ, fd, ptr, buf.len(), off)
+    }
+
+    /// Write buf to fd at offset off, and wait for the write to complete
+    ///
+    /// Returns the number of bytes written. See read_blocking().
+    pub fn write_blocking(&mut self, fd: libc::c_int, buf: &[u8], off: u64) -> io::Result<usize> {
+        let ptr = buf.as_ptr() as *const libc::c_void;
+        self.rw_blocking(IORING_OP_WRITE, fd, ptr, buf.len(), off)
+    }
+
+    fn rw_blocking(&mut self, op: u8, fd: libc::c_int, buf: *const libc::c_void, len: usize, off: u64)
+    -> io::Result<usize> {
+        if self.sq_ready() > 0 || self.cq_ready() > 0 {
+            return Err(IoUringError::NotIdle.into())
+        }
+
+        let len = len.try_into().unwrap_or(u32::MAX);
+        // NB: the ring is idle, so there is always an sqe
+        let mut sqe = self.get_sqe().unwrap();
+        sqe.prep_rw(op, fd, buf, len, off);
+        sqe.set_data(0);
+
+        let cqe = self.submit_borrowed(0)?;
+        match cqe.res() {
+            r if r < 0 => Err(io::Error::from_raw_os_error(-r)),
+            r => Ok(r as usize),
+        }
+    }
+
+    /// Submit a vectored read from fd at offset off into bufs, with the ring owning the buffers
+    ///
+    /// The buffers (and the iovec array pointing to them) are kept by the ring until the
+    /// operation completes, and can be recovered with take_owned_iovecs() once the cqe for
+    /// user_data is reaped. Dropping the ring cancels the pending owned operations (with
+    /// sync_cancel()) before freeing their buffers, or leaks the buffers if that fails. user_data needs to be unique among the pending owned operations, and
+    /// not reserved (see RESERVED_USER_DATA), so that it can be cancelled with cancel_owned().
+    pub fn submit_readv_owned(&mut self, fd: libc::c_int, bufs: Vec<Vec<u8>>, off: u64, user_data: u64)
+    -> Result<(), IoUringError> {
+        self.submit_rwv_owned(IORING_OP_READV, fd, bufs, off, user_data)
+    }
+
+    /// Submit a vectored write of bufs to fd at offset off, with the ring owning the buffers
+    ///
+    /// Same as submit_readv_owned(), but for writing.
+    pub fn submit_writev_owned(&mut self, fd: libc::c_int, bufs: Vec<Vec<u8>>, off: u64, user_data: u64)
+    -> Result<(), IoUringError> {
+        self.submit_rwv_owned(IORING_OP_WRITEV, fd, bufs, off, user_data)
+    }
+
+    fn submit_rwv_owned(&mut self, op: u8, fd: libc::c_int, mut bufs: Vec<Vec<u8>>, off: u64, user_data: u64)
+    -> Result<(), IoUringError> {
+        if user_data >= RESERVED_USER_DATA {
+            return Err(IoUringError::InvalidInput("user_data is reserved".into()))
+        }
+        if self.owned_iovecs.contains_key(&user_data) {
+            return Err(IoUringError::InvalidInput("user_data already in use".into()))
+        }
+        let nr_vecs = match u32::try_from(bufs.len()) {
+            Ok(n) if n <= libc::UIO_MAXIOV as u32 => n,
+            _ => {
+                return Err(IoUringError::InvalidInput("too many iovecs".into()))
+            }
+        };
+
+        // NB: moving the Vecs does not move their heap buffers, so the iovecs remain valid
+        let iovecs: Vec<libc::iovec> = bufs.iter_mut().map(|b| libc::iovec {
+            iov_base: b.as_mut_ptr() as *mut libc::c_void,
+            iov_len: b.len(),
+        }).collect();
+        let mut sqe = match self.get_sqe() {
+            Some(x) => x,
+            None => return Err(IoUringError::SqFull),
+        };
+        sqe.prep_rw(op, fd, iovecs.as_ptr() as *const libc::c_void, nr_vecs, off);
+        sqe.set_data(user_data);
+        self.owned_iovecs.insert(user_data, OwnedIovecs { bufs, _iovecs: iovecs });
+        // NB: if submitting fails, the sqe remains queued, so we keep the buffers
+        self.submit()?;
+        Ok(())
+    }
+
+    // Release the owned buffers when the ring is dropped. Returns false if they were leaked.
+    //
+    // NB: the kernel tears down rings asynchronously, so io-wq workers may still be using the
+    // buffers of pending operations after close(). sync_cancel() only returns once the
+    // cancelled requests are done, so the buffers can be freed after it. If it fails (e.g.,
+    // before 6.0), they are leaked instead.
+    fn release_owned(&mut self) -> bool {
+        if self.owned_iovecs.is_empty() {
+            return true
+        }
+        let owned = mem::take(&mut self.owned_iovecs);
+        // NB: nothing can have been submitted to a disabled ring
+        if self.disabled || self.sync_cancel(CancelCriteria::All, None).is_ok() {
+            return true
+        }
+        mem::forget(owned);
+        false
+    }
+
+    /// Recover the buffers of a completed submit_readv_owned() or submit_writev_owned() operation
+    ///
+    /// cqe is the completion of the operation. Returns None if there are no owned buffers for
+    /// its user_data.
+    pub fn take_owned_iovecs(&mut self, cqe: &io_uring_cqe) -> Option<Vec<Vec<u8>>> {
+        self.owned_iovecs.remove(&cqe.user_data()).map(|o| o.bufs)
+    }
+
+    /// Does the ring own buffers for the operation with user_data? (see submit_readv_owned())
+    pub fn owns_iovecs(&self, user_data: u64) -> bool {
+        self.owned_iovecs.contains_key(&user_data)
+    }
+
+    /// Cancel the owned operation with user_data (see submit_readv_owned())
+    ///
+    /// This submits an IORING_OP_ASYNC_CANCEL (with CANCEL_USER_DATA), and is meant for callers
+    /// that stop waiting for the operation, e.g., when dropping a future. The kernel may still
+    /// be using the buffers until the cancelled operation's cqe arrives, so they remain owned by
+    /// the ring until then, and can be recovered (or dropped) with take_owned_iovecs().
+    pub fn cancel_owned(&mut self, user_data: u64) -> Result<(), IoUringError> {
+        if !self.owned_iovecs.contains_key(&user_data) {
+            return Err(IoUringError::InvalidInput("no owned operation with this user_data".into()))
+        }
+        let mut sqe = match self.get_sqe() {
+            Some(x) => x,
+            None => return Err(IoUringError::SqFull),
+        };
+        sqe.prep_cancel(user_data);
+        sqe.set_data(CANCEL_USER_DATA);
+        self.submit()?;
+        Ok(())
+    }
+}
+
+// split ring
+impl IoUring {
+    /// Split the ring into its submission and completion halves, and call f with them
+    ///
+    /// The halves can be used concurrently, from different threads (e.g., with
+    /// std::thread::scope()): the submitting thread keeps using the SubmissionQueue, while the
+    /// CompletionQueue waits for and consumes cqes. Each half only writes its own ring index
+    /// (the SQ tail and the CQ head, respectively), so they need no synchronization between
+    /// them: the kernel publishes cqes with a release store of the CQ tail, which the
+    /// CompletionQueue reads with acquire, so the cqes and the data the kernel wrote for them
+    /// (e.g., read buffers) are visible to the consuming thread. Any other state shared
+    /// between the threads (e.g., per-request state keyed by user_data) needs its own
+    /// synchronization. Cqes consumed by the CompletionQueue count towards in_flight() once f
+    /// returns.
+    pub fn split<R, F>(&mut self, f: F) -> R
+    where F: FnOnce(SubmissionQueue<'_>, CompletionQueue<'_>) -> R {
+        let mut consumed = 0;
+        let cq = CompletionQueue {
+            cq: self.cq,
+            fd: self.fd,
+            sys: self.sys.clone(),
+            consumed: &mut consumed,
+            disabled: self.disabled,
+        };
+        let ret = f(SubmissionQueue { ior: self }, cq);
+        self.in_flight = self.in_flight.saturating_sub(consumed);
+        ret
+    }
+}
+
+impl SubmissionQueue<'_> {
+    /// Get a new sqe (see IoUring::get_sqe())
+    pub fn get_sqe(&mut self) -> Option<SQEntry<'_>> {
+        self.ior.get_sqe()
+    }
+
+    /// Submit the sqes acquired via get_sqe(), without waiting (see IoUring::submit())
+    pub fn submit(&mut self) -> Result<u32, IoUringError> {
+        self.ior.submit()
+    }
+
+    /// Number of sqes that can be acquired via get_sqe() before the SQ is full
+    pub fn sq_space_left(&self) -> u32 {
+        self.ior.sq_space_left()
+    }
+}
+
+// NB: the CQ pointers point to the ring mapping, which outlives the CompletionQueue, and only
+// the CompletionQueue accesses the CQ while the ring is split.
+unsafe impl Send for CompletionQueue<'_> {}
+
+impl CompletionQueue<'_> {
+    /// Enter the kernel to wait until min cqes are ready, without submitting anything
+    ///
+    /// The cqes are not consumed. This never touches the SQ, so the SubmissionQueue can be
+    /// used concurrently. It uses the ring fd, even if the ring is registered (see
+    /// IoUring::register_ring_fd()), since the registered index is only valid in the thread
+    /// that registered it. Rings with DEFER_TASKRUN only allow the submitting thread to wait.
+    pub fn wait(&mut self, min: u32) -> Result<(), IoUringError> {
+        if self.disabled {
+            return Err(IoUringError::RingDisabled)
+        }
+        let null = std::ptr::null_mut::<libc::sigset_t>();
+        let flags = EnterFlags::GETEVENTS.bits();
+        let ret = unsafe { io_uring_enter(&*self.sys, self.fd, 0, min, flags, null) };
+        if ret < 0 {
+            return Err(IoUringError::Submit(io::Error::last_os_error()))
+        }
+        Ok(())
+    }
+
+    /// Number of ready cqes
+    pub fn ready(&self) -> u32 {
+        let ktail_p = self.cq.ktail as *mut std::sync::atomic::AtomicU32;
+        let ktail = unsafe { (&*ktail_p).load(std::sync::atomic::Ordering::Acquire) };
+        let khead = unsafe { *self.cq.khead };
+        (std::num::Wrapping(ktail) - std::num::Wrapping(khead)).0
+    }
+
+    /// Call f on every ready cqe, and consume them (see IoUring::for_each_completion())
+    ///
+    /// Returns the number of cqes processed.
+    pub fn for_each<F: FnMut(&io_uring_cqe)>(&mut self, mut f: F) -> u32 {
+        let head = unsafe { *self.cq.khead };
+        let iter = CqIter { curr: std::num::Wrapping(head), cq: &self.cq };
+        let mut nr = 0;
+        for cqe in iter {
+            f(&cqe);
+            nr += 1;
+        }
+
+        let khead_p = self.cq.khead as *mut std::sync::atomic::AtomicU32;
+        unsafe { (&*khead_p).store(head.wrapping_add(nr), std::sync::atomic::Ordering::Release) };
+        *self.consumed += nr;
+        nr
+    }
+}
+
+// queue functions: CQ
+impl IoUring {
+    /// Iterate over the ready completion queue entries (cqes)
+    ///
+    /// The iterator does not consume the entries: use cq_advance() for that.
+    pub fn cq_iter(&self) -> CqIter<'_> {
+        let cq_head = unsafe { *self.cq.khead };
+        CqIter {
+            curr: std::num::Wrapping(cq_head),
+            cq: &self.cq,
+        }
+    }
+
+    /// Number of ready cqes
+    // liburing: io_uring_cq_ready()
+    pub fn cq_ready(&self) -> u32 {
+        let ktail_p = self.cq.ktail as *mut std::sync::atomic::AtomicU32;
+        let ktail = unsafe { (&*ktail_p).load(std::sync::atomic::Ordering::Acquire) };
+        let khead = unsafe { *self.cq.khead };
+        (std::num::Wrapping(ktail) - std::num::Wrapping(khead)).0
+    }
+
+    /// Mark nr cqes as consumed, so that the kernel can reuse their slots.
+    // liburing: io_uring_cq_advance()
+    pub fn cq_advance(&mut self, nr: u32) {
+        if nr == 0 {
+            return
+        }
+        self.in_flight = self.in_flight.saturating_sub(nr);
+
+        // Ensure that the kernel sees the updated head only after we are done reading the
+        // entries.
+        let khead_p = self.cq.khead as *mut std::sync::atomic::AtomicU32;
+        unsafe {
+            let head = std::num::Wrapping(*self.cq.khead) + std::num::Wrapping(nr);
+            (&*khead_p).store(head.0, std::sync::atomic::Ordering::Release);
+        }
+    }
+
+    /// Wait for a cqe, and consume it
+    // liburing: io_uring_wait_cqe() + io_uring_cqe_seen()
+    pub fn wait_cqe(&mut self) -> Result<io_uring_cqe, IoUringError> {
+        self.wait_cqe_with(WaitStrategy::Block)
+    }
+
+    /// Wait for a cqe using the given strategy, and consume it
+    ///
+    /// Spinning avoids the system call overhead when a completion is imminent, at the cost of
+    /// CPU time. WaitStrategy::Spin still enters the kernel on COOP_TASKRUN and DEFER_TASKRUN
+    /// rings: their task work runs only there, so spinning alone could wait forever.
+    pub fn wait_cqe_with(&mut self, strategy: WaitStrategy) -> Result<io_uring_cqe, IoUringError> {
+        let taskrun = SetupFlags::COOP_TASKRUN | SetupFlags::DEFER_TASKRUN;
+        let strategy = match strategy {
+            WaitStrategy::Spin if self.flags.intersects(taskrun) => {
+                WaitStrategy::SpinThenBlock { spin_iters: SPIN_TASKRUN_ITERS }
+            },
+            s => s,
+        };
+        let spin_iters = match strategy {
+            WaitStrategy::Block => 0,
+            WaitStrategy::SpinThenBlock { spin_iters } => spin_iters,
+            WaitStrategy::Spin => u32::MAX,
+        };
+
+        let mut iter = 0;
+        loop {
+            if let Some(cqe) = self.cq_iter().next() {
+                self.cq_advance(1);
+                return Ok(cqe)
+            }
+
+            if strategy == WaitStrategy::Spin {
+                std::hint::spin_loop();
+            } else if iter < spin_iters {
+                iter += 1;
+                std::hint::spin_loop();
+            } else {
+                self.wait_only(1)?;
+            }
+        }
+    }
+
+    /// Wait for a cqe for up to timeout, and consume it
+    ///
+    /// Returns None if no cqe arrived within the timeout. If timeout is None, this is the same
+    /// as wait_cqe(). This uses IORING_ENTER_EXT_ARG (5.11+). Older kernels are handled by
+    /// submitting an IORING_OP_TIMEOUT (with TIMEOUT_USER_DATA) that completes after the next
+    /// cqe or the timeout, and whose cqe is removed from the CQ before returning. Like any
+    /// submission, this also submits the sqes that are queued.
+    //
+    // NB: The kernel does not wake up futex waiters when it posts cqes, so waiting on the CQ
+    // tail with a futex is not an option: a timed io_uring_enter() is the way to wait.
+    // liburing: io_uring_wait_cqe_timeout()
+    pub fn wait_cqe_timeout(&mut self, timeout: Option<std::time::Duration>)
+    -> Result<Option<io_uring_cqe>, IoUringError> {
+        let timeout = match timeout {
+            None => return self.wait_cqe().map(Some),
+            Some(t) => t,
+        };
+        if self.disabled {
+            return Err(IoUringError::RingDisabled)
+        }
+        if !self.features.contains(FeatureFlags::EXT_ARG) {
+            return self.wait_cqe_timeout_sqe(timeout)
+        }
+
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            if let Some(cqe) = self.cq_iter().next() {
+                self.cq_advance(1);
+                return Ok(Some(cqe))
+            }
+
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                return Ok(None)
+            }
+            // NB: the ring may have moved since the last wait, so the ts pointer is set every time
+            let wait_arg = &mut self.wait_arg;
+            wait_arg.ts = KernelTimespec::from(deadline - now);
+            wait_arg.arg = io_uring_getevents_arg {
+                ts: &wait_arg.ts as *const KernelTimespec as u64,
+                ..Default::default()
+            };
+            let arg = &wait_arg.arg as *const io_uring_getevents_arg as *const libc::c_void;
+            let (fd, flags) = self.enter_target(EnterFlags::GETEVENTS | EnterFlags::EXT_ARG);
+            let ret = unsafe {
+                self.sys.enter(fd, 0, 1, flags.bits(), arg, mem::size_of::<io_uring_getevents_arg>())
+            };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::ETIME) | Some(libc::EINTR) => (),
+                    _ => return Err(IoUringError::Submit(err)),
+                }
+            }
+        }
+    }
+
+    // wait_cqe_timeout() for kernels without EXT_ARG
+    // liburing: __io_uring_submit_timeout()
+    fn wait_cqe_timeout_sqe(&mut self, timeout: std::time::Duration)
+    -> Result<Option<io_uring_cqe>, IoUringError> {
+        // NB: cqes posted before the timeout is submitted do not complete it
+        if self.sq_ready() > 0 {
+            self.submit()?;
+        }
+        if let Some(cqe) = self.cq_iter().next() {
+            self.cq_advance(1);
+            return Ok(Some(cqe))
+        }
+
+        self.wait_arg.ts = KernelTimespec::from(timeout);
+        let ts = &self.wait_arg.ts as *const KernelTimespec;
+        let mut sqe = match self.get_sqe() {
+            Some(x) => x,
+            None => return Err(IoUringError::SqFull),
+        };
+        // NB: the timeout completes after one more cqe is posted, or when it expires
+        sqe.prep_timeout(unsafe { &*ts }, 1, 0, TimeoutClock::Monotonic);
+        sqe.set_data(TIMEOUT_USER_DATA);
+        self.submit()?;
+
+        // wait for the timeout's cqe, so that it does not show up later
+        let pos = loop {
+            if let Some(pos) = self.cq_iter().position(|cqe| cqe.user_data() == TIMEOUT_USER_DATA) {
+                break pos as u32
+            }
+            match self.wait_only(1) {
+                Err(IoUringError::Submit(ref e)) if e.raw_os_error() == Some(libc::EINTR) => (),
+                Err(e) => return Err(e),
+                Ok(_) => (),
+            }
+        };
+
+        // remove it by moving the cqes before it one slot forward, and consuming one slot.
+        // NB: the slots between the head and the tail belong to us until the head moves.
+        let (head, mask) = unsafe { (std::num::Wrapping(*self.cq.khead), *self.cq.kring_mask) };
+        let cqe_size = mem::size_of::<io_uring_cqe>() << self.cq.cqe_shift;
+        let slot = |i: u32| unsafe {
+            self.cq.cqes.add((((head + std::num::Wrapping(i)).0 & mask) << self.cq.cqe_shift) as usize) as *mut u8
+        };
+        for i in (0..pos).rev() {
+            unsafe { std::ptr::copy_nonoverlapping(slot(i), slot(i + 1), cqe_size) };
+        }
+        self.cq_advance(1);
+
+        if let Some(cqe) = self.cq_iter().next() {
+            self.cq_advance(1);
+            return Ok(Some(cqe))
+        }
+        Ok(None)
+    }
+
+    /// Wait for exactly n cqes, and consume them
+    ///
+    /// io_uring_enter() may return before wait_nr completions are available (e.g., when it is
+    /// interrupted by a signal), so this enters the kernel again until n cqes are collected,
+    /// retrying on EINTR. Further ready cqes are left in the CQ. The requests need to have been
+    /// submitted already: this blocks forever if fewer than n cqes will ever be posted.
+    pub fn wait_cqe_n(&mut self, n: u32) -> io::Result<Vec<io_uring_cqe>> {
+        let n = n as usize;
+        let mut cqes = Vec::with_capacity(n);
+        loop {
+            let nr = {
+                let before = cqes.len();
+                cqes.extend(self.cq_iter().take(n - before));
+                cqes.len() - before
+            };
+            self.cq_advance(nr as u32);
+            if cqes.len() == n {
+                return Ok(cqes)
+            }
+
+            match self.wait_only((n - cqes.len()) as u32) {
+                Err(IoUringError::Submit(e)) if e.raw_os_error() == Some(libc::EINTR) => (),
+                Err(e) => return Err(e.into()),
+                Ok(_) => (),
+            }
+        }
+    }
+
+    /// Enter the kernel to wait for wait_nr completions, without submitting anything
+    ///
+    /// The completions are not consumed. Returns the value of io_uring_enter() (i.e., the
+    /// number of sqes submitted, which is 0 unless the kernel picked up sqes on its own).
+    pub fn wait_only(&mut self, wait_nr: u32) -> Result<u32, IoUringError> {
+        if self.disabled {
+            return Err(IoUringError::RingDisabled)
+        }
+        let null = std::ptr::null_mut::<libc::sigset_t>();
+        let (fd, flags) = self.enter_target(EnterFlags::GETEVENTS);
+        let ret = unsafe { io_uring_enter(&*self.sys, fd, 0, wait_nr, flags.bits(), null) };
+        if ret < 0 {
+            return Err(IoUringError::Submit(io::Error::last_os_error()))
+        }
+        Ok(ret as u32)
+    }
+
+    /// Number of submitted requests whose cqes have not been consumed yet
+    ///
+    /// This counts submitted sqes minus consumed cqes, so it is only accurate for requests that
+    /// post exactly one cqe: e.g., multishot requests, zero-copy sends (notifications), and
+    /// IOSQE_CQE_SKIP_SUCCESS break the accounting.
+    pub fn in_flight(&self) -> u32 {
+        self.in_flight
+    }
+
+    /// Get the next ready cqe without copying it out of the CQ.
+    ///
+    /// The cqe is consumed (the CQ head is advanced) when the returned guard is dropped. The
+    /// guard mutably borrows the ring, so nothing can be submitted or reaped while it is held.
+    // liburing: io_uring_peek_cqe() + io_uring_cqe_seen()
+    pub fn next_cqe(&mut self) -> Option<CqeGuard<'_>> {
+        if self.cq_ready() == 0 {
+            return None
+        }
+        let head = unsafe { *self.cq.khead };
+        let mask = unsafe { *self.cq.kring_mask };
+        let cqe = unsafe { self.cq.cqes.add(((head & mask) << self.cq.cqe_shift) as usize) };
+        Some(CqeGuard { ior: self, cqe })
+    }
+
+    /// Call f on every ready cqe, and consume them.
+    ///
+    /// The CQ head is updated once after all entries are processed, instead of once per entry.
+    ///
+    /// Returns the number of cqes processed.
+    pub fn for_each_completion<F: FnMut(&io_uring_cqe)>(&mut self, mut f: F) -> u32 {
+        let mut nr = 0;
+        for cqe in self.cq_iter() {
+            f(&cqe);
+            nr += 1;
+        }
+        self.cq_advance(nr);
+        nr
+    }
+
+    /// Like for_each_completion(), but consume stale cqes (see Generations) without calling f
+    ///
+    /// f gets the cqe and its decoded user_data. Returns the number of cqes that f was called
+    /// for.
+    pub fn for_each_current_completion<F>(&mut self, gens: &Generations, mut f: F) -> u32
+    where F: FnMut(&io_uring_cqe, UserData) {
+        let mut nr = 0;
+        let mut current = 0;
+        for cqe in self.cq_iter() {
+            let data = UserData(cqe.user_data());
+            if !gens.is_stale(data) {
+                f(&cqe, data);
+                current += 1;
+            }
+            nr += 1;
+        }
+        self.cq_advance(nr);
+        current
+    }
+
+    /// Register handler for the completions tagged with op_tag (see UserData), replacing any
+    /// previous handler for the tag
+    ///
+    /// Handlers are called by dispatch().
+    pub fn on_completion(&mut self, op_tag: u16, handler: CompletionHandler) {
+        self.handlers.insert(op_tag, handler);
+    }
+
+    /// Consume the ready cqes, calling the handler registered (with on_completion()) for the
+    /// tag of each
+    ///
+    /// cqes with no handler for their tag are consumed without being handled. Returns the
+    /// number of cqes handled.
+    pub fn dispatch(&mut self) -> u32 {
+        let cq_head = unsafe { *self.cq.khead };
+        // NB: not cq_iter(), so that the handlers can be borrowed mutably while iterating
+        let iter = CqIter { curr: std::num::Wrapping(cq_head), cq: &self.cq };
+        let mut nr = 0;
+        let mut handled = 0;
+        for cqe in iter {
+            if let Some(handler) = self.handlers.get_mut(&UserData(cqe.user_data()).tag()) {
+                handler(&cqe);
+                handled += 1;
+            }
+            nr += 1;
+        }
+        self.cq_advance(nr);
+        handled
+    }
+
+    /// Consume up to max ready cqes, and return them.
+    ///
+    /// This bounds the time spent processing completions, e.g., so that an event loop can
+    /// return to submitting new requests.
+    pub fn drain_up_to(&mut self, max: u32) -> Vec<io_uring_cqe> {
+        let cqes: Vec<io_uring_cqe> = self.cq_iter().take(max as usize).collect();
+        self.cq_advance(cqes.len() as u32);
+        cqes
+    }
+
+    /// Copy up to out.len() ready cqes into out, without consuming them.
+    ///
+    /// Returns the number of cqes copied. Use cq_advance() to consume them.
+    // liburing: io_uring_peek_batch_cqe()
+    pub fn peek_batch(&mut self, out: &mut [io_uring_cqe]) -> u32 {
+        let mut nr = 0;
+        for (slot, cqe) in out.iter_mut().zip(self.cq_iter()) {
+            *slot = cqe;
+            nr += 1;
+        }
+        nr
+    }
+}
+
+// register functions
+impl IoUring {
+    /// io_uring_register() helper
+    fn register(&self, opcode: libc::c_uint, arg: *mut libc::c_void, nr_args: libc::c_uint)
+    -> Result<libc::c_long, IoUringError> {
+        let ret = unsafe { self.sys.register(self.fd, opcode, arg, nr_args) };
+        if ret < 0 {
+            return Err(IoUringError::Register(io::Error::last_os_error()))
+        }
+        Ok(ret)
+    }
+
+    /// Register the credentials of the current thread as a personality, and return its id (5.6+)
+    ///
+    /// Requests can then run with these credentials (see SQEntry::set_personality()),
+    /// regardless of the credentials of the thread that submits them.
+    pub fn register_personality(&mut self) -> Result<u16, IoUringError> {
+        let id = self.register(IORING_REGISTER_PERSONALITY, std::ptr::null_mut(), 0)?;
+        Ok(id as u16)
+    }
+
+    /// Unregister a personality returned by register_personality()
+    pub fn unregister_personality(&mut self, id: u16) -> Result<(), IoUringError> {
+        self.register(IORING_UNREGISTER_PERSONALITY, std::ptr::null_mut(), id as libc::c_uint)?;
+        Ok(())
+    }
+
+    /// Register an eventfd, which the kernel signals on every completion
+    pub fn register_eventfd(&mut self, fd: libc::c_int) -> Result<(), IoUringError> {
+        let mut fd = fd;
+        let arg = &mut fd as *mut libc::c_int as *mut libc::c_void;
+        self.register(IORING_REGISTER_EVENTFD, arg, 1)?;
+        Ok(())
+    }
+
+    /// Set the maximum number of bounded and unbounded io-wq workers (5.15+)
+    ///
+    /// A value of 0 leaves the corresponding limit unchanged, so iowq_max_workers(0, 0) queries
+    /// the limits. Returns the previous (bounded, unbounded) limits.
+    pub fn iowq_max_workers(&mut self, bounded: u32, unbounded: u32) -> Result<(u32, u32), IoUringError> {
+        let mut arg = [bounded, unbounded];
+        self.register(IORING_REGISTER_IOWQ_MAX_WORKERS, arg.as_mut_ptr() as *mut libc::c_void, 2)?;
+        Ok((arg[0], arg[1]))
+    }
+
+    /// Register an eventfd, which the kernel signals only on completions of requests that
+    /// went async (5.6+)
+    ///
+    /// Requests that complete inline during submission do not signal the eventfd, which avoids
+    /// spurious wakeups for a caller that reaps those completions right after submitting.
+    /// Unregister with unregister_eventfd().
+    pub fn register_eventfd_async(&mut self, fd: libc::c_int) -> Result<(), IoUringError> {
+        let mut fd = fd;
+        let arg = &mut fd as *mut libc::c_int as *mut libc::c_void;
+        self.register(IORING_REGISTER_EVENTFD_ASYNC, arg, 1)?;
+        Ok(())
+    }
+
+    /// Register files (fds) for use with fixed file operations
+    ///
+    /// The index of an fd in fds is its fixed file index.
+    pub fn register_files(&mut self, fds: &[libc::c_int]) -> Result<(), IoUringError> {
+        let arg = fds.as_ptr() as *mut libc::c_void;
+        // NB: len() is usize, arg is u32. This will panic if a conversion cannot be made.
+        self.register(IORING_REGISTER_FILES, arg, fds.len().try_into().unwrap())?;
+        self.fixed_files = fds.to_vec();
+        Ok(())
+    }
+
+    /// Register the files (fds) yielded by fds, like register_files()
+    ///
+    /// The kernel allows up to IORING_MAX_FIXED_FILES files (and no more than RLIMIT_NOFILE):
+    /// larger tables are rejected with InvalidInput, before they are collected.
+    pub fn register_files_iter<I: IntoIterator<Item = libc::c_int>>(&mut self, fds: I) -> Result<(), IoUringError> {
+        let fds: Vec<libc::c_int> = fds.into_iter().take(IORING_MAX_FIXED_FILES + 1).collect();
+        if fds.len() > IORING_MAX_FIXED_FILES {
+            return Err(IoUringError::InvalidInput("too many files".into()))
+        }
+        self.register_files(&fds)
+    }
+
+    /// Replace the registered files starting at fixed file index offset with fds (5.5+)
+    ///
+    /// An fd of -1 clears the slot. Returns the number of slots updated.
+    pub fn update_files(&mut self, offset: u32, fds: &[libc::c_int]) -> Result<u32, IoUringError> {
+        let mut up = io_uring_rsrc_update { offset, resv: 0, data: fds.as_ptr() as u64 };
+        let arg = &mut up as *mut io_uring_rsrc_update as *mut libc::c_void;
+        // NB: len() is usize, arg is u32. This will panic if a conversion cannot be made.
+        let nr = self.register(IORING_REGISTER_FILES_UPDATE, arg, fds.len().try_into().unwrap())? as u32;
+        let start = offset as usize;
+        if let Some(slots) = self.fixed_files.get_mut(start..start + nr as usize) {
+            slots.copy_from_slice(&fds[..nr as usize]);
+        }
+        Ok(nr)
+    }
+
+    /// The fixed file table, as registered with register_files() and update_files()
+    ///
+    /// Free slots are -1. Updates the kernel performs itself (e.g., prep_accept_direct() or
+    /// direct opens and closes) are not reflected.
+    pub fn registered_files(&self) -> &[libc::c_int] {
+        &self.fixed_files
+    }
+
+    /// Unregister all registered files
+    pub fn unregister_files(&mut self) -> Result<(), IoUringError> {
+        self.register(IORING_UNREGISTER_FILES, std::ptr::null_mut(), 0)?;
+        self.fixed_files.clear();
+        Ok(())
+    }
+
+    /// Register buffers for use with fixed buffer operations (e.g., prep_read_fixed())
+    ///
+    /// The index of a buffer in iovecs is its buffer index. The kernel pins the buffers'
+    /// memory until they are unregistered.
+    pub fn register_buffers(&mut self, iovecs: &[libc::iovec]) -> Result<(), IoUringError> {
+        let arg = iovecs.as_ptr() as *mut libc::c_void;
+        // NB: len() is usize, arg is u32. This will panic if a conversion cannot be made.
+        self.register(IORING_REGISTER_BUFFERS, arg, iovecs.len().try_into().unwrap())?;
+        self.fixed_bufs = iovecs.iter().map(|v| (v.iov_base as usize, v.iov_len)).collect();
+        Ok(())
+    }
+
+    /// Register count buffers of buf_size bytes, carved out of a single allocation at base.
+    ///
+    /// base needs to point to (at least) buf_size*count bytes. Registering a single allocation
+    /// (e.g., one mmap()ed region) avoids fragmentation, and is more cache-friendly than
+    /// registering many separate allocations.
+    ///
+    /// Returns the buffer indices to use with fixed buffer operations.
+    pub fn register_buffer_pool(&mut self, base: *mut u8, buf_size: usize, count: usize)
+    -> Result<std::ops::Range<u16>, IoUringError> {
+        let nr = match u16::try_from(count) {
+            Ok(x) => x,
+            Err(_) => {
+                return Err(IoUringError::InvalidInput("too many buffers".into()))
+            }
+        };
+        let iovecs: Vec<libc::iovec> = (0..count).map(|i| libc::iovec {
+            iov_base: base.wrapping_add(i * buf_size) as *mut libc::c_void,
+            iov_len: buf_size,
+        }).collect();
+        self.register_buffers(&iovecs)?;
+        Ok(0..nr)
+    }
+
+    /// Unregister all registered buffers
+    pub fn unregister_buffers(&mut self) -> Result<(), IoUringError> {
+        self.register(IORING_UNREGISTER_BUFFERS, std::ptr::null_mut(), 0)?;
+        self.fixed_bufs.clear();
+        Ok(())
+    }
+
+    /// Cancel the in-flight requests that match criteria, and wait for them to complete (6.0+)
+    ///
+    /// Unlike IORING_OP_ASYNC_CANCEL, this does not go through the SQ/CQ, which is convenient,
+    /// e.g., when shutting down. The cancelled requests still post their (-ECANCELED) cqes. If
+    /// timeout is None, wait until all matching requests are cancelled.
+    ///
+    /// Returns the number of cancelled requests.
+    pub fn sync_cancel(&mut self, criteria: CancelCriteria, timeout: Option<std::time::Duration>)
+    -> Result<u32, IoUringError> {
+        let mut reg: io_uring_sync_cancel_reg = unsafe { mem::zeroed() };
+        let mut flags = AsyncCancelFlags::ALL;
+        match criteria {
+            CancelCriteria::UserData(data) => reg.addr = data,
+            CancelCriteria::Fd(fd) => {
+                reg.fd = fd;
+                flags.insert(AsyncCancelFlags::FD);
+            },
+            CancelCriteria::All => flags.insert(AsyncCancelFlags::ANY),
+        }
+        reg.flags = flags.bits();
+        reg.timeout = match timeout {
+            Some(t) => KernelTimespec::from(t),
+            // NB: -1 means no timeout
+            None => KernelTimespec { tv_sec: -1, tv_nsec: -1 },
+        };
+
+        let arg = &mut reg as *mut io_uring_sync_cancel_reg as *mut libc::c_void;
+        let ret = self.register(IORING_REGISTER_SYNC_CANCEL, arg, 1)?;
+        Ok(ret as u32)
+    }
+
+    /// Query the kernel for the supported operations (5.6+)
+    ///
+    /// The result is cached, so only the first call issues a system call.
+    pub fn probe(&mut self) -> Result<&Probe, IoUringError> {
+        if self.probe.is_none() {
+            let mut probe: Box<io_uring_probe> = Box::new(unsafe { mem::zeroed() });
+            let arg = &mut *probe as *mut io_uring_probe as *mut libc::c_void;
+            self.register(IORING_REGISTER_PROBE, arg, PROBE_OPS_NR as libc::c_uint)?;
+            self.probe = Some(Probe(probe));
+        }
+        Ok(self.probe.as_ref().unwrap())
+    }
+
+    /// Check that the kernel supports operation op (IORING_OP_*), before preparing an sqe for it.
+    ///
+    /// Otherwise, an unsupported operation is only reported via an -EINVAL cqe result, which is
+    /// hard to tell apart from other errors.
+    pub fn check_op(&mut self, op: u8) -> Result<(), IoUringError> {
+        if self.probe()?.is_supported(op) {
+            Ok(())
+        } else {
+            Err(IoUringError::Unsupported(op))
+        }
+    }
+
+    /// Unregister a previously registered eventfd
+    pub fn unregister_eventfd(&mut self) -> Result<(), IoUringError> {
+        self.register(IORING_UNREGISTER_EVENTFD, std::ptr::null_mut(), 0)?;
+        Ok(())
+    }
+
+    /// Register the ring fd with the task (5.18+), and return its index
+    ///
+    /// Once registered, all io_uring_enter() calls use the index (with
+    /// IORING_ENTER_REGISTERED_RING) instead of the fd, which avoids the fd lookup on every
+    /// call. The registration is per task, so the ring should only be entered from the thread
+    /// that registered it. Registering an already registered ring returns its index.
+    pub fn register_ring_fd(&mut self) -> Result<u32, IoUringError> {
+        if let Some(idx) = self.registered_index {
+            return Ok(idx)
+        }
+        let mut upd = io_uring_rsrc_update {
+            offset: u32::MAX, // allocate an index
+            data: self.fd as u64,
+            ..Default::default()
+        };
+        let arg = &mut upd as *mut io_uring_rsrc_update as *mut libc::c_void;
+        self.register(IORING_REGISTER_RING_FDS, arg, 1)?;
+        self.registered_index = Some(upd.offset);
+        Ok(upd.offset)
+    }
+
+    /// Restrict what the ring allows (5.10+)
+    ///
+    /// The ring needs to have been created disabled (see Builder::disabled()), and restrictions
+    /// can only be registered once. They take effect once the ring is enabled with
+    /// enable_rings().
+    pub fn register_restrictions(&mut self, restrictions: &[Restriction]) -> Result<(), IoUringError> {
+        let mut res: Vec<io_uring_restriction> = restrictions.iter().map(|r| {
+            let (opcode, arg) = match *r {
+                Restriction::RegisterOp(op) => (IORING_RESTRICTION_REGISTER_OP, op),
+                Restriction::SqeOp(op) => (IORING_RESTRICTION_SQE_OP, op),
+                Restriction::SqeFlagsAllowed(f) => (IORING_RESTRICTION_SQE_FLAGS_ALLOWED, f),
+                Restriction::SqeFlagsRequired(f) => (IORING_RESTRICTION_SQE_FLAGS_REQUIRED, f),
+            };
+            io_uring_restriction { opcode, arg, ..Default::default() }
+        }).collect();
+        let arg = res.as_mut_ptr() as *mut libc::c_void;
+        // NB: len() is usize, arg is u32. This will panic if a conversion cannot be made.
+        self.register(IORING_REGISTER_RESTRICTIONS, arg, res.len().try_into().unwrap())?;
+        Ok(())
+    }
+
+    /// Is the io_uring_register() opcode reg_op (IORING_REGISTER_*) supported by the kernel?
+    ///
+    /// The probe only covers operations, but the kernel rejects restrictions for register
+    /// opcodes it does not know, so the first call probes for them with a few temporary
+    /// disabled rings, and caches the result for the process. Kernels before 5.10 do not support restrictions:
+    /// for those, reg_op is checked against the kernel version. This reports whether the
+    /// kernel knows reg_op: it may still fail, e.g., if the feature is not configured.
+    pub fn register_supported(&self, reg_op: u32) -> bool {
+        reg_op < *REGISTER_OPS.get_or_init(register_ops_nr)
+    }
+
+    /// Enable a ring created disabled (see Builder::disabled()) (5.10+)
+    pub fn enable_rings(&mut self) -> Result<(), IoUringError> {
+        self.register(IORING_REGISTER_ENABLE_RINGS, std::ptr::null_mut(), 0)?;
+        self.disabled = false;
+        Ok(())
+    }
+
+    /// Unregister the ring fd registered with register_ring_fd()
+    pub fn unregister_ring_fd(&mut self) -> Result<(), IoUringError> {
+        let idx = match self.registered_index {
+            Some(x) => x,
+            None => {
+                return Err(IoUringError::InvalidInput("ring fd is not registered".into()))
+            }
+        };
+        let mut upd = io_uring_rsrc_update { offset: idx, ..Default::default() };
+        let arg = &mut upd as *mut io_uring_rsrc_update as *mut libc::c_void;
+        self.register(IORING_UNREGISTER_RING_FDS, arg, 1)?;
+        self.registered_index = None;
+        Ok(())
+    }
+}
+
+impl From<std::time::Duration> for KernelTimespec {
+    fn from(d: std::time::Duration) -> KernelTimespec {
+        KernelTimespec {
+            // NB: this will panic for durations that do not fit in an i64 (~292 billion years)
+            tv_sec: d.as_secs().try_into().unwrap(),
+            tv_nsec: d.subsec_nanos().into(),
+        }
+    }
+}
+
+impl TimeoutClock {
+    /// The IORING_TIMEOUT_* flag selecting the clock
+    pub fn flags(self) -> u32 {
+        match self {
+            TimeoutClock::Monotonic => 0,
+            TimeoutClock::Boottime => IORING_TIMEOUT_BOOTTIME,
+            TimeoutClock::Realtime => IORING_TIMEOUT_REALTIME,
+        }
+    }
+}
+
+impl std::fmt::Display for RingDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "io_uring fd={}", self.fd)?;
+        writeln!(f, "     {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}", "head", "tail", "mask", "entries", "flags", "dropped")?;
+        writeln!(f, "SQ   {:>10} {:>10} {:>10} {:>10} {:>#10x} {:>10}",
+                 self.sq_head, self.sq_tail, self.sq_mask, self.sq_entries, self.sq_flags, self.sq_dropped)?;
+        writeln!(f, "SQE  {:>10} {:>10}", self.sqe_head, self.sqe_tail)?;
+        writeln!(f, "     {:>10} {:>10} {:>10} {:>10} {:>10}", "head", "tail", "mask", "entries", "overflow")?;
+        write!(f, "CQ   {:>10} {:>10} {:>10} {:>10} {:>10}",
+               self.cq_head, self.cq_tail, self.cq_mask, self.cq_entries, self.cq_overflow)
+    }
+}
+
+impl Params {
+    /// number of SQ entries
+    pub fn sq_entries(&self) -> u32 {
+        self.0.sq_entries
+    }
+
+    /// number of CQ entries
+    pub fn cq_entries(&self) -> u32 {
+        self.0.cq_entries
+    }
+
+    /// setup flags (IORING_SETUP_*)
+    pub fn flags(&self) -> u32 {
+        self.0.flags
+    }
+
+    /// features supported by the kernel (IORING_FEAT_*)
+    pub fn features(&self) -> u32 {
+        self.0.features
+    }
+
+    pub fn sq_thread_cpu(&self) -> u32 {
+        self.0.sq_thread_cpu
+    }
+
+    pub fn sq_thread_idle(&self) -> u32 {
+        self.0.sq_thread_idle
+    }
+
+    pub fn sq_off(&self) -> &io_sqring_offsets {
+        &self.0.sq_off
+    }
+
+    pub fn cq_off(&self) -> &io_cqring_offsets {
+        &self.0.cq_off
+    }
+}
+
+impl std::fmt::Debug for Params {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Params")
+            .field("sq_entries", &self.sq_entries())
+            .field("cq_entries", &self.cq_entries())
+            .field("flags", &self.flags())
+            .field("features", &self.features())
+            .field("sq_thread_cpu", &self.sq_thread_cpu())
+            .field("sq_thread_idle", &self.sq_thread_idle())
+            .field("sq_off", self.sq_off())
+            .field("cq_off", self.cq_off())
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for Probe {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let ops = (0..=self.last_op()).filter(|op| self.is_supported(*op)).map(opcode_name);
+        f.debug_set().entries(ops).finish()
+    }
+}
+
+impl Probe {
+    /// last operation known to the kernel
+    pub fn last_op(&self) -> u8 {
+        self.0.last_op
+    }
+
+    /// is operation op (IORING_OP_*) supported?
+    pub fn is_supported(&self, op: u8) -> bool {
+        let p = &self.0;
+        op <= p.last_op && p.ops[op as usize].flags & IO_URING_OP_SUPPORTED != 0
+    }
+}
+
+// debugging
+impl IoUring {
+    /// Check that the ring pointers are consistent with the offsets reported by the kernel
+    ///
+    /// This is meant for catching mmap and pointer setup bugs (e.g., in tests): every pointer
+    /// needs to be at its offset within its mapping, each ring mask needs to be its number of
+    /// entries minus one, and the indices need to be within the number of entries.
+    pub fn verify_offsets(&self) -> bool {
+        let p = &self.params.0;
+        let (sq_ptr, cq_ptr) = (self.sq.ring_ptr as usize, self.cq.ring_ptr as usize);
+        let at = |base: usize, sz: usize, ptr: *mut u32, off: u32| {
+            off as usize + mem::size_of::<u32>() <= sz && ptr as usize == base + off as usize
+        };
+        let sq_at = |ptr, off| at(sq_ptr, self.sq.ring_sz, ptr, off);
+        let cq_at = |ptr, off| at(cq_ptr, self.cq.ring_sz, ptr, off);
+
+        let (sq, cq) = (&self.sq, &self.cq);
+        let (so, co) = (&p.sq_off, &p.cq_off);
+        let offsets =
+            sq_at(sq.khead, so.head) && sq_at(sq.ktail, so.tail) &&
+            sq_at(sq.kring_mask, so.ring_mask) && sq_at(sq.kring_entries, so.ring_entries) &&
+            sq_at(sq.kflags, so.flags) && sq_at(sq.kdropped, so.dropped) &&
+            (sq.array.is_null() || sq_at(sq.array, so.array)) &&
+            cq_at(cq.khead, co.head) && cq_at(cq.ktail, co.tail) &&
+            cq_at(cq.kring_mask, co.ring_mask) && cq_at(cq.kring_entries, co.ring_entries) &&
+            cq_at(cq.overflow, co.overflow) &&
+            cq.cqes as usize == cq_ptr + co.cqes as usize &&
+            co.cqes as usize + p.cq_entries as usize * self.cqe_size() <= cq.ring_sz;
+        if !offsets {
+            return false
+        }
+
+        let d = self.dump();
+        let in_range = |head: u32, tail: u32, entries: u32| {
+            (std::num::Wrapping(tail) - std::num::Wrapping(head)).0 <= entries
+        };
+        d.sq_entries == p.sq_entries && d.sq_mask == d.sq_entries - 1 && d.sq_entries.is_power_of_two() &&
+        d.cq_entries == p.cq_entries && d.cq_mask == d.cq_entries - 1 && d.cq_entries.is_power_of_two() &&
+        in_range(d.sq_head, d.sq_tail, d.sq_entries) && in_range(d.cq_head, d.cq_tail, d.cq_entries)
+    }
+
+    /// Capture all the ring indices, e.g., for diagnosing a stuck ring
+    pub fn dump(&self) -> RingDump {
+        let load = |p: *mut u32| -> u32 {
+            let p = p as *mut std::sync::atomic::AtomicU32;
+            unsafe { (&*p).load(std::sync::atomic::Ordering::Acquire) }
+        };
+
+        RingDump {
+            fd: self.fd,
+            sq_head: load(self.sq.khead),
+            sq_tail: load(self.sq.ktail),
+            sq_mask: load(self.sq.kring_mask),
+            sq_entries: load(self.sq.kring_entries),
+            sq_flags: load(self.sq.kflags),
+            sq_dropped: load(self.sq.kdropped),
+            sqe_head: self.sq.sqe_head.0,
+            sqe_tail: self.sq.sqe_tail.0,
+            cq_head: load(self.cq.khead),
+            cq_tail: load(self.cq.ktail),
+            cq_mask: load(self.cq.kring_mask),
+            cq_entries: load(self.cq.kring_entries),
+            cq_overflow: load(self.cq.overflow),
+        }
+    }
+}
+
+// fixed buffer pool
+impl FixedBufferPool {
+    /// Allocate count page-aligned buffers of buf_size bytes, and register them with the ring
+    ///
+    /// This replaces the ring's fixed buffers, so it fails if buffers are already registered.
+    pub fn new(ior: &mut IoUring, buf_size: usize, count: usize) -> Result<FixedBufferPool, IoUringError> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let size = buf_size.checked_mul(count).filter(|s| *s > 0 && count <= u16::MAX as usize);
+        let size = match size {
+            Some(s) => s,
+            None => {
+                return Err(IoUringError::InvalidInput("invalid buffer pool size".into()))
+            }
+        };
+
+        let mut base: *mut libc::c_void = std::ptr::null_mut();
+        let err = unsafe { libc::posix_memalign(&mut base, page, size) };
+        if err != 0 {
+            return Err(IoUringError::Setup(io::Error::from_raw_os_error(err)))
+        }
+        let base = base as *mut u8;
+        unsafe { std::ptr::write_bytes(base, 0, size) };
+
+        let free_all = |e| {
+            unsafe { libc::free(base as *mut libc::c_void) };
+            Err(e)
+        };
+        let ring_fd = unsafe { libc::fcntl(ior.fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if ring_fd < 0 {
+            return free_all(IoUringError::Setup(io::Error::last_os_error()))
+        }
+        let indices = match ior.register_buffer_pool(base, buf_size, count) {
+            Ok(x) => x,
+            Err(e) => {
+                unsafe { close(ring_fd) };
+                return free_all(e)
+            }
+        };
+
+        Ok(FixedBufferPool {
+            ring_fd,
+            base,
+            buf_size,
+            // NB: reversed, so that buffers are handed out in index order
+            free: std::cell::RefCell::new(indices.rev().collect()),
+        })
+    }
+
+    /// Get a free buffer, if any
+    pub fn acquire(&self) -> Option<FixedBuf<'_>> {
+        let index = self.free.borrow_mut().pop()?;
+        Some(FixedBuf { pool: self, index })
+    }
+
+    /// Size of each buffer
+    pub fn buf_size(&self) -> usize {
+        self.buf_size
+    }
+}
+
+impl Drop for FixedBufferPool {
+    fn drop(&mut self) {
+        // NB: the buffers need to be unregistered before they are freed, so that the kernel
+        // stops using them.
+        let null = std::ptr::null_mut::<libc::c_void>();
+        unsafe {
+            io_uring_register(self.ring_fd, IORING_UNREGISTER_BUFFERS, null, 0);
+            close(self.ring_fd);
+            libc::free(self.base as *mut libc::c_void);
+        }
+    }
+}
+
+impl FixedBuf<'_> {
+    /// Index of the buffer, for fixed buffer operations
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.pool.base.wrapping_add(self.index as usize * self.pool.buf_size)
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.as_ptr() as *mut u8
+    }
+
+    pub fn len(&self) -> usize {
+        self.pool.buf_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl std::ops::Deref for FixedBuf<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.as_ptr(), self.len()) }
+    }
+}
+
+impl std::ops::DerefMut for FixedBuf<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.as_mut_ptr(), self.len()) }
+    }
+}
+
+impl Drop for FixedBuf<'_> {
+    fn drop(&mut self) {
+        self.pool.free.borrow_mut().push(self.index);
+    }
+}
+
+impl<'a> IoVecs<'a> {
+    /// iovecs for the (read-only) buffers in bufs
+    pub fn new(bufs: &'a [&[u8]]) -> IoVecs<'a> {
+        let iovecs = bufs.iter().map(|b| libc::iovec {
+            iov_base: b.as_ptr() as *mut libc::c_void,
+            iov_len: b.len(),
+        }).collect();
+        IoVecs { iovecs, writable: false, _p: std::marker::PhantomData }
+    }
+
+    /// iovecs for the buffers in bufs
+    ///
+    /// NB: borrowing bufs mutably for 'a ensures the buffers cannot be accessed while the
+    /// iovecs are alive.
+    pub fn new_mut(bufs: &'a mut [&mut [u8]]) -> IoVecs<'a> {
+        let iovecs = bufs.iter_mut().map(|b| libc::iovec {
+            iov_base: b.as_mut_ptr() as *mut libc::c_void,
+            iov_len: b.len(),
+        }).collect();
+        IoVecs { iovecs, writable: true, _p: std::marker::PhantomData }
+    }
+
+    pub fn as_ptr(&self) -> *const libc::iovec {
+        self.iovecs.as_ptr()
+    }
+
+    /// Number of iovecs
+    pub fn len(&self) -> usize {
+        self.iovecs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iovecs.is_empty()
+    }
+
+    /// Total number of bytes in the buffers
+    pub fn total_len(&self) -> usize {
+        self.iovecs.iter().map(|v| v.iov_len).sum()
+    }
+}
+
+// provided buffer ring
+impl BufRing {
+    /// Allocate entries buffers of buf_size bytes, and register them as the buffer group bgid
+    ///
+    /// entries needs to be a power of two, up to 32768. All the buffers are initially available
+    /// to the kernel, in id order.
+    pub fn new(ior: &mut IoUring, bgid: u16, buf_size: usize, entries: u16) -> Result<BufRing, IoUringError> {
+        if !entries.is_power_of_two() || entries > 32768 || buf_size == 0 || buf_size > u32::MAX as usize {
+            return Err(IoUringError::InvalidInput("invalid buffer ring size".into()))
+        }
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let bufs_size = match buf_size.checked_mul(entries as usize) {
+            Some(s) => s,
+            None => {
+                return Err(IoUringError::InvalidInput("invalid buffer ring size".into()))
+            }
+        };
+
+        // NB: the kernel requires the ring to be page-aligned
+        let ring_size = entries as usize * mem::size_of::<io_uring_buf>();
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        let ring = unsafe { libc::mmap(std::ptr::null_mut(), ring_size, prot, flags, -1, 0) };
+        if ring == libc::MAP_FAILED {
+            return Err(IoUringError::Setup(io::Error::last_os_error()))
+        }
+        let mut bufs: *mut libc::c_void = std::ptr::null_mut();
+        let err = unsafe { libc::posix_memalign(&mut bufs, page, bufs_size) };
+        if err != 0 {
+            unsafe { libc::munmap(ring, ring_size) };
+            return Err(IoUringError::Setup(io::Error::from_raw_os_error(err)))
+        }
+        let free_all = |e| {
+            unsafe {
+                libc::free(bufs);
+                libc::munmap(ring, ring_size);
+            }
+            Err(e)
+        };
+
+        let ring_fd = unsafe { libc::fcntl(ior.fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if ring_fd < 0 {
+            return free_all(IoUringError::Setup(io::Error::last_os_error()))
+        }
+        let mut reg = io_uring_buf_reg {
+            ring_addr: ring as u64,
+            ring_entries: entries as u32,
+            bgid,
+            ..Default::default()
+        };
+        let arg = &mut reg as *mut io_uring_buf_reg as *mut libc::c_void;
+        if let Err(e) = ior.register(IORING_REGISTER_PBUF_RING, arg, 1) {
+            unsafe { close(ring_fd) };
+            return free_all(e)
+        }
+
+        let br = BufRing {
+            ring_fd,
+            ring: ring as *mut io_uring_buf,
+            entries,
+            bgid,
+            bufs: bufs as *mut u8,
+            buf_size,
+            tail: std::cell::Cell::new(0),
+        };
+        for bid in 0..entries {
+            br.recycle(bid);
+        }
+        Ok(br)
+    }
+
+    /// Buffer group id, for selecting buffers from this ring
+    pub fn bgid(&self) -> u16 {
+        self.bgid
+    }
+
+    /// Size of each buffer
+    pub fn buf_size(&self) -> usize {
+        self.buf_size
+    }
+
+    /// Number of buffers
+    pub fn entries(&self) -> u16 {
+        self.entries
+    }
+
+    /// The buffer with id bid
+    ///
+    /// The kernel writes into the buffers it owns at any time, which the borrow checker cannot
+    /// see: recycle() takes &self, and nothing ties a bid to the cqe that reported it.
+    ///
+    /// # Safety
+    ///
+    /// The buffer needs to belong to the application: a cqe for a request that selected from
+    /// this ring's group needs to have reported bid as used, and the slice must not be used
+    /// after the buffer is recycled (which hands it back to the kernel).
+    pub unsafe fn buf(&self, bid: u16) -> &[u8] {
+        assert!(bid < self.entries, "invalid buffer id: {}", bid);
+        let ptr = self.bufs.wrapping_add(bid as usize * self.buf_size);
+        unsafe { std::slice::from_raw_parts(ptr, self.buf_size) }
+    }
+
+    /// Hand the buffer with id bid (back) to the kernel
+    // liburing: io_uring_buf_ring_add() + io_uring_buf_ring_advance()
+    pub fn recycle(&self, bid: u16) {
+        assert!(bid < self.entries, "invalid buffer id: {}", bid);
+        let tail = self.tail.get();
+        let idx = (tail & (self.entries - 1)) as usize;
+        // NB: do not write the whole entry: the resv field of the first one is the ring tail
+        unsafe {
+            let buf = self.ring.add(idx);
+            (*buf).addr = self.bufs.wrapping_add(bid as usize * self.buf_size) as u64;
+            (*buf).len = self.buf_size as u32;
+            (*buf).bid = bid;
+        }
+
+        // ensure the kernel sees the entry before the new tail
+        let tail = tail.wrapping_add(1);
+        self.tail.set(tail);
+        let ktail_p = unsafe { &(*self.ring).resv } as *const u16 as *const std::sync::atomic::AtomicU16;
+        unsafe { (*ktail_p).store(tail, std::sync::atomic::Ordering::Release) };
+    }
+}
+
+impl Drop for BufRing {
+    fn drop(&mut self) {
+        // NB: the ring needs to be unregistered before the buffers are freed, so that the kernel
+        // stops using them.
+        let mut reg = io_uring_buf_reg { bgid: self.bgid, ..Default::default() };
+        let arg = &mut reg as *mut io_uring_buf_reg as *mut libc::c_void;
+        let ring_size = self.entries as usize * mem::size_of::<io_uring_buf>();
+        unsafe {
+            io_uring_register(self.ring_fd, IORING_UNREGISTER_PBUF_RING, arg, 1);
+            close(self.ring_fd);
+            libc::free(self.bufs as *mut libc::c_void);
+            libc::munmap(self.ring as *mut libc::c_void, ring_size);
+        }
+    }
+}
+
+impl<'a> Acceptor<'a> {
+    /// Accept connections on the listening socket fd, with cqes tagged with user_data
+    ///
+    /// NB: Like submit_read_full(), this requires an idle ring.
+    pub fn new(ring: &'a mut IoUring, fd: libc::c_int, user_data: u64) -> Result<Acceptor<'a>, IoUringError> {
+        if ring.sq_ready() > 0 || ring.cq_ready() > 0 {
+            return Err(IoUringError::NotIdle)
+        }
+        Ok(Acceptor { ring, fd, user_data, armed: false })
+    }
+
+    /// Wait for the next connection, and return its fd
+    ///
+    /// The multishot accept is (re-)armed as needed: the kernel terminates it on errors, which
+    /// are returned here, and the next call re-arms it.
+    pub fn next_conn(&mut self) -> io::Result<libc::c_int> {
+        if !self.armed {
+            // NB: the ring is only used by the acceptor, so there is always an sqe
+            let mut sqe = self.ring.get_sqe().unwrap();
+            sqe.prep_multishot_accept(self.fd, libc::SOCK_CLOEXEC as u32);
+            sqe.set_data(self.user_data);
+            self.ring.submit()?;
+            self.armed = true;
+        }
+
+        let cqe = loop {
+            let cqe = self.ring.wait_cqe()?;
+            if cqe.user_data() == self.user_data {
+                break cqe;
+            }
+        };
+        if cqe.flags() & IORING_CQE_F_MORE == 0 {
+            self.armed = false;
+        }
+        match cqe.res() {
+            r if r < 0 => Err(io::Error::from_raw_os_error(-r)),
+            fd => Ok(fd),
+        }
+    }
+}
+
+impl Drop for Acceptor<'_> {
+    fn drop(&mut self) {
+        if !self.armed {
+            return
+        }
+        if self.ring.sync_cancel(CancelCriteria::UserData(self.user_data), None).is_err() {
+            return
+        }
+        // reap the remaining cqes, closing any connections accepted in the meantime
+        while let Ok(cqe) = self.ring.wait_cqe() {
+            if cqe.user_data() != self.user_data {
+                continue
+            }
+            if cqe.res() >= 0 {
+                unsafe { close(cqe.res()) };
+            }
+            if cqe.flags() & IORING_CQE_F_MORE == 0 {
+                break
+            }
+        }
+    }
+}
+
+impl SockAddr {
+    /// An empty address, e.g., to be filled by prep_accept_addr()
+    pub fn empty() -> SockAddr {
+        SockAddr {
+            storage: unsafe { mem::zeroed() },
+            len: mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t,
+        }
+    }
+
+    pub fn as_ptr(&self) -> *const libc::sockaddr {
+        &self.storage as *const libc::sockaddr_storage as *const libc::sockaddr
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut libc::sockaddr {
+        &mut self.storage as *mut libc::sockaddr_storage as *mut libc::sockaddr
+    }
+
+    /// Length of the address
+    pub fn len(&self) -> libc::socklen_t {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.storage.ss_family == libc::AF_UNSPEC as libc::sa_family_t
+    }
+
+    /// The address as a SocketAddr, if it is an IPv4 or IPv6 address
+    pub fn to_socket_addr(&self) -> Option<std::net::SocketAddr> {
+        match self.storage.ss_family as libc::c_int {
+            libc::AF_INET if self.len as usize >= mem::size_of::<libc::sockaddr_in>() => {
+                let sin = unsafe { &*(self.as_ptr() as *const libc::sockaddr_in) };
+                let ip = std::net::Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+                Some(std::net::SocketAddrV4::new(ip, u16::from_be(sin.sin_port)).into())
+            },
+            libc::AF_INET6 if self.len as usize >= mem::size_of::<libc::sockaddr_in6>() => {
+                let sin6 = unsafe { &*(self.as_ptr() as *const libc::sockaddr_in6) };
+                let ip = std::net::Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                let port = u16::from_be(sin6.sin6_port);
+                Some(std::net::SocketAddrV6::new(ip, port, sin6.sin6_flowinfo, sin6.sin6_scope_id).into())
+            },
+            _ => None,
+        }
+    }
+}
+
+impl From<std::net::SocketAddr> for SockAddr {
+    fn from(addr: std::net::SocketAddr) -> SockAddr {
+        let mut ret = SockAddr::empty();
+        match addr {
+            std::net::SocketAddr::V4(a) => {
+                let sin = unsafe { &mut *(ret.as_mut_ptr() as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = a.port().to_be();
+                sin.sin_addr.s_addr = u32::from_ne_bytes(a.ip().octets());
+                ret.len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            },
+            std::net::SocketAddr::V6(a) => {
+                let sin6 = unsafe { &mut *(ret.as_mut_ptr() as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = a.port().to_be();
+                sin6.sin6_flowinfo = a.flowinfo();
+                sin6.sin6_addr.s6_addr = a.ip().octets();
+                sin6.sin6_scope_id = a.scope_id();
+                ret.len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            },
+        }
+        ret
+    }
+}
+
+// queue functions: polling the ring fd
+impl IoUring {
+    /// Check, without blocking, whether there are cqes ready to be reaped.
+    ///
+    /// This uses poll(2) on the ring fd, which reports POLLIN when the CQ is not empty. The ring
+    /// fd is pollable since the first io_uring kernel (5.1), so external poll loops can wait on
+    /// it directly without registering an eventfd.
+    pub fn is_readable(&self) -> io::Result<bool> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ret = unsafe { libc::poll(&mut pfd, 1, 0) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(pfd.revents & libc::POLLIN != 0)
+    }
+
+    /// Set or clear O_NONBLOCK on the ring fd
+    ///
+    /// This is for reactors that require their fds to be nonblocking. Note that io_uring_enter()
+    /// ignores O_NONBLOCK: waiting for completions still blocks, so a reactor should only reap
+    /// cqes after the fd polls readable (see is_readable()), or use wait_only(0).
+    pub fn set_nonblocking(&self, nb: bool) -> io::Result<()> {
+        let flags = unsafe { libc::fcntl(self.fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        let new_flags = if nb { flags | libc::O_NONBLOCK } else { flags & !libc::O_NONBLOCK };
+        if new_flags != flags && unsafe { libc::fcntl(self.fd, libc::F_SETFL, new_flags) } < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(())
+    }
+
+    /// Is O_NONBLOCK set on the ring fd? (see set_nonblocking())
+    pub fn is_nonblocking(&self) -> io::Result<bool> {
+        let flags = unsafe { libc::fcntl(self.fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(flags & libc::O_NONBLOCK != 0)
+    }
+}
+
+impl UserData {
+    /// Pack tag, gen, and index into a user_data value
+    #[allow(clippy::new_ret_no_self)] // the packed value is what SQEntry::set_data() takes
+    pub fn new(tag: u16, gen: u16, index: u32) -> u64 {
+        (tag as u64) << 48 | (gen as u64) << 32 | index as u64
+    }
+
+    pub fn tag(&self) -> u16 {
+        (self.0 >> 48) as u16
+    }
+
+    pub fn gen(&self) -> u16 {
+        (self.0 >> 32) as u16
+    }
+
+    pub fn index(&self) -> u32 {
+        self.0 as u32
+    }
+}
+
+impl From<u64> for UserData {
+    fn from(data: u64) -> UserData {
+        UserData(data)
+    }
+}
+
+impl Generations {
+    /// Generations for indices 0..n, all starting at 0
+    pub fn new(n: usize) -> Generations {
+        Generations(vec![0; n])
+    }
+
+    /// Current generation of index
+    pub fn current(&self, index: u32) -> u16 {
+        self.0[index as usize]
+    }
+
+    /// Move index to the next generation (e.g., when it is reused), and return it
+    ///
+    /// Completions of requests submitted with an older generation are stale after this.
+    pub fn bump(&mut self, index: u32) -> u16 {
+        let gen = &mut self.0[index as usize];
+        *gen = gen.wrapping_add(1);
+        *gen
+    }
+
+    /// user_data for a request on index, with its current generation
+    pub fn user_data(&self, tag: u16, index: u32) -> u64 {
+        UserData::new(tag, self.current(index), index)
+    }
+
+    /// Is data for an older generation of its index (or for an unknown index)?
+    pub fn is_stale(&self, data: UserData) -> bool {
+        self.0.get(data.index() as usize) != Some(&data.gen())
+    }
+}
+
+impl io_uring_sqe {
+    /// An sqe for opcode (IORING_OP_*), with all other fields zeroed
+    pub fn new(opcode: u8) -> io_uring_sqe {
+        let mut sqe: io_uring_sqe = unsafe { mem::zeroed() };
+        sqe.opcode = opcode;
+        sqe
+    }
+
+    /// An sqe with the given (raw) contents, e.g., one built by C code
+    pub fn from_bytes(bytes: [u8; 64]) -> io_uring_sqe {
+        unsafe { mem::transmute(bytes) }
+    }
+
+    /// The raw contents of the sqe
+    pub fn as_bytes(&self) -> &[u8; 64] {
+        unsafe { &*(self as *const io_uring_sqe as *const [u8; 64]) }
+    }
+
+    /// IOSQE_* flags
+    pub fn with_flags(mut self, flags: u8) -> io_uring_sqe {
+        self.flags = flags;
+        self
+    }
+
+    pub fn with_ioprio(mut self, ioprio: u16) -> io_uring_sqe {
+        self.ioprio = ioprio;
+        self
+    }
+
+    pub fn with_fd(mut self, fd: libc::c_int) -> io_uring_sqe {
+        self.fd = fd;
+        self
+    }
+
+    pub fn with_off(mut self, off: u64) -> io_uring_sqe {
+        self.off = off;
+        self
+    }
+
+    pub fn with_addr(mut self, addr: u64) -> io_uring_sqe {
+        self.addr = addr;
+        self
+    }
+
+    pub fn with_len(mut self, len: u32) -> io_uring_sqe {
+        self.len = len;
+        self
+    }
+
+    /// The operation-specific flags (e.g., rw_flags, or msg_flags)
+    pub fn with_op_flags(mut self, flags: u32) -> io_uring_sqe {
+        self.args.msg_flags = flags;
+        self
+    }
+
+    pub fn with_user_data(mut self, user_data: u64) -> io_uring_sqe {
+        self.user_data = user_data;
+        self
+    }
+}
+
+impl io_uring_cqe {
+    /// user data of the sqe for this completion (see SQEntry::set_data())
+    pub fn user_data(&self) -> u64 {
+        self.user_data
+    }
+
+    /// result of the operation: negative values are -errno
+    pub fn res(&self) -> i32 {
+        self.res
+    }
+
+    /// flags of the completion (IORING_CQE_F_*)
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// the socket has more data to read after this recv completion (5.19+)
+    ///
+    /// The recv can be re-armed immediately, without waiting for a poll.
+    pub fn sock_nonempty(&self) -> bool {
+        self.flags & IORING_CQE_F_SOCK_NONEMPTY != 0
+    }
+
+    /// id of the provided buffer used by the request, if any (see BufRing)
+    pub fn buffer_id(&self) -> Option<u16> {
+        if self.flags & IORING_CQE_F_BUFFER != 0 {
+            Some((self.flags >> 16) as u16)
+        } else {
+            None
+        }
+    }
+
+    /// interpret the completion of a multishot read (see SQEntry::prep_read_multishot())
+    ///
+    /// Anything but Data with more set means that the read is no longer armed.
+    pub fn multishot_read(&self) -> MultishotRead {
+        match self.res {
+            r if r == -libc::ENOBUFS => MultishotRead::NeedBuffers,
+            r if r < 0 => MultishotRead::Error(Errno(-r)),
+            0 => MultishotRead::Eof,
+            r => match self.buffer_id() {
+                Some(bid) => MultishotRead::Data { bid, len: r as usize, more: self.flags & IORING_CQE_F_MORE != 0 },
+                // NB: a read always consumes a buffer, so this should not happen
+                None => MultishotRead::Error(Errno(libc::EINVAL)),
+            },
+        }
+    }
+
+    /// this is the notification cqe of a zero-copy send (6.0+)
+    ///
+    /// A zero-copy send posts two cqes: the first one has the result (and IORING_CQE_F_MORE
+    /// set), and the notification is posted when the buffer can be reused.
+    pub fn is_notif(&self) -> bool {
+        self.flags & IORING_CQE_F_NOTIF != 0
+    }
+
+    /// interpret the result, given the operation (IORING_OP_*) of the sqe for this completion
+    pub fn interpret(&self, op: u8) -> CqeResult {
+        if self.res < 0 {
+            return CqeResult::Error(Errno(-self.res));
+        }
+        match op {
+            IORING_OP_ACCEPT | IORING_OP_OPENAT | IORING_OP_OPENAT2 | IORING_OP_SOCKET
+            | IORING_OP_FIXED_FD_INSTALL => CqeResult::Fd(self.res),
+            IORING_OP_POLL_ADD => CqeResult::Events(self.res as u32),
+            _ => CqeResult::Bytes(self.res as usize),
+        }
+    }
+
+    /// result of a poll (e.g., prep_poll_add()): the mask of returned events (POLL*), or the
+    /// error
+    ///
+    /// The mask is 32 bits wide, as for prep_poll_add32(), so no returned bits are dropped.
+    pub fn poll_result(&self) -> io::Result<u32> {
+        match self.res {
+            r if r < 0 => Err(io::Error::from_raw_os_error(-r)),
+            r => Ok(r as u32),
+        }
+    }
+}
+
+impl From<Errno> for io::Error {
+    fn from(e: Errno) -> io::Error {
+        io::Error::from_raw_os_error(e.0)
+    }
+}
+
+impl std::ops::Deref for CqeGuard<'_> {
+    type Target = io_uring_cqe;
+
+    fn deref(&self) -> &io_uring_cqe {
+        // NB: the kernel does not write to the entry until the CQ head moves past it, which
+        // happens only when the guard is dropped.
+        unsafe { &*self.cqe }
+    }
+}
+
+impl CqeGuard<'_> {
+    /// The extra 16 bytes of a 32-byte cqe (see Builder::cqe32()), or None for 16-byte cqes
+    pub fn big_cqe(&self) -> Option<[u64; 2]> {
+        if self.ior.cq.cqe_shift == 0 {
+            return None
+        }
+        let extra = unsafe { self.cqe.add(1) as *const [u64; 2] };
+        Some(unsafe { std::ptr::read_volatile(extra) })
+    }
+}
+
+impl Drop for CqeGuard<'_> {
+    fn drop(&mut self) {
+        self.ior.cq_advance(1);
+    }
+}
+
+impl<'a> Iterator for CqIter<'a> {
+    type Item = io_uring_cqe;
+
+    fn next(&mut self) -> Option<io_uring_cqe> {
+        let ktail_p = self.cq.ktail as *mut std::sync::atomic::AtomicU32;
+        let tail_ = unsafe { (&*ktail_p).load(std::sync::atomic::Ordering::Acquire) };
+        let tail = std::num::Wrapping(tail_);
+        if self.curr == tail {
+            return None
+        }
+
+        let mask = unsafe { *self.cq.kring_mask };
+        let idx = self.curr.0 & mask;
+        let cqe: io_uring_cqe = unsafe {
+            read_cqe_volatile(self.cq.cqes.add((idx << self.cq.cqe_shift) as usize))
+        };
+        self.curr += std::num::Wrapping(1);
+        Some(cqe)
+
+    }
+}
+
+impl IoUring {
+    // /// Fill the next SQEntry in the queue via the provided function.
+    // ///
+    // /// Returns:
+    // ///  None: queue is full (fill function was not executed)
+    // ///  Some(Err(x)): fill function returned Err(x), queue was not updated
+    // ///  Some(Err(x)): fill function returned Ok(x), queue was updated
+    // pub fn fill_next_sqe<F, E, R>(&mut self, fill: F) -> Option<Result<E,R>>
+    // where F: FnOnce(&mut SQEntry) -> Result<E,R> {
+    //     let sq = &mut self.sq;
+    //     let next: u32 = sq.sqe_tail + 1;
+    //     let nentries: u32 = unsafe { *sq.kring_entries };
+    //     if next - sq.sqe_head > nentries {
+    //         return None
+    //     }
+
+    //     let mut sqe = {
+    //         let mask = unsafe { *sq.kring_mask };
+    //         let idx = sq.sqe_tail & mask;
+    //         let sqe_p = unsafe { sq.sqes.offset(idx as isize) };
+    //         SQEntry(sqe_p)
+    //     };
+    //     sqe.reset();
+
+    //     let fret = fill(&mut sqe);
+    //     if fret.is_ok() {
+    //         // update tail to commit new entry
+    //         sq.sqe_tail = next;
+    //     }
+
+    //     Some(fret)
+    // }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get the value of field from the /proc/self/smaps entry that contains addr
+    fn smaps_field(addr: *mut libc::c_void, field: &str) -> Option<String> {
+        let addr = addr as u64;
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let mut in_region = false;
+        for line in smaps.lines() {
+            let first = line.split_whitespace().next().unwrap_or("");
+            if let Some((start, end)) = first.split_once('-') {
+                if let (Ok(start), Ok(end)) = (u64::from_str_radix(start, 16), u64::from_str_radix(end, 16)) {
+                    in_region = start <= addr && addr < end;
+                    continue;
+                }
+            }
+            if in_region && line.starts_with(field) && line[field.len()..].starts_with(':') {
+                return Some(line[field.len() + 1..].trim().to_string());
+            }
+        }
+        None
+    }
+
+    /// Is the region that contains addr locked?
+    ///
+    /// mlock() ignores special mappings (pfn, mixed, or io maps), whose pages are never swapped
+    /// out, so treat those as locked as well.
+    fn smaps_locked(addr: *mut libc::c_void) -> bool {
+        let locked = smaps_field(addr, "Locked").unwrap();
+        let vmflags = smaps_field(addr, "VmFlags").unwrap();
+        locked != "0 kB" || vmflags.split_whitespace().any(|f| ["lo", "pf", "mm", "io"].contains(&f))
+    }
+
+    #[test]
+    fn lock_memory() {
+        let ior = match Builder::new(4).lock_memory(true).build() {
+            Ok(x) => x,
+            // memlock limit is not sufficient
+            Err(IoUringError::Setup(e)) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) => panic!("{}", e),
+        };
+        assert!(smaps_locked(ior.sq.ring_ptr));
+        assert!(smaps_locked(ior.sq.sqes as *mut libc::c_void));
+        assert!(smaps_locked(ior.cq.ring_ptr));
+
+        // the locked ring works as usual
+        let mut ior = ior;
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+        assert_eq!(ior.for_each_completion(|cqe| assert_eq!(cqe.res(), 0)), 1);
+    }
+
+    #[test]
+    fn kick_sqpoll() {
+        let mut ior = match Builder::new(8).sqpoll(1).build() {
+            Ok(x) => x,
+            // SQPOLL requires privileges on older kernels
+            Err(IoUringError::Setup(e)) if e.kind() == io::ErrorKind::PermissionDenied => return,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!(ior.sq_thread_idle(), 1);
+        assert!(matches!(IoUring::init(4).unwrap().kick_sqpoll(), Err(IoUringError::InvalidInput(_))));
+        let disabled = Builder::new(4).sqpoll(1).disabled(true).build().unwrap();
+        assert!(matches!(disabled.kick_sqpoll(), Err(IoUringError::RingDisabled)));
+
+        // wait for the poll thread to go to sleep
+        let need_wakeup = |ior: &IoUring| unsafe {
+            let flags = std::ptr::read_volatile(ior.sq.kflags);
+            SQFlags::from_bits_unchecked(flags).contains(SQFlags::NEED_WAKEUP)
+        };
+        for _ in 0..1000 {
+            if need_wakeup(&ior) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(need_wakeup(&ior));
+
+        // queue work without entering the kernel, and kick the thread to process it
+        for i in 0..4 {
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_nop();
+            sqe.set_data(i);
+        }
+        assert_eq!(ior.flush_sq(), 4);
+        ior.kick_sqpoll().unwrap();
+
+        let mut nr = 0;
+        for _ in 0..1000 {
+            nr += ior.for_each_completion(|_| ());
+            if nr == 4 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(nr, 4);
+    }
+
+    #[test]
+    fn error_variants() {
+        match IoUring::init(0) {
+            Err(IoUringError::Setup(e)) => assert_eq!(e.raw_os_error(), Some(libc::EINVAL)),
+            _ => panic!("expected a setup error"),
+        }
+
+        let mut ior = IoUring::init(4).unwrap();
+        match ior.register_eventfd(-1) {
+            Err(IoUringError::Register(e)) => assert_eq!(e.raw_os_error(), Some(libc::EBADF)),
+            _ => panic!("expected a register error"),
+        }
+
+        // point the ring to an fd that is not an io_uring, and try to submit
+        let fd = ior.fd;
+        ior.fd = std::os::unix::io::IntoRawFd::into_raw_fd(std::fs::File::open("/dev/null").unwrap());
+        ior.get_sqe().unwrap().prep_nop();
+        let res = ior.submit();
+        unsafe { libc::close(ior.fd) };
+        ior.fd = fd;
+        match res {
+            Err(IoUringError::Submit(e)) => assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP)),
+            _ => panic!("expected a submit error"),
+        }
+    }
+
+    #[test]
+    fn submit_all() {
+        let queue_batch = |ior: &mut IoUring| {
+            for i in 0..3 {
+                let mut sqe = ior.get_sqe().unwrap();
+                if i == 1 {
+                    sqe.prep_rw(IORING_OP_INVALID, -1, std::ptr::null(), 0, 0);
+                } else {
+                    sqe.prep_nop();
+                }
+                sqe.set_data(i);
+            }
+        };
+
+        let mut ior = Builder::new(4).submit_all(true).build().unwrap();
+        queue_batch(&mut ior);
+        assert_eq!(ior.submit().unwrap(), 3);
+        let mut res = vec![];
+        ior.for_each_completion(|cqe| res.push((cqe.user_data(), cqe.res())));
+        assert_eq!(res, vec![(0, 0), (1, -libc::EINVAL), (2, 0)]);
+
+        // without submit_all, the submission stops at the invalid sqe
+        let mut ior = IoUring::init(4).unwrap();
+        queue_batch(&mut ior);
+        assert_eq!(ior.submit().unwrap(), 2);
+        let mut res = vec![];
+        ior.for_each_completion(|cqe| res.push((cqe.user_data(), cqe.res())));
+        assert_eq!(res, vec![(0, 0), (1, -libc::EINVAL)]);
+    }
+
+    #[test]
+    fn check_op() {
+        let mut ior = IoUring::init(4).unwrap();
+        assert!(ior.check_op(IORING_OP_NOP).is_ok());
+        assert!(ior.check_op(IORING_OP_WRITE).is_ok());
+        assert!(matches!(ior.check_op(IORING_OP_INVALID), Err(IoUringError::Unsupported(IORING_OP_INVALID))));
+
+        // mock an old (5.6) kernel, where WRITE is the last operation
+        let mut probe: Box<io_uring_probe> = Box::new(unsafe { mem::zeroed() });
+        probe.last_op = IORING_OP_WRITE;
+        probe.ops_len = IORING_OP_WRITE + 1;
+        for op in 0..=IORING_OP_WRITE {
+            probe.ops[op as usize] = io_uring_probe_op { op, resv: 0, flags: IO_URING_OP_SUPPORTED, resv2: 0 };
+        }
+        ior.probe = Some(Probe(probe));
+        assert_eq!(ior.probe().unwrap().last_op(), IORING_OP_WRITE);
+        assert!(ior.check_op(IORING_OP_WRITE).is_ok());
+        let newer_op = IORING_OP_WRITE + 1;
+        assert!(matches!(ior.check_op(newer_op), Err(IoUringError::Unsupported(op)) if op == newer_op));
+    }
+
+    #[test]
+    fn sqpoll_cpu() {
+        assert!(matches!(Builder::new(4).sqpoll_cpu(0).build(), Err(IoUringError::InvalidInput(_))));
+
+        let ior = match Builder::new(4).sqpoll(0).sqpoll_cpu(0).build() {
+            Ok(x) => x,
+            // SQPOLL requires privileges on older kernels
+            Err(IoUringError::Setup(e)) if e.kind() == io::ErrorKind::PermissionDenied => return,
+            Err(e) => panic!("{}", e),
+        };
+        assert!(ior.flags.contains(SetupFlags::SQ_AFF));
+        assert_eq!(ior.sqpoll_cpu(), Some(0));
+
+        let ior = Builder::new(4).sqpoll(0).build().unwrap();
+        assert_eq!(ior.sqpoll_cpu(), None);
+    }
+
+    #[test]
+    fn no_sqarray() {
+        let mut ior = match Builder::new(4).no_sqarray(true).build() {
+            Ok(x) => x,
+            // not supported (before 6.6)
+            Err(IoUringError::Setup(e)) if e.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(e) => panic!("{}", e),
+        };
+        assert!(ior.sq.array.is_null());
+
+        // go around the ring a few times
+        for round in 0..3 {
+            for i in 0..4 {
+                let mut sqe = ior.get_sqe().unwrap();
+                sqe.prep_nop();
+                sqe.set_data(round * 4 + i);
+            }
+            assert_eq!(ior.submit_and_wait(4).unwrap(), 4);
+            let data: Vec<u64> = ior.drain_up_to(4).iter().map(|cqe| cqe.user_data()).collect();
+            assert_eq!(data, (round * 4..(round + 1) * 4).collect::<Vec<u64>>());
+        }
+    }
+
+    #[test]
+    fn no_sqarray_ring_size() {
+        let ior = match Builder::new(4).no_sqarray(true).build() {
+            Ok(x) => x,
+            // not supported (before 6.6)
+            Err(IoUringError::Setup(e)) if e.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(e) => panic!("{}", e),
+        };
+        // the kernel reports no array, and the SQ ring is sized by the cqes at its end
+        let p = ior.params();
+        assert_eq!(p.sq_off().array, 0);
+        let expected = p.cq_off().cqes as usize + p.cq_entries() as usize * mem::size_of::<io_uring_cqe>();
+        assert_eq!(ior.sq.ring_sz, expected);
+
+        // and that is what is mapped
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let aligned = expected.div_ceil(page) * page;
+        let size_kb = smaps_field(ior.sq.ring_ptr, "Size").unwrap();
+        assert_eq!(size_kb, format!("{} kB", aligned / 1024));
+    }
+
+    #[test]
+    fn index_wrap() {
+        let mut ior = IoUring::init(4).unwrap();
+
+        // NB: we only modify the shared indices for checking the computations, so nothing is
+        // submitted to the kernel.
+        unsafe {
+            *ior.cq.khead = u32::MAX - 1;
+            *ior.cq.ktail = 2;
+        }
+        assert_eq!(ior.cq_ready(), 4);
+
+        unsafe { *ior.sq.khead = u32::MAX - 1 };
+        ior.sq.sqe_head = std::num::Wrapping(u32::MAX - 1);
+        ior.sq.sqe_tail = std::num::Wrapping(u32::MAX - 1);
+        assert_eq!(ior.sq_ready(), 0);
+        assert_eq!(ior.sq_space_left(), 4);
+        for i in 0..4 {
+            assert!(ior.get_sqe().is_some());
+            assert_eq!(ior.sq_ready(), i + 1);
+            assert_eq!(ior.sq_space_left(), 4 - (i + 1));
+        }
+        assert_eq!(ior.sq.sqe_tail.0, 2);
+        assert!(ior.get_sqe().is_none());
+
+        // kernel consumes two entries across the wrap
+        unsafe { *ior.sq.khead = 0 };
+        assert_eq!(ior.sq_ready(), 2);
+        assert_eq!(ior.sq_space_left(), 2);
+    }
+
+    #[test]
+    fn sqpoll_submit_no_syscall() {
+        let mut ior = match Builder::new(8).sqpoll(2000).build() {
+            Ok(x) => x,
+            // SQPOLL requires privileges on older kernels
+            Err(IoUringError::Setup(e)) if e.kind() == io::ErrorKind::PermissionDenied => return,
+            Err(e) => panic!("{}", e),
+        };
+
+        let wait_cqes = |ior: &mut IoUring, nr: u32| {
+            let mut done = 0;
+            for _ in 0..1000 {
+                done += ior.for_each_completion(|_| ());
+                if done == nr {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            assert_eq!(done, nr);
+        };
+
+        // make sure the poll thread is awake
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!(ior.submit().unwrap(), 1);
+        wait_cqes(&mut ior, 1);
+
+        // point the ring to an invalid fd: any io_uring_enter() would fail with EBADF
+        let fd = ior.fd;
+        ior.fd = -1;
+        for _ in 0..4 {
+            ior.get_sqe().unwrap().prep_nop();
+        }
+        let res = ior.submit();
+        ior.fd = fd;
+        assert_eq!(res.unwrap(), 4);
+        wait_cqes(&mut ior, 4);
+    }
+
+    #[test]
+    fn ioprio() {
+        let mut ior = IoUring::init(4).unwrap();
+        let f = std::fs::File::open("/dev/zero").unwrap();
+        let mut buf = [1u8; 16];
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_read(std::os::unix::io::AsRawFd::as_raw_fd(&f), buf.as_mut_ptr() as *mut libc::c_void, 16, 0);
+        sqe.set_ioprio(IOPRIO_CLASS_RT, 2);
+        assert_eq!(unsafe { (*sqe.0).ioprio }, (1 << 13) | 2);
+
+        ior.submit_and_wait(1).unwrap();
+        let res = ior.wait_cqe().unwrap().res();
+        // RT needs privileges
+        if res == -libc::EPERM {
+            return;
+        }
+        assert_eq!(res, 16);
+        assert_eq!(buf, [0u8; 16]);
+    }
+
+    #[test]
+    fn cqe_flags() {
+        let cqe = |flags| io_uring_cqe { user_data: 0, res: 0, flags };
+
+        let c = cqe(0);
+        assert!(!c.sock_nonempty() && !c.is_notif());
+        let c = cqe(IORING_CQE_F_SOCK_NONEMPTY);
+        assert!(c.sock_nonempty() && !c.is_notif());
+        let c = cqe(IORING_CQE_F_NOTIF);
+        assert!(!c.sock_nonempty() && c.is_notif());
+        let c = cqe(IORING_CQE_F_MORE | IORING_CQE_F_SOCK_NONEMPTY | IORING_CQE_F_NOTIF);
+        assert!(c.sock_nonempty() && c.is_notif());
+    }
+
+    #[test]
+    fn short_submit() {
+        let mut ior = IoUring::init(8).unwrap();
+        for i in 0..5 {
+            let mut sqe = ior.get_sqe().unwrap();
+            if i == 1 {
+                sqe.prep_rw(IORING_OP_INVALID, -1, std::ptr::null(), 0, 0);
+            } else {
+                sqe.prep_nop();
+            }
+            sqe.set_data(i);
+        }
+
+        // the submission stops at the invalid sqe, and the rest remain queued
+        assert_eq!(ior.submit().unwrap(), 2);
+        assert_eq!(ior.sq_ready(), 3);
+        assert_eq!(ior.sq_space_left(), 5);
+
+        // submitting again picks up the remainder
+        assert_eq!(ior.submit_and_wait(3).unwrap(), 3);
+        assert_eq!(ior.sq_ready(), 0);
+        let mut res = vec![];
+        ior.for_each_completion(|cqe| res.push((cqe.user_data(), cqe.res())));
+        assert_eq!(res, vec![(0, 0), (1, -libc::EINVAL), (2, 0), (3, 0), (4, 0)]);
+
+        // afterwards, only new sqes are submitted
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!(ior.submit().unwrap(), 1);
+    }
+
+    #[test]
+    fn uring_cmd_layout() {
+        let mut ior = IoUring::init(4).unwrap();
+        let f = std::fs::File::open("/dev/null").unwrap();
+        let fd = std::os::unix::io::AsRawFd::as_raw_fd(&f);
+        let data: Vec<u8> = (1..=16).collect();
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_uring_cmd(fd, 0xdead_beef, &data);
+
+        let bytes = unsafe { std::slice::from_raw_parts(sqe.0 as *const u8, 64) };
+        assert_eq!(bytes[0], IORING_OP_URING_CMD);
+        assert_eq!(&bytes[4..8], &fd.to_ne_bytes());
+        // cmd_op at offset 8, cmd at offset 48
+        assert_eq!(&bytes[8..12], &0xdead_beef_u32.to_ne_bytes());
+        assert_eq!(&bytes[12..48], &[0u8; 36][..]);
+        assert_eq!(&bytes[48..64], &data[..]);
+
+        // newer kernels implement commands on /dev/null as no-ops
+        ior.submit_and_wait(1).unwrap();
+        let res = ior.wait_cqe().unwrap().res();
+        assert!(res == 0 || res == -libc::EOPNOTSUPP, "unexpected result: {}", res);
+
+        // pipes do not support commands
+        let mut fds = [-1; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        ior.get_sqe().unwrap().prep_uring_cmd(fds[0], 0, &[]);
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), -libc::EOPNOTSUPP);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn poll_add32() {
+        let mut ior = IoUring::init(4).unwrap();
+        let mut fds = [-1; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        let mask = (libc::EPOLLIN | libc::EPOLLEXCLUSIVE) as u32;
+        assert!(mask > u16::MAX as u32);
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_poll_add32(fds[0], mask);
+        let sqe_ref = unsafe { &*sqe.0 };
+        assert_eq!((sqe_ref.off, sqe_ref.addr, sqe_ref.len), (0, 0, 0));
+        assert_eq!(unsafe { sqe_ref.args.poll32_events }, mask);
+        assert_eq!(ior.submit().unwrap(), 1);
+        assert_eq!(ior.cq_ready(), 0);
+
+        assert_eq!(unsafe { libc::write(fds[1], b"x".as_ptr() as _, 1) }, 1);
+        let cqe = ior.wait_cqe().unwrap();
+        assert_eq!(cqe.res(), libc::POLLIN as i32);
+        assert_eq!(cqe.poll_result().unwrap(), cqe.res() as u32);
+        assert_eq!(cqe.interpret(IORING_OP_POLL_ADD), CqeResult::Events(cqe.res() as u32));
+
+        // the decoders keep the high bits of the returned mask
+        let mask = (libc::EPOLLIN | libc::EPOLLEXCLUSIVE) as u32;
+        let high = io_uring_cqe { user_data: 0, res: mask as i32, flags: 0 };
+        assert_eq!(high.poll_result().unwrap(), mask);
+        assert_eq!(high.interpret(IORING_OP_POLL_ADD), CqeResult::Events(mask));
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn submit_after_cq_overflow() {
+        let mut ior = IoUring::init(4).unwrap();
+        let cq_entries = ior.cq_entries();
+        let sq_flags = |ior: &IoUring| SQFlags::from_bits_truncate(unsafe { *ior.sq.kflags });
+
+        // overflow the CQ, without consuming any cqes
+        let total = cq_entries + 8;
+        for _ in 0..total / 4 {
+            for _ in 0..4 {
+                ior.get_sqe().unwrap().prep_nop();
+            }
+            assert_eq!(ior.submit().unwrap(), 4);
+        }
+        assert_eq!(ior.cq_ready(), cq_entries);
+        assert!(sq_flags(&ior).contains(SQFlags::CQ_OVERFLOW));
+
+        // make space in the CQ, and submit: the overflowed cqes are not lost
+        ior.cq_advance(cq_entries);
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!(ior.submit().unwrap(), 1);
+        let mut reaped = cq_entries;
+        while reaped < total + 1 {
+            ior.wait_cqe().unwrap();
+            reaped += 1;
+        }
+        assert!(!sq_flags(&ior).contains(SQFlags::CQ_OVERFLOW));
+        assert_eq!(ior.cq_ready(), 0);
+    }
+
+    #[test]
+    fn registered_ring() {
+        let mut ior = IoUring::init(4).unwrap();
+        let idx = ior.register_ring_fd().unwrap();
+        assert_eq!(ior.register_ring_fd().unwrap(), idx);
+        let (fd, flags) = ior.enter_target(EnterFlags::GETEVENTS);
+        assert_eq!(fd, idx as libc::c_int);
+        assert_eq!(flags, EnterFlags::GETEVENTS | EnterFlags::REGISTERED_RING);
+
+        // point the ring to an invalid fd: io_uring_enter() needs to use the registered index
+        let ring_fd = ior.fd;
+        ior.fd = -1;
+        ior.get_sqe().unwrap().prep_nop();
+        let res = ior.submit_and_wait(1);
+        let cqe = ior.wait_cqe();
+        ior.fd = ring_fd;
+        assert_eq!(res.unwrap(), 1);
+        assert_eq!(cqe.unwrap().res(), 0);
+
+        ior.unregister_ring_fd().unwrap();
+        assert!(ior.unregister_ring_fd().is_err());
+        assert_eq!(ior.enter_target(EnterFlags::GETEVENTS), (ring_fd, EnterFlags::GETEVENTS));
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+    }
+
+    #[test]
+    fn setup_error() {
+        let err = SetupError::annotate(io::Error::from_raw_os_error(libc::ENOSYS));
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        let setup_err = err.get_ref().and_then(|e| e.downcast_ref::<SetupError>()).unwrap();
+        assert_eq!(setup_err.errno(), libc::ENOSYS);
+        assert!(err.to_string().contains("CONFIG_IO_URING"));
+
+        let err = SetupError::annotate(io::Error::from_raw_os_error(libc::EPERM));
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("/proc/sys/kernel/io_uring_disabled"));
+
+        // other errors are not annotated
+        let err = SetupError::annotate(io::Error::from_raw_os_error(libc::EINVAL));
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn splice_layout() {
+        let mut ior = IoUring::init(4).unwrap();
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_splice(7, SPLICE_OFF_NONE, 9, 4096, 100, SPLICE_F_FD_IN_FIXED);
+        let bytes = unsafe { std::slice::from_raw_parts(sqe.0 as *const u8, 64) };
+        assert_eq!(bytes[0], IORING_OP_SPLICE);
+        assert_eq!(bytes[4..8], 9i32.to_ne_bytes());          // fd (fd_out)
+        assert_eq!(bytes[8..16], 4096u64.to_ne_bytes());      // off (off_out)
+        assert_eq!(bytes[16..24], u64::MAX.to_ne_bytes());    // addr (splice_off_in)
+        assert_eq!(bytes[24..28], 100u32.to_ne_bytes());      // len
+        assert_eq!(bytes[28..32], (1u32 << 31).to_ne_bytes()); // splice_flags
+        assert_eq!(bytes[44..48], 7i32.to_ne_bytes());        // splice_fd_in
+    }
+
+    /// A simulated kernel: the rings live in user memory, and NOPs complete on submission
+    struct MockSyscalls {
+        entries: u32,
+        // NB: addresses (not pointers), so that the mock is Send + Sync
+        sq_ring: usize,
+        cq_ring: usize,
+        sqes: usize,
+        // errnos to fail the next enter() calls with
+        enter_errors: std::sync::Mutex<Vec<i32>>,
+        enters: std::sync::atomic::AtomicU32,
+        // mmap() offset (IORING_OFF_*) to fail with MAP_FAILED
+        mmap_fail: Option<libc::off_t>,
+        // addresses passed to munmap()
+        munmaps: std::sync::Mutex<Vec<usize>>,
+    }
+
+    // SQ ring layout (in u32s), followed by the array
+    const MOCK_SQ_WORDS: usize = 8;
+    // CQ ring layout (in u32s), followed by the cqes
+    const MOCK_CQ_WORDS: usize = 8;
+
+    impl MockSyscalls {
+        fn new(entries: u32) -> MockSyscalls {
+            let alloc = |bytes: usize| Box::into_raw(vec![0u64; bytes / 8].into_boxed_slice()) as *mut u64 as usize;
+            let n = entries as usize;
+            MockSyscalls {
+                entries,
+                sq_ring: alloc(4 * (MOCK_SQ_WORDS + n)),
+                cq_ring: alloc(4 * MOCK_CQ_WORDS + 16 * 2 * n),
+                sqes: alloc(64 * n),
+                enter_errors: std::sync::Mutex::new(vec![]),
+                enters: std::sync::atomic::AtomicU32::new(0),
+                mmap_fail: None,
+                munmaps: std::sync::Mutex::new(vec![]),
+            }
+        }
+
+        fn sq(&self, word: usize) -> *mut u32 {
+            (self.sq_ring as *mut u32).wrapping_add(word)
+        }
+
+        fn cq(&self, word: usize) -> *mut u32 {
+            (self.cq_ring as *mut u32).wrapping_add(word)
+        }
+
+        /// Set all the ring indices to idx (e.g., to check wraparound)
+        unsafe fn set_indices(&self, idx: u32) {
+            for p in [self.sq(0), self.sq(1), self.cq(0), self.cq(1)] {
+                *p = idx;
+            }
+        }
+    }
+
+    impl Drop for MockSyscalls {
+        fn drop(&mut self) {
+            let n = self.entries as usize;
+            let free = |addr: usize, bytes: usize| unsafe {
+                drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(addr as *mut u64, bytes / 8)));
+            };
+            free(self.sq_ring, 4 * (MOCK_SQ_WORDS + n));
+            free(self.cq_ring, 4 * MOCK_CQ_WORDS + 16 * 2 * n);
+            free(self.sqes, 64 * n);
+        }
+    }
+
+    unsafe impl Syscalls for MockSyscalls {
+        unsafe fn setup(&self, entries: libc::c_uint, params: *mut io_uring_params) -> libc::c_int {
+            assert_eq!(entries, self.entries);
+            let p = &mut *params;
+            p.sq_entries = entries;
+            p.cq_entries = 2 * entries;
+            p.sq_off = io_sqring_offsets { head: 0, tail: 4, ring_mask: 8, ring_entries: 12, flags: 16, dropped: 20,
+                                           array: 4 * MOCK_SQ_WORDS as u32, resv1: 0, resv2: 0 };
+            p.cq_off = io_cqring_offsets { head: 0, tail: 4, ring_mask: 8, ring_entries: 12, overflow: 16,
+                                           cqes: 4 * MOCK_CQ_WORDS as u32, resv: [0; 2] };
+            *self.sq(2) = entries - 1;
+            *self.sq(3) = entries;
+            *self.cq(2) = 2 * entries - 1;
+            *self.cq(3) = 2 * entries;
+            // NB: the ring fd needs to be a real fd, since it is closed on drop
+            libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDONLY)
+        }
+
+        unsafe fn enter(&self, _fd: libc::c_int, to_submit: libc::c_uint, _min_complete: libc::c_uint,
+                        _flags: libc::c_uint, _arg: *const libc::c_void, _argsz: libc::size_t) -> libc::c_long {
+            self.enters.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            if let Some(errno) = self.enter_errors.lock().unwrap().pop() {
+                *libc::__errno_location() = errno;
+                return -1;
+            }
+
+            let (sq_mask, cq_mask) = (*self.sq(2), *self.cq(2));
+            let mut submitted = 0;
+            while submitted < to_submit && *self.sq(0) != *self.sq(1) {
+                let head = *self.sq(0);
+                let idx = *self.sq(MOCK_SQ_WORDS + (head & sq_mask) as usize);
+                let sqe = &*(self.sqes as *const io_uring_sqe).add(idx as usize);
+                let res = if sqe.opcode == IORING_OP_NOP { 0 } else { -libc::EINVAL };
+
+                let tail = *self.cq(1);
+                if tail.wrapping_sub(*self.cq(0)) == cq_mask + 1 {
+                    *self.cq(4) += 1; // overflow
+                } else {
+                    let cqes = self.cq(MOCK_CQ_WORDS) as *mut io_uring_cqe;
+                    *cqes.add((tail & cq_mask) as usize) = io_uring_cqe { user_data: sqe.user_data, res, flags: 0 };
+                    *self.cq(1) = tail.wrapping_add(1);
+                }
+                *self.sq(0) = head.wrapping_add(1);
+                submitted += 1;
+            }
+            submitted as libc::c_long
+        }
+
+        unsafe fn register(&self, _fd: libc::c_int, _opcode: libc::c_uint, _arg: *mut libc::c_void,
+                           _nr_args: libc::c_uint) -> libc::c_long {
+            *libc::__errno_location() = libc::EINVAL;
+            -1
+        }
+
+        unsafe fn mmap(&self, _len: libc::size_t, _fd: libc::c_int, off: libc::off_t, _locked: bool) -> *mut libc::c_void {
+            if self.mmap_fail == Some(off) {
+                *libc::__errno_location() = libc::ENOMEM;
+                return libc::MAP_FAILED;
+            }
+            match off {
+                IORING_OFF_SQ_RING => self.sq_ring as *mut libc::c_void,
+                IORING_OFF_CQ_RING => self.cq_ring as *mut libc::c_void,
+                IORING_OFF_SQES => self.sqes as *mut libc::c_void,
+                _ => libc::MAP_FAILED,
+            }
+        }
+
+        unsafe fn munmap(&self, addr: *mut libc::c_void, _len: libc::size_t) -> libc::c_int {
+            self.munmaps.lock().unwrap().push(addr as usize);
+            0
+        }
+    }
+
+    #[test]
+    fn mock_mmap_failure() {
+        // each failing mapping unmaps the ones before it
+        let cases = [
+            (IORING_OFF_SQ_RING, 0),
+            (IORING_OFF_SQES, 1),
+            (IORING_OFF_CQ_RING, 2),
+        ];
+        for &(off, unmapped) in cases.iter() {
+            let mut mock = MockSyscalls::new(4);
+            mock.mmap_fail = Some(off);
+            let mock = std::sync::Arc::new(mock);
+            match Builder::new(4).syscalls(mock.clone()).build() {
+                Err(IoUringError::Setup(e)) => assert_eq!(e.raw_os_error(), Some(libc::ENOMEM)),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("mmap failure at {:#x} was not detected", off),
+            }
+            let munmaps = mock.munmaps.lock().unwrap().clone();
+            let mapped = [mock.sq_ring, mock.sqes];
+            assert_eq!(munmaps.len(), unmapped, "{:#x}", off);
+            assert!(munmaps.iter().all(|addr| mapped.contains(addr)));
+            assert!(!munmaps.contains(&(libc::MAP_FAILED as usize)));
+            // the ring was dropped, not leaked
+            assert_eq!(std::sync::Arc::strong_count(&mock), 1);
+        }
+    }
+
+    #[test]
+    fn mock_wraparound() {
+        let mock = std::sync::Arc::new(MockSyscalls::new(4));
+        let mut ior = Builder::new(4).syscalls(mock.clone()).build().unwrap();
+        assert!(ior.verify_offsets());
+
+        // start right before the indices wrap around
+        let start = u32::MAX - 5;
+        unsafe { mock.set_indices(start) };
+        ior.sq.sqe_head = std::num::Wrapping(start);
+        ior.sq.sqe_tail = std::num::Wrapping(start);
+
+        let mut data = 0;
+        for _ in 0..8 {
+            for i in 0..3 {
+                let mut sqe = ior.get_sqe().unwrap();
+                sqe.prep_nop();
+                sqe.set_data(data + i);
+            }
+            assert_eq!(ior.submit().unwrap(), 3);
+            for i in 0..3 {
+                let cqe = ior.wait_cqe().unwrap();
+                assert_eq!((cqe.user_data(), cqe.res()), (data + i, 0));
+            }
+            data += 3;
+        }
+        assert_eq!(unsafe { *mock.sq(0) }, start.wrapping_add(24));
+        assert_eq!(unsafe { *mock.cq(0) }, start.wrapping_add(24));
+        assert_eq!(ior.in_flight(), 0);
+        assert!(ior.verify_offsets());
+
+        // errors from enter
+        mock.enter_errors.lock().unwrap().push(libc::EIO);
+        ior.get_sqe().unwrap().prep_nop();
+        assert!(matches!(ior.submit(), Err(IoUringError::Submit(e)) if e.raw_os_error() == Some(libc::EIO)));
+        // the sqe is resubmitted
+        assert_eq!(ior.submit().unwrap(), 1);
+        ior.wait_cqe().unwrap();
+
+        // EBUSY: flush (one more enter), and retry once
+        mock.enter_errors.lock().unwrap().push(libc::EBUSY);
+        let enters = mock.enters.load(std::sync::atomic::Ordering::Relaxed);
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!(ior.submit().unwrap(), 1);
+        assert_eq!(mock.enters.load(std::sync::atomic::Ordering::Relaxed), enters + 3);
+        ior.wait_cqe().unwrap();
+    }
+
+    #[test]
+    fn mock_borrowed_rollback() {
+        let mock = std::sync::Arc::new(MockSyscalls::new(4));
+        let mut ior = Builder::new(4).syscalls(mock.clone()).build().unwrap();
+        let mut buf = [0u8; 16];
+
+        // the read pointing to buf is not left in the SQ after a failed submit
+        mock.enter_errors.lock().unwrap().push(libc::EIO);
+        let err = ior.submit_read_full(0, &mut buf, 0, 1).unwrap_err();
+        assert!(matches!(err, IoUringError::Submit(e) if e.raw_os_error() == Some(libc::EIO)));
+        assert_eq!(ior.sq_ready(), 0);
+        assert_eq!(unsafe { (*mock.sq(0), *mock.sq(1)) }, (0, 0));
+        assert_eq!(ior.submit().unwrap(), 0);
+
+        mock.enter_errors.lock().unwrap().push(libc::EIO);
+        assert_eq!(ior.read_blocking(0, &mut buf, 0).unwrap_err().raw_os_error(), Some(libc::EIO));
+        assert_eq!(ior.sq_ready(), 0);
+        mock.enter_errors.lock().unwrap().push(libc::EIO);
+        assert_eq!(ior.write_blocking(1, &buf, 0).unwrap_err().raw_os_error(), Some(libc::EIO));
+        assert_eq!(ior.sq_ready(), 0);
+        assert_eq!(unsafe { (*mock.sq(0), *mock.sq(1)) }, (0, 0));
+
+        // the ring is still usable
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+        assert_eq!(ior.wait_cqe().unwrap().res(), 0);
+    }
+
+    #[test]
+    fn eventfd_async() {
+        let mut ior = IoUring::init(4).unwrap();
+        let efd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        assert!(efd >= 0);
+        ior.register_eventfd_async(efd).unwrap();
+        let read_efd = || {
+            let mut cnt: u64 = 0;
+            let ret = unsafe { libc::read(efd, &mut cnt as *mut u64 as *mut libc::c_void, 8) };
+            if ret == 8 { cnt } else { 0 }
+        };
+
+        // an inline completion does not signal the eventfd
+        ior.get_sqe().unwrap().prep_nop();
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), 0);
+        assert_eq!(read_efd(), 0);
+
+        // a request that is punted to a worker does
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_nop();
+        unsafe { (*sqe.0).flags |= SqeFlags::ASYNC.bits() };
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), 0);
+        assert!(read_efd() > 0);
+
+        ior.unregister_eventfd().unwrap();
+        unsafe { libc::close(efd) };
+    }
+
+    #[test]
+    fn nodrop_backpressure() {
+        let mut ior = IoUring::init(2).unwrap();
+        assert!(ior.features.contains(FeatureFlags::NODROP));
+        assert_eq!(ior.max_in_flight(), u32::MAX);
+
+        let cq_entries = ior.cq_entries();
+        let submit_nops = |ior: &mut IoUring| -> u32 {
+            let mut n = 0;
+            while n < 2 * cq_entries {
+                match ior.try_get_sqe() {
+                    Some(mut sqe) => sqe.prep_nop(),
+                    None => break,
+                }
+                ior.submit().unwrap();
+                n += 1;
+            }
+            n
+        };
+
+        // with NODROP, more than cq_entries requests can be in flight
+        let n = submit_nops(&mut ior);
+        assert_eq!(n, 2 * cq_entries);
+        let mut reaped = 0;
+        while reaped < n {
+            reaped += ior.for_each_completion(|cqe| assert_eq!(cqe.res(), 0));
+            if reaped < n {
+                ior.wait_only(1).unwrap();
+            }
+        }
+        assert_eq!(ior.in_flight(), 0);
+
+        // without it, in-flight requests are limited to the CQ size
+        ior.features.remove(FeatureFlags::NODROP);
+        assert_eq!(ior.max_in_flight(), cq_entries);
+        assert_eq!(submit_nops(&mut ior), cq_entries);
+        ior.wait_only(cq_entries).unwrap();
+        assert_eq!(ior.for_each_completion(|_| {}), cq_entries);
+    }
+
+    #[test]
+    fn warm() {
+        let minflt = || {
+            let mut ru: libc::rusage = unsafe { mem::zeroed() };
+            assert_eq!(unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut ru) }, 0);
+            ru.ru_minflt
+        };
+        // faults taken by touching every page of the ring (which is what warm() does)
+        let touch_faults = |ior: &mut IoUring| {
+            let before = minflt();
+            ior.warm();
+            minflt() - before
+        };
+
+        // NB: fault in the code and the stack used below, before measuring
+        touch_faults(&mut IoUring::init(4).unwrap());
+
+        let mut cold = IoUring::init(4096).unwrap();
+        let cold_faults = touch_faults(&mut cold);
+
+        // NB: page faults can also come from unrelated activity (e.g., THP collapsing, or the
+        // allocator), so allow for a few
+        let mut warmed = IoUring::init(4096).unwrap();
+        warmed.warm();
+        let warm_faults = touch_faults(&mut warmed);
+        assert!(warm_faults <= 4, "{} faults after warm() ({} for a cold ring)", warm_faults, cold_faults);
+    }
+
+    #[test]
+    fn sqe_reuse_zeroed() {
+        let mut ior = IoUring::init(1).unwrap();
+        assert_eq!(ior.sq_entries(), 1);
+        let fd = libc::STDIN_FILENO;
+
+        // use the slot for a READ_FIXED, with no buffers registered
+        let mut buf = [0u8; 16];
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_read_fixed(fd, buf.as_mut_ptr() as *mut libc::c_void, 16, 0, 5);
+        sqe.set_data(1);
+        let sqe_p = sqe.0;
+        assert_eq!(unsafe { (*sqe_p).idx.buf_index }, 5);
+        ior.submit_and_wait(1).unwrap();
+        assert!(ior.wait_cqe().unwrap().res() < 0);
+
+        // the same slot is handed out zeroed
+        let mut sqe = ior.get_sqe().unwrap();
+        assert_eq!(sqe.0, sqe_p);
+        let bytes = unsafe { std::slice::from_raw_parts(sqe_p as *const u8, mem::size_of::<io_uring_sqe>()) };
+        assert!(bytes.iter().all(|b| *b == 0));
+        sqe.prep_nop();
+        sqe.set_data(2);
+        ior.submit_and_wait(1).unwrap();
+        let cqe = ior.wait_cqe().unwrap();
+        assert_eq!((cqe.user_data(), cqe.res()), (2, 0));
+    }
+
+    // the actual system calls, except that IORING_REGISTER_SYNC_CANCEL fails as on pre-6.0 kernels
+    struct NoSyncCancel;
+
+    unsafe impl Syscalls for NoSyncCancel {
+        unsafe fn setup(&self, entries: libc::c_uint, params: *mut io_uring_params) -> libc::c_int {
+            LibcSyscalls.setup(entries, params)
+        }
+
+        unsafe fn enter(&self, fd: libc::c_int, to_submit: libc::c_uint, min_complete: libc::c_uint,
+                        flags: libc::c_uint, arg: *const libc::c_void, argsz: libc::size_t) -> libc::c_long {
+            LibcSyscalls.enter(fd, to_submit, min_complete, flags, arg, argsz)
+        }
+
+        unsafe fn register(&self, fd: libc::c_int, opcode: libc::c_uint, arg: *mut libc::c_void,
+                           nr_args: libc::c_uint) -> libc::c_long {
+            if opcode == IORING_REGISTER_SYNC_CANCEL {
+                *libc::__errno_location() = libc::EINVAL;
+                return -1
+            }
+            LibcSyscalls.register(fd, opcode, arg, nr_args)
+        }
+
+        unsafe fn mmap(&self, len: libc::size_t, fd: libc::c_int, off: libc::off_t, locked: bool) -> *mut libc::c_void {
+            LibcSyscalls.mmap(len, fd, off, locked)
+        }
+
+        unsafe fn munmap(&self, addr: *mut libc::c_void, len: libc::size_t) -> libc::c_int {
+            LibcSyscalls.munmap(addr, len)
+        }
+    }
+
+    #[test]
+    fn shutdown_multishot() {
+        use std::os::unix::io::AsRawFd;
+
+        let builders = [Builder::new(8), Builder::new(8).syscalls(std::sync::Arc::new(NoSyncCancel))];
+        for builder in builders {
+            let mut ior = builder.build().unwrap();
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+            // an armed multishot accept, with one (unconsumed) cqe posted already
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_multishot_accept(listener.as_raw_fd(), libc::SOCK_CLOEXEC as u32);
+            sqe.set_data(1);
+            // a request that never posts a cqe, but is accounted for in in_flight()
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_nop();
+            sqe.set_data(2);
+            unsafe { (*sqe.0).flags |= SqeFlags::CQE_SKIP_SUCCESS.bits() };
+            assert_eq!(ior.submit().unwrap(), 2);
+
+            let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            ior.wait_only(1).unwrap();
+            let cqe = ior.cq_iter().next().unwrap();
+            assert_eq!(cqe.user_data(), 1);
+            assert!(cqe.flags() & IORING_CQE_F_MORE != 0);
+            unsafe { libc::close(cqe.res()) };
+            assert_eq!(ior.in_flight(), 2);
+
+            ior.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    fn register_ops_nr() {
+        let ior = IoUring::init(4).unwrap();
+        assert!(ior.register_supported(IORING_REGISTER_SYNC_CANCEL));
+        // the probe is cached (for all rings), and covers (at least) the opcodes older kernels know
+        let nr = *REGISTER_OPS.get().unwrap();
+        assert_eq!(nr, super::register_ops_nr());
+        assert!(nr as usize > REGISTER_OP_VERSIONS.len());
+        assert!(!ior.register_supported(nr));
+    }
+
+    #[test]
+    fn wait_cqe_timeout_sqe() {
+        use std::time::{Duration, Instant};
+
+        let mut ior = IoUring::init(4).unwrap();
+        // NB: pretend to be a pre-5.11 kernel
+        ior.features.remove(FeatureFlags::EXT_ARG);
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (rfd, wfd) = (fds[0], fds[1]);
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_poll_add(rfd, libc::POLLIN as u16);
+        sqe.set_data(7);
+        assert_eq!(ior.submit().unwrap(), 1);
+
+        // nothing happens
+        let start = Instant::now();
+        assert!(ior.wait_cqe_timeout(Some(Duration::from_millis(20))).unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(ior.cq_ready(), 0);
+
+        // completion triggered from another thread, followed by other cqes
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            assert_eq!(unsafe { libc::write(wfd, b"x".as_ptr() as _, 1) }, 1);
+            wfd
+        });
+        let start = Instant::now();
+        let cqe = ior.wait_cqe_timeout(Some(Duration::from_secs(10))).unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!((cqe.user_data(), cqe.res()), (7, libc::POLLIN as i32));
+        assert_eq!(ior.in_flight(), 0);
+
+        // queued sqes are submitted first, and their cqes returned right away
+        ior.get_sqe().unwrap().prep_nop();
+        let start = Instant::now();
+        assert_eq!(ior.wait_cqe_timeout(Some(Duration::from_secs(10))).unwrap().unwrap().user_data(), 0);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // two cqes posted at once: the timeout's cqe is removed from among them
+        let wfd = writer.join().unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(unsafe { libc::read(rfd, buf.as_mut_ptr() as _, 1) }, 1);
+        for i in 1..3 {
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_poll_add(rfd, libc::POLLIN as u16);
+            sqe.set_data(i);
+        }
+        ior.submit().unwrap();
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            assert_eq!(unsafe { libc::write(wfd, b"x".as_ptr() as _, 1) }, 1);
+            wfd
+        });
+        let mut data = vec![];
+        for _ in 0..2 {
+            data.push(ior.wait_cqe_timeout(Some(Duration::from_secs(10))).unwrap().unwrap().user_data());
+        }
+        data.sort();
+        assert_eq!(data, vec![1, 2]);
+        assert!(ior.wait_cqe_timeout(Some(Duration::from_millis(1))).unwrap().is_none());
+        assert_eq!(ior.cq_ready(), 0);
+
+        let wfd = writer.join().unwrap();
+        unsafe {
+            libc::close(rfd);
+            libc::close(wfd);
+        }
+    }
+
+    #[test]
+    fn drop_owned() {
+        let builders = [(Builder::new(4), true), (Builder::new(4).syscalls(std::sync::Arc::new(NoSyncCancel)), false)];
+        for (builder, freed) in builders {
+            let mut ior = builder.build().unwrap();
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            let (rfd, wfd) = (fds[0], fds[1]);
+
+            // a read that is pending when the ring is dropped
+            ior.submit_readv_owned(rfd, vec![vec![0; 4]], 0, 1).unwrap();
+            assert!(ior.owns_iovecs(1));
+            assert_eq!(ior.release_owned(), freed);
+            assert!(!ior.owns_iovecs(1));
+            if freed {
+                // the read is gone before the buffer is freed, so it does not take the data
+                assert_eq!(unsafe { libc::write(wfd, b"late".as_ptr() as _, 4) }, 4);
+                let mut buf = [0u8; 4];
+                assert_eq!(unsafe { libc::read(rfd, buf.as_mut_ptr() as _, 4) }, 4);
+                assert_eq!(&buf, b"late");
+            }
+            drop(ior);
+            unsafe {
+                libc::close(rfd);
+                libc::close(wfd);
+            }
+        }
+    }
 }
@@ -4,6 +4,53 @@ pub mod io_uring;
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::io::AsRawFd;
+
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    /// Allocator counting the allocations of each thread (see allocations())
+    struct CountingAlloc;
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            ALLOCATIONS.with(|n| n.set(n.get() + 1));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOC: CountingAlloc = CountingAlloc;
+
+    /// Number of allocations of the current thread so far
+    fn allocations() -> usize {
+        ALLOCATIONS.with(|n| n.get())
+    }
+
+    /// Create a pipe, returning (read fd, write fd)
+    fn pipe() -> (libc::c_int, libc::c_int) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        (fds[0], fds[1])
+    }
+
+    /// Create a new (empty) file for a test
+    fn tmpfile(name: &str) -> (std::path::PathBuf, std::fs::File) {
+        let path = std::env::temp_dir().join(format!("iouring-{}-{}", std::process::id(), name));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        (path, file)
+    }
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
@@ -12,7 +59,2257 @@ mod tests {
     #[test]
     fn hello() {
         let res = crate::io_uring::IoUring::init(4);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn entries() {
+        let ior = crate::io_uring::IoUring::init(5).unwrap();
+        assert_eq!(ior.sq_entries(), 8);
+        assert_eq!(ior.cq_entries(), 16);
+
+        let ior = crate::io_uring::IoUring::init(100).unwrap();
+        assert_eq!(ior.sq_entries(), 128);
+        assert_eq!(ior.cq_entries(), 256);
+
+        for n in [0, 32768 + 1] {
+            match crate::io_uring::IoUring::init(n) {
+                Err(crate::io_uring::IoUringError::Setup(e)) => assert_eq!(e.raw_os_error(), Some(libc::EINVAL)),
+                r => panic!("unexpected result for {}: {:?}", n, r.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn for_each_completion() {
+        let mut ior = crate::io_uring::IoUring::init(128).unwrap();
+        for i in 0..100 {
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_nop();
+            sqe.set_data(i);
+        }
+        assert_eq!(ior.submit_and_wait(100).unwrap(), 100);
+
+        let mut seen = vec![];
+        let nr = ior.for_each_completion(|cqe| seen.push(cqe.user_data()));
+        assert_eq!(nr, 100);
+        assert_eq!(seen, (0..100).collect::<Vec<u64>>());
+
+        // everything was consumed
+        assert_eq!(ior.for_each_completion(|_| ()), 0);
+    }
+
+    #[test]
+    fn drain_up_to() {
+        let mut ior = crate::io_uring::IoUring::init(64).unwrap();
+        for i in 0..50 {
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_nop();
+            sqe.set_data(i);
+        }
+        assert_eq!(ior.submit_and_wait(50).unwrap(), 50);
+
+        for i in 0..5 {
+            let cqes = ior.drain_up_to(10);
+            let data: Vec<u64> = cqes.iter().map(|cqe| cqe.user_data()).collect();
+            assert_eq!(data, (i * 10..(i + 1) * 10).collect::<Vec<u64>>());
+        }
+        assert!(ior.drain_up_to(10).is_empty());
+    }
+
+    #[test]
+    fn is_readable() {
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        assert!(!ior.is_readable().unwrap());
+
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+        // completion posted, but not reaped yet
+        assert!(ior.is_readable().unwrap());
+
+        assert_eq!(ior.for_each_completion(|_| ()), 1);
+        assert!(!ior.is_readable().unwrap());
+    }
+
+    #[test]
+    fn write_current_pos() {
+        use crate::io_uring::OFF_CURRENT_POS;
+
+        let (path, file) = tmpfile("write_current_pos");
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        for buf in [&b"hello "[..], &b"world"[..]].iter() {
+            let iov = [std::io::IoSlice::new(buf)];
+            ior.get_sqe().unwrap().prep_write_slice(file.as_raw_fd(), &iov, OFF_CURRENT_POS);
+            assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+            assert_eq!(ior.for_each_completion(|cqe| assert_eq!(cqe.res(), buf.len() as i32)), 1);
+
+            ior.get_sqe().unwrap().prep_write(file.as_raw_fd(), buf.as_ptr() as _, buf.len() as u32, OFF_CURRENT_POS);
+            assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+            assert_eq!(ior.for_each_completion(|cqe| assert_eq!(cqe.res(), buf.len() as i32)), 1);
+        }
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello hello worldworld");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cqe_fields() {
+        let (path, file) = tmpfile("cqe_fields");
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let buf = b"0123456789";
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_write(file.as_raw_fd(), buf.as_ptr() as _, buf.len() as u32, 0);
+        sqe.set_data(0xdead_beef_cafe);
+        assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+
+        let cqes: Vec<_> = ior.cq_iter().collect();
+        assert_eq!(cqes.len(), 1);
+        assert_eq!(cqes[0].user_data(), 0xdead_beef_cafe);
+        assert_eq!(cqes[0].res(), buf.len() as i32);
+        assert_eq!(cqes[0].flags(), 0);
+        ior.cq_advance(1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[allow(clippy::zombie_processes)] // the child is reaped via the ring
+    fn waitid() {
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        if ior.check_op(crate::io_uring::IORING_OP_WAITID).is_err() {
+            return;
+        }
+
+        let child = std::process::Command::new("sh").args(["-c", "exit 7"]).spawn().unwrap();
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        ior.get_sqe().unwrap().prep_waitid(libc::P_PID, child.id(), &mut info, libc::WEXITED);
+        assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+        assert_eq!(ior.for_each_completion(|cqe| assert_eq!(cqe.res(), 0)), 1);
+        unsafe {
+            assert_eq!(info.si_pid() as u32, child.id());
+            assert_eq!(info.si_status(), 7);
+        }
+    }
+
+    #[test]
+    fn fixed_fd_install() {
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        if ior.check_op(crate::io_uring::IORING_OP_FIXED_FD_INSTALL).is_err() {
+            return;
+        }
+
+        let (rfd, wfd) = pipe();
+        assert_eq!(unsafe { libc::write(wfd, b"hello".as_ptr() as _, 5) }, 5);
+        ior.register_files(&[rfd]).unwrap();
+        unsafe { libc::close(rfd) };
+
+        ior.get_sqe().unwrap().prep_fixed_fd_install(0, 0);
+        assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+        let mut fd = -1;
+        assert_eq!(ior.for_each_completion(|cqe| fd = cqe.res()), 1);
+        assert!(fd >= 0);
+        assert_ne!(unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC, 0);
+
+        let mut buf = [0u8; 5];
+        assert_eq!(unsafe { libc::read(fd, buf.as_mut_ptr() as _, 5) }, 5);
+        assert_eq!(&buf, b"hello");
+        unsafe {
+            libc::close(fd);
+            libc::close(wfd);
+        }
+    }
+
+    #[test]
+    fn wait_cqe_with() {
+        use crate::io_uring::WaitStrategy;
+
+        let strategies = [
+            WaitStrategy::Block,
+            WaitStrategy::SpinThenBlock { spin_iters: 10 },
+            WaitStrategy::Spin,
+        ];
+        // NB: Spin must not hang on a ring whose task work only runs in the kernel
+        for &defer in &[false, true] {
+            let mut ior = crate::io_uring::Builder::new(4).defer_taskrun(defer).build().unwrap();
+            for (i, strategy) in strategies.iter().enumerate() {
+                // the read completes once the other thread writes to the pipe, during the wait
+                let (rfd, wfd) = pipe();
+                let mut buf = [0u8; 5];
+                let mut sqe = ior.get_sqe().unwrap();
+                sqe.prep_read(rfd, buf.as_mut_ptr() as _, buf.len() as u32, 0);
+                sqe.set_data(i as u64);
+                assert_eq!(ior.submit().unwrap(), 1);
+
+                let writer = std::thread::spawn(move || {
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    assert_eq!(unsafe { libc::write(wfd, b"hello".as_ptr() as _, 5) }, 5);
+                });
+                let cqe = ior.wait_cqe_with(*strategy).unwrap();
+                assert_eq!(cqe.user_data(), i as u64);
+                assert_eq!(cqe.res(), 5);
+                assert_eq!(&buf, b"hello");
+
+                writer.join().unwrap();
+                unsafe {
+                    libc::close(rfd);
+                    libc::close(wfd);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn register_buffer_pool() {
+        const BUF_SIZE: usize = 4096;
+        const NBUFS: usize = 64;
+
+        let (path, file) = tmpfile("register_buffer_pool");
+        let mut ior = crate::io_uring::IoUring::init(8).unwrap();
+        let slab = unsafe {
+            let prot = libc::PROT_READ | libc::PROT_WRITE;
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+            let ptr = libc::mmap(std::ptr::null_mut(), BUF_SIZE * NBUFS, prot, flags, -1, 0);
+            assert_ne!(ptr, libc::MAP_FAILED);
+            ptr as *mut u8
+        };
+        let bufs = ior.register_buffer_pool(slab, BUF_SIZE, NBUFS).unwrap();
+        assert_eq!(bufs, 0..NBUFS as u16);
+        let buf = |idx: u16| unsafe { slab.add(idx as usize * BUF_SIZE) };
+
+        // write from a few buffers, and read the data back into others
+        let idxs = [0u16, 1, 31, 63];
+        for (i, idx) in idxs.iter().enumerate() {
+            unsafe { std::ptr::write_bytes(buf(*idx), b'a' + i as u8, BUF_SIZE) };
+            let off = (i * BUF_SIZE) as u64;
+            ior.get_sqe().unwrap().prep_write_fixed(file.as_raw_fd(), buf(*idx) as _, BUF_SIZE as u32, off, *idx);
+        }
+        assert_eq!(ior.submit_and_wait(4).unwrap(), 4);
+        assert_eq!(ior.for_each_completion(|cqe| assert_eq!(cqe.res(), BUF_SIZE as i32)), 4);
+
+        let ridxs = [2u16, 10, 32, 62];
+        for (i, ridx) in ridxs.iter().copied().enumerate() {
+            let off = (i * BUF_SIZE) as u64;
+            ior.get_sqe().unwrap().prep_read_fixed(file.as_raw_fd(), buf(ridx) as _, BUF_SIZE as u32, off, ridx);
+            assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+            assert_eq!(ior.wait_cqe().unwrap().res(), BUF_SIZE as i32);
+            let data = unsafe { std::slice::from_raw_parts(buf(ridx), BUF_SIZE) };
+            assert!(data.iter().all(|b| *b == b'a' + i as u8));
+        }
+
+        ior.unregister_buffers().unwrap();
+        unsafe { libc::munmap(slab as _, BUF_SIZE * NBUFS) };
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sync_cancel() {
+        use crate::io_uring::{CancelCriteria, IoUringError};
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (rfd, wfd) = pipe();
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_poll_add(rfd, libc::POLLIN as u16);
+        sqe.set_data(42);
+        assert_eq!(ior.submit().unwrap(), 1);
+        assert_eq!(ior.cq_ready(), 0);
+
+        let timeout = std::time::Duration::from_secs(1);
+        match ior.sync_cancel(CancelCriteria::Fd(rfd), Some(timeout)) {
+            Ok(nr) => assert_eq!(nr, 1),
+            // not supported (before 6.0)
+            Err(IoUringError::Register(e)) if e.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(e) => panic!("{}", e),
+        }
+        let cqe = ior.wait_cqe().unwrap();
+        assert_eq!(cqe.user_data(), 42);
+        assert_eq!(cqe.res(), -libc::ECANCELED);
+
+        // nothing left to cancel
+        assert_eq!(ior.sync_cancel(CancelCriteria::All, None).unwrap(), 0);
+        unsafe {
+            libc::close(rfd);
+            libc::close(wfd);
+        }
+    }
+
+    #[test]
+    fn interpret() {
+        use crate::io_uring::{CqeResult, Errno, IORING_OP_ACCEPT, IORING_OP_POLL_ADD, IORING_OP_READ};
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+
+        // accept
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _conn = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_accept(listener.as_raw_fd(), std::ptr::null_mut(), std::ptr::null_mut(), 0);
+        ior.submit_and_wait(1).unwrap();
+        let fd = match ior.wait_cqe().unwrap().interpret(IORING_OP_ACCEPT) {
+            CqeResult::Fd(fd) => fd,
+            r => panic!("unexpected result: {:?}", r),
+        };
+        assert!(fd >= 0);
+        unsafe { libc::close(fd) };
+
+        // poll
+        let (rfd, wfd) = pipe();
+        assert_eq!(unsafe { libc::write(wfd, b"hello".as_ptr() as *const libc::c_void, 5) }, 5);
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_poll_add(rfd, libc::POLLIN as u16);
+        ior.submit_and_wait(1).unwrap();
+        let cqe = ior.wait_cqe().unwrap();
+        assert_eq!(cqe.interpret(IORING_OP_POLL_ADD), CqeResult::Events(libc::POLLIN as u32));
+
+        // read
+        let mut buf = [0u8; 16];
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_read(rfd, buf.as_mut_ptr() as *mut libc::c_void, buf.len() as u32, 0);
+        ior.submit_and_wait(1).unwrap();
+        let cqe = ior.wait_cqe().unwrap();
+        assert_eq!(cqe.interpret(IORING_OP_READ), CqeResult::Bytes(5));
+        assert_eq!(&buf[..5], b"hello");
+
+        // error
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_read(-1, buf.as_mut_ptr() as *mut libc::c_void, buf.len() as u32, 0);
+        ior.submit_and_wait(1).unwrap();
+        let cqe = ior.wait_cqe().unwrap();
+        assert_eq!(cqe.interpret(IORING_OP_READ), CqeResult::Error(Errno(libc::EBADF)));
+
+        unsafe {
+            libc::close(rfd);
+            libc::close(wfd);
+        }
+    }
+
+    #[test]
+    fn submit_and_wait_overcommit() {
+        use crate::io_uring::IoUringError;
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_nop();
+        let wait_nr = ior.cq_entries() + 1;
+        match ior.submit_and_wait(wait_nr) {
+            Err(IoUringError::InvalidInput(_)) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        // the nop is still queued
+        assert_eq!(ior.sq_ready(), 1);
+        assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+        assert_eq!(ior.wait_cqe().unwrap().res(), 0);
+    }
+
+    #[test]
+    fn peek_batch() {
+        use crate::io_uring::io_uring_cqe;
+
+        let mut ior = crate::io_uring::IoUring::init(32).unwrap();
+        for i in 0..20 {
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_nop();
+            sqe.set_data(i);
+        }
+        assert_eq!(ior.submit_and_wait(20).unwrap(), 20);
+
+        let mut cqes = [io_uring_cqe::default(); 8];
+        let mut data = vec![];
+        loop {
+            let nr = ior.peek_batch(&mut cqes);
+            if nr == 0 {
+                break;
+            }
+            assert_eq!(nr as usize, std::cmp::min(8, 20 - data.len()));
+            // peeking does not consume
+            assert_eq!(ior.peek_batch(&mut cqes), nr);
+            data.extend(cqes[..nr as usize].iter().map(|cqe| cqe.user_data()));
+            ior.cq_advance(nr);
+        }
+        assert_eq!(data, (0..20).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn submit_read_full() {
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (rfd, wfd) = pipe();
+        assert_eq!(unsafe { libc::fcntl(rfd, libc::F_SETPIPE_SZ, 4096) }, 4096);
+
+        // the writer writes in chunks, so that reads are short
+        let writer = std::thread::spawn(move || {
+            for i in 0..3u8 {
+                let chunk = [i; 4096];
+                let ret = unsafe { libc::write(wfd, chunk.as_ptr() as *const libc::c_void, chunk.len()) };
+                assert_eq!(ret, 4096);
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            unsafe { libc::close(wfd) };
+        });
+
+        // read until EOF, since the buffer is larger than what is written
+        let mut buf = vec![0xffu8; 4 * 4096];
+        let cqe = ior.submit_read_full(rfd, &mut buf, 0, 42).unwrap();
+        writer.join().unwrap();
+        assert_eq!(cqe.user_data(), 42);
+        assert_eq!(cqe.res(), 3 * 4096);
+        for i in 0..3 {
+            assert!(buf[i * 4096..(i + 1) * 4096].iter().all(|b| *b == i as u8));
+        }
+        assert!(buf[3 * 4096..].iter().all(|b| *b == 0xff));
+        assert_eq!(ior.cq_ready(), 0);
+
+        // queued sqes would be submitted along with the read
+        ior.get_sqe().unwrap().prep_nop();
+        assert!(matches!(ior.submit_read_full(rfd, &mut buf, 0, 42), Err(crate::io_uring::IoUringError::NotIdle)));
+        unsafe { libc::close(rfd) };
+    }
+
+    #[test]
+    fn dump() {
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let d = ior.dump();
+        assert!(d.fd >= 0);
+        assert_eq!((d.sq_head, d.sq_tail, d.sqe_head, d.sqe_tail), (0, 0, 0, 0));
+        assert_eq!((d.cq_head, d.cq_tail, d.cq_overflow), (0, 0, 0));
+        assert_eq!(d.sq_entries, ior.sq_entries());
+        assert_eq!(d.cq_entries, ior.cq_entries());
+        assert_eq!(d.sq_mask, d.sq_entries - 1);
+        assert_eq!(d.cq_mask, d.cq_entries - 1);
+
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!(ior.dump().sqe_tail, 1);
+        ior.submit_and_wait(1).unwrap();
+        let d = ior.dump();
+        assert_eq!((d.sq_tail, d.cq_tail, d.cq_head), (1, 1, 0));
+        assert!(d.to_string().contains("CQ "));
+    }
+
+    #[test]
+    fn wait_only() {
+        let mut ior = crate::io_uring::IoUring::init(8).unwrap();
+        let (rfd, wfd) = pipe();
+        let mut buf = [0u8; 8];
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_read(rfd, buf.as_mut_ptr() as *mut libc::c_void, buf.len() as u32, 0);
+        for _ in 0..3 {
+            ior.get_sqe().unwrap().prep_nop();
+        }
+        assert_eq!(ior.submit().unwrap(), 4);
+
+        // the nops complete, but the read is pending until the write below
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            assert_eq!(unsafe { libc::write(wfd, b"x".as_ptr() as *const libc::c_void, 1) }, 1);
+            wfd
+        });
+        assert_eq!(ior.wait_only(4).unwrap(), 0);
+        assert_eq!(ior.cq_ready(), 4);
+        assert_eq!(ior.for_each_completion(|_| ()), 4);
+        assert_eq!(buf[0], b'x');
+
+        let wfd = writer.join().unwrap();
+        unsafe {
+            libc::close(rfd);
+            libc::close(wfd);
+        }
+    }
+
+    #[test]
+    fn numa_node() {
+        use crate::io_uring::{Builder, IoUringError};
+
+        // requires a kernel with NUMA support
+        if !std::path::Path::new("/sys/devices/system/node/node0").exists() {
+            return;
+        }
+
+        let mut ior = Builder::new(4).numa_node(0).build().unwrap();
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+        assert_eq!(ior.wait_cqe().unwrap().res(), 0);
+
+        // the policy of the thread is restored
+        let mut mode: libc::c_int = -1;
+        let null = std::ptr::null_mut::<libc::c_ulong>();
+        assert_eq!(unsafe { libc::syscall(libc::SYS_get_mempolicy, &mut mode, null, 0, 0, 0) }, 0);
+        assert_eq!(mode, 0); // MPOL_DEFAULT
+
+        match Builder::new(4).numa_node(1000).build() {
+            Err(IoUringError::Setup(_)) => (),
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn fsync_barrier() {
+        let mut ior = crate::io_uring::IoUring::init(16).unwrap();
+        let (path, file) = tmpfile("fsync-barrier");
+        let buf = vec![0xaau8; 64 * 1024];
+        for i in 0..8 {
+            let mut sqe = ior.get_sqe().unwrap();
+            let off = i * buf.len() as u64;
+            sqe.prep_write(file.as_raw_fd(), buf.as_ptr() as *const libc::c_void, buf.len() as u32, off);
+            sqe.set_data(i);
+        }
+        ior.fsync_barrier(file.as_raw_fd(), true, 100).unwrap();
+        assert_eq!(ior.submit_and_wait(9).unwrap(), 9);
+
+        let mut data = vec![];
+        ior.for_each_completion(|cqe| {
+            assert!(cqe.res() >= 0);
+            data.push(cqe.user_data());
+        });
+        assert_eq!(data.len(), 9);
+        // the fsync completes after all the writes
+        assert_eq!(data[8], 100);
+        assert_eq!(file.metadata().unwrap().len(), 8 * 64 * 1024);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn sqpoll_available() {
+        let ior = crate::io_uring::IoUring::init(4).unwrap();
+        let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::uname(&mut uts) }, 0);
+        let release = unsafe { std::ffi::CStr::from_ptr(uts.release.as_ptr()) }.to_str().unwrap();
+        let mut ver = release.split(|c: char| !c.is_ascii_digit()).map(|x| x.parse::<u32>().unwrap());
+        let (major, minor) = (ver.next().unwrap(), ver.next().unwrap());
+        assert_eq!(ior.sqpoll_available(), (major, minor) >= (5, 11));
+    }
+
+    #[test]
+    fn poll_update() {
+        use crate::io_uring::{IORING_POLL_UPDATE_EVENTS, IORING_POLL_UPDATE_USER_DATA};
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (rfd, wfd) = pipe();
+
+        // the write end of the pipe never becomes readable
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_poll_add(wfd, libc::POLLIN as u16);
+        sqe.set_data(1);
+        assert_eq!(ior.submit().unwrap(), 1);
+        assert_eq!(ior.cq_ready(), 0);
+
+        // but it is writable
+        let mut sqe = ior.get_sqe().unwrap();
+        let mask = (libc::POLLIN | libc::POLLOUT) as u16;
+        sqe.prep_poll_update(1, 2, mask, IORING_POLL_UPDATE_EVENTS | IORING_POLL_UPDATE_USER_DATA);
+        sqe.set_data(3);
+        assert_eq!(ior.submit().unwrap(), 1);
+        ior.wait_only(2).unwrap();
+
+        let mut res = vec![];
+        ior.for_each_completion(|cqe| res.push((cqe.user_data(), cqe.res())));
+        res.sort_unstable();
+        assert_eq!(res, vec![(2, libc::POLLOUT as i32), (3, 0)]);
+        unsafe {
+            libc::close(rfd);
+            libc::close(wfd);
+        }
+    }
+
+    #[test]
+    fn close_direct() {
+        use crate::io_uring::IORING_FILE_INDEX_ALLOC;
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        ior.register_files(&[-1]).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _conns: Vec<_> = (0..3).map(|_| std::net::TcpStream::connect(addr).unwrap()).collect();
+
+        let accept_direct = |ior: &mut crate::io_uring::IoUring| {
+            let mut sqe = ior.get_sqe().unwrap();
+            let null = std::ptr::null_mut();
+            sqe.prep_accept_direct(listener.as_raw_fd(), null, null as _, 0, IORING_FILE_INDEX_ALLOC);
+            ior.submit_and_wait(1).unwrap();
+            ior.wait_cqe().unwrap().res()
+        };
+
+        assert_eq!(accept_direct(&mut ior), 0);
+        // the table is full
+        assert_eq!(accept_direct(&mut ior), -libc::ENFILE);
+
+        ior.get_sqe().unwrap().prep_close_direct(0);
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), 0);
+
+        // the slot is reused
+        assert_eq!(accept_direct(&mut ior), 0);
+    }
+
+    #[test]
+    fn next_cqe() {
+        let mut ior = crate::io_uring::IoUring::init(8).unwrap();
+        for i in 0..5 {
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_nop();
+            sqe.set_data(i);
+        }
+        assert_eq!(ior.submit_and_wait(5).unwrap(), 5);
+
+        for i in 0..5 {
+            let head = ior.dump().cq_head;
+            {
+                let cqe = ior.next_cqe().unwrap();
+                assert_eq!(cqe.user_data(), i);
+                assert_eq!(cqe.res(), 0);
+            }
+            assert_eq!(ior.dump().cq_head, head + 1);
+        }
+        assert!(ior.next_cqe().is_none());
+        assert_eq!(ior.dump().cq_head, 5);
+    }
+
+    #[test]
+    #[cfg(feature = "nix")]
+    fn nix_openat() {
+        use nix::fcntl::OFlag;
+        use nix::sys::stat::Mode;
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (path, mut file) = tmpfile("nix-openat");
+        std::io::Write::write_all(&mut file, b"hello").unwrap();
+        let cpath = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_openat_nix(libc::AT_FDCWD, &cpath, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty());
+        ior.submit_and_wait(1).unwrap();
+        let fd = ior.wait_cqe().unwrap().res();
+        assert!(fd >= 0);
+
+        let mut buf = [0u8; 5];
+        assert_eq!(unsafe { libc::read(fd, buf.as_mut_ptr() as _, 5) }, 5);
+        assert_eq!(&buf, b"hello");
+        unsafe { libc::close(fd) };
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "nix")]
+    fn nix_connect() {
+        use nix::sys::socket::{socket, AddressFamily, SockFlag, SockType, SockaddrIn};
+        use std::os::fd::AsRawFd;
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(a) => SockaddrIn::from(a),
+            _ => unreachable!(),
+        };
+        let sock = socket(AddressFamily::Inet, SockType::Stream, SockFlag::SOCK_CLOEXEC, None).unwrap();
+
+        ior.get_sqe().unwrap().prep_connect_nix(sock.as_raw_fd(), &addr);
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), 0);
+        assert!(listener.accept().is_ok());
+    }
+
+    #[test]
+    fn reset() {
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let cycle = |ior: &mut crate::io_uring::IoUring| {
+            for i in 0..4 {
+                let mut sqe = ior.get_sqe().unwrap();
+                sqe.prep_nop();
+                sqe.set_data(i);
+            }
+            assert_eq!(ior.submit_and_wait(4).unwrap(), 4);
+            let cqes = ior.drain_up_to(4);
+            assert_eq!(cqes.iter().map(|c| c.user_data()).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+        };
+        cycle(&mut ior);
+
+        // leave the ring dirty: unreaped cqes, and unsubmitted sqes
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+        ior.get_sqe().unwrap().prep_nop();
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!((ior.sq_ready(), ior.cq_ready()), (2, 1));
+
+        ior.reset();
+        assert_eq!((ior.sq_ready(), ior.cq_ready()), (0, 0));
+        assert_eq!(ior.sq_space_left(), 4);
+        let d = ior.dump();
+        assert_eq!((d.sq_head, d.sq_tail, d.sqe_head, d.sqe_tail), (5, 5, 5, 5));
+        assert_eq!(d.cq_head, d.cq_tail);
+
+        cycle(&mut ior);
+        assert_eq!(ior.submit().unwrap(), 0);
+    }
+
+    #[test]
+    fn prime_workers() {
+        let iou_workers = || {
+            std::fs::read_dir("/proc/self/task").unwrap().filter(|t| {
+                let comm = t.as_ref().unwrap().path().join("comm");
+                std::fs::read_to_string(comm).map(|c| c.starts_with("iou-wrk")).unwrap_or(false)
+            }).count()
+        };
+
+        for defer in [false, true] {
+            let mut ior = crate::io_uring::Builder::new(4).defer_taskrun(defer).build().unwrap();
+            ior.prime_workers().unwrap();
+            assert!(iou_workers() > 0);
+            assert_eq!((ior.sq_ready(), ior.cq_ready()), (0, 0));
+
+            // a blocking operation
+            let f = std::fs::File::open("/dev/zero").unwrap();
+            let mut buf = [1u8; 64];
+            ior.get_sqe().unwrap().prep_read(f.as_raw_fd(), buf.as_mut_ptr() as _, 64, 0);
+            ior.submit_and_wait(1).unwrap();
+            assert_eq!(ior.wait_cqe().unwrap().res(), 64);
+            assert_eq!(buf, [0u8; 64]);
+        }
+    }
+
+    #[test]
+    fn in_flight() {
+        let mut ior = crate::io_uring::IoUring::init(8).unwrap();
+        assert_eq!(ior.in_flight(), 0);
+        for _ in 0..5 {
+            ior.get_sqe().unwrap().prep_nop();
+        }
+        // not submitted yet
+        assert_eq!(ior.in_flight(), 0);
+        assert_eq!(ior.submit_and_wait(5).unwrap(), 5);
+        assert_eq!(ior.in_flight(), 5);
+
+        assert_eq!(ior.drain_up_to(2).len(), 2);
+        assert_eq!(ior.in_flight(), 3);
+        ior.wait_cqe().unwrap();
+        assert_eq!(ior.in_flight(), 2);
+        assert_eq!(ior.for_each_completion(|_| ()), 2);
+        assert_eq!(ior.in_flight(), 0);
+    }
+
+    #[test]
+    fn kick_and_pump() {
+        let mut ior = crate::io_uring::IoUring::init(8).unwrap();
+        let mut done = vec![];
+        let mut next = 0;
+        for burst in 1..=8 {
+            for _ in 0..burst {
+                let mut sqe = ior.get_sqe().unwrap();
+                sqe.prep_nop();
+                sqe.set_data(next);
+                next += 1;
+            }
+            if burst % 2 == 0 {
+                assert_eq!(ior.kick().unwrap(), burst);
+            }
+            ior.pump(|cqe| done.push(cqe.user_data())).unwrap();
+        }
+        while done.len() < next as usize {
+            ior.wait_only(1).unwrap();
+            ior.pump(|cqe| done.push(cqe.user_data())).unwrap();
+        }
+        assert_eq!(done, (0..next).collect::<Vec<_>>());
+        assert_eq!(ior.kick().unwrap(), 0);
+    }
+
+    #[test]
+    fn params() {
+        let ior = crate::io_uring::IoUring::init(100).unwrap();
+        let p = ior.params();
+        assert_eq!(p.sq_entries(), ior.sq_entries());
+        assert_eq!(p.cq_entries(), ior.cq_entries());
+        assert_ne!(p.features(), 0);
+
+        // head is at offset 0 of both rings, but all the other fields follow it
+        let sq_off = p.sq_off();
+        assert!(sq_off.tail > 0 && sq_off.ring_mask > 0 && sq_off.ring_entries > 0);
+        assert!(sq_off.flags > 0 && sq_off.dropped > 0 && sq_off.array > 0);
+        let cq_off = p.cq_off();
+        assert!(cq_off.tail > 0 && cq_off.ring_mask > 0 && cq_off.ring_entries > 0);
+        assert!(cq_off.overflow > 0 && cq_off.cqes > 0);
+
+        // identical rings get identical parameters
+        let ior2 = crate::io_uring::IoUring::init(100).unwrap();
+        assert_eq!(format!("{:?}", ior2.params()), format!("{:?}", p));
+    }
+
+    #[test]
+    fn wait_cqe_timeout() {
+        use std::time::{Duration, Instant};
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (rfd, wfd) = pipe();
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_poll_add(rfd, libc::POLLIN as u16);
+        sqe.set_data(7);
+        assert_eq!(ior.submit().unwrap(), 1);
+
+        // nothing happens
+        let start = Instant::now();
+        assert!(ior.wait_cqe_timeout(Some(Duration::from_millis(20))).unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // completion triggered from another thread
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            assert_eq!(unsafe { libc::write(wfd, b"x".as_ptr() as _, 1) }, 1);
+            wfd
+        });
+        let start = Instant::now();
+        let cqe = ior.wait_cqe_timeout(Some(Duration::from_secs(10))).unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(cqe.user_data(), 7);
+        assert_eq!(cqe.res(), libc::POLLIN as i32);
+
+        let wfd = writer.join().unwrap();
+        unsafe {
+            libc::close(rfd);
+            libc::close(wfd);
+        }
+    }
+
+    #[test]
+    fn fixed_buffer_pool() {
+        use crate::io_uring::FixedBufferPool;
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (path, file) = tmpfile("fixed-buffer-pool");
+        let fd = file.as_raw_fd();
+        {
+            let pool = FixedBufferPool::new(&mut ior, 4096, 4).unwrap();
+            let mut wbuf = pool.acquire().unwrap();
+            assert_eq!((wbuf.index(), wbuf.len()), (0, 4096));
+            assert_eq!(wbuf.as_ptr() as usize % 4096, 0);
+            wbuf[..5].copy_from_slice(b"hello");
+
+            ior.get_sqe().unwrap().prep_write_fixed_buf(fd, &wbuf, 5, 0);
+            ior.submit_and_wait(1).unwrap();
+            assert_eq!(ior.wait_cqe().unwrap().res(), 5);
+
+            let mut rbuf = pool.acquire().unwrap();
+            assert_eq!(rbuf.index(), 1);
+            ior.get_sqe().unwrap().prep_read_fixed_buf(fd, &mut rbuf, 5, 0);
+            ior.submit_and_wait(1).unwrap();
+            assert_eq!(ior.wait_cqe().unwrap().res(), 5);
+            assert_eq!(&rbuf[..5], b"hello");
+
+            // buffers are returned to the pool
+            drop(wbuf);
+            assert_eq!(pool.acquire().unwrap().index(), 0);
+            let bufs: Vec<_> = std::iter::from_fn(|| pool.acquire()).collect();
+            assert_eq!(bufs.iter().map(|b| b.index()).collect::<Vec<_>>(), vec![0, 2, 3]);
+            assert!(pool.acquire().is_none());
+
+            // buffers are registered
+            assert!(FixedBufferPool::new(&mut ior, 4096, 1).is_err());
+        }
+
+        // dropping the pool unregistered the buffers
+        assert!(matches!(
+            ior.unregister_buffers(),
+            Err(crate::io_uring::IoUringError::Register(e)) if e.raw_os_error() == Some(libc::ENXIO)
+        ));
+        assert!(FixedBufferPool::new(&mut ior, 4096, 1).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn opcode_name() {
+        use crate::io_uring::*;
+
+        assert_eq!(opcode_name(IORING_OP_NOP), "NOP");
+        assert_eq!(opcode_name(IORING_OP_READV), "READV");
+        assert_eq!(opcode_name(IORING_OP_WRITEV), "WRITEV");
+        assert_eq!(opcode_name(IORING_OP_POLL_ADD), "POLL_ADD");
+        assert_eq!(opcode_name(IORING_OP_FIXED_FD_INSTALL), "FIXED_FD_INSTALL");
+        assert_eq!(opcode_name(IORING_OP_PIPE), "PIPE");
+        assert_eq!(opcode_name(IORING_OP_PIPE + 1), format!("UNKNOWN({})", IORING_OP_PIPE + 1));
+        assert_eq!(opcode_name(255), "UNKNOWN(255)");
+
+        let err = IoUringError::Unsupported(IORING_OP_URING_CMD);
+        assert_eq!(err.to_string(), "io_uring operation URING_CMD is not supported");
+    }
+
+    #[test]
+    fn submit_readv_owned() {
+        use std::io::Write;
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (path, mut file) = tmpfile("readv-owned");
+        file.write_all(b"hello world").unwrap();
+
+        let fd = file.as_raw_fd();
+        ior.submit_readv_owned(fd, vec![vec![0; 6], vec![0; 5]], 0, 0xdead).unwrap();
+        assert!(matches!(
+            ior.submit_readv_owned(fd, vec![vec![0; 1]], 0, 0xdead),
+            Err(crate::io_uring::IoUringError::InvalidInput(_))
+        ));
+
+        let cqe = ior.wait_cqe().unwrap();
+        assert_eq!((cqe.user_data(), cqe.res()), (0xdead, 11));
+        let bufs = ior.take_owned_iovecs(&cqe).unwrap();
+        assert_eq!(bufs, vec![b"hello ".to_vec(), b"world".to_vec()]);
+        assert!(ior.take_owned_iovecs(&cqe).is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_write_blocking() {
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (path, file) = tmpfile("rw-blocking");
+        std::fs::write(&path, b"hello world").unwrap();
+
+        let fd = file.as_raw_fd();
+        let mut buf = [0u8; 16];
+        assert_eq!(ior.read_blocking(fd, &mut buf, 6).unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+
+        assert_eq!(ior.write_blocking(fd, b"HELLO", 0).unwrap(), 5);
+        assert_eq!(ior.read_blocking(fd, &mut buf, 0).unwrap(), 11);
+        assert_eq!(&buf[..11], b"HELLO world");
+
+        let err = ior.read_blocking(-1, &mut buf, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn link_timeout() {
+        use crate::io_uring::KernelTimespec;
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (rfd, wfd) = pipe();
+        let ts = KernelTimespec::from(std::time::Duration::from_millis(50));
+
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_poll_add(rfd, libc::POLLIN as u16);
+        sqe.set_link();
+        sqe.set_data(1);
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_link_timeout(&ts, 0, crate::io_uring::TimeoutClock::Monotonic);
+        sqe.set_data(2);
+        assert_eq!(ior.submit().unwrap(), 2);
+
+        let start = std::time::Instant::now();
+        let mut res = [0; 2];
+        for _ in 0..2 {
+            let cqe = ior.wait_cqe().unwrap();
+            res[cqe.user_data() as usize - 1] = cqe.res();
+        }
+        assert!(start.elapsed() >= std::time::Duration::from_millis(40));
+        assert_eq!(res[0], -libc::ECANCELED);
+        assert!(res[1] == -libc::ETIME || res[1] == -libc::EALREADY);
+        unsafe { libc::close(rfd) };
+        unsafe { libc::close(wfd) };
     }
 
+    #[test]
+    fn verify_offsets() {
+        let mut ior = crate::io_uring::IoUring::init(8).unwrap();
+        assert!(ior.verify_offsets());
+        let d = ior.dump();
+        assert_eq!(d.sq_mask, d.sq_entries - 1);
+        assert_eq!(d.cq_mask, d.cq_entries - 1);
+
+        ior.get_sqe().unwrap().prep_nop();
+        ior.submit_and_wait(1).unwrap();
+        assert!(ior.verify_offsets());
+    }
+
+    #[test]
+    fn submit_recv_all() {
+        use std::io::Write;
+        use std::os::unix::net::UnixStream;
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (rx, mut tx) = UnixStream::pair().unwrap();
+
+        // the message arrives in two writes
+        let writer = std::thread::spawn(move || {
+            tx.write_all(b"hell").unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            tx.write_all(b"o wo").unwrap();
+            tx
+        });
+        let mut buf = [0u8; 8];
+        let cqe = ior.submit_recv_all(rx.as_raw_fd(), &mut buf, 42).unwrap();
+        assert_eq!((cqe.user_data(), cqe.res()), (42, 8));
+        assert_eq!(&buf, b"hello wo");
+
+        // EOF: partial result
+        let mut tx = writer.join().unwrap();
+        tx.write_all(b"rld").unwrap();
+        drop(tx);
+        let cqe = ior.submit_recv_all(rx.as_raw_fd(), &mut buf, 43).unwrap();
+        assert_eq!(cqe.res(), 3);
+        assert_eq!(&buf[..3], b"rld");
+
+        // an interrupted wait cancels the recv, which would otherwise stay pending
+        extern "C" fn on_signal(_: libc::c_int) {}
+        // NB: without SA_RESTART, so that the signal interrupts io_uring_enter() with EINTR
+        unsafe {
+            let mut sa: libc::sigaction = std::mem::zeroed();
+            sa.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+            assert_eq!(libc::sigaction(libc::SIGUSR1, &sa, std::ptr::null_mut()), 0);
+        }
+        let (rx, mut tx) = UnixStream::pair().unwrap();
+        let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let thread = unsafe { libc::pthread_self() } as usize;
+        let signaller = {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    assert_eq!(unsafe { libc::pthread_kill(thread as libc::pthread_t, libc::SIGUSR1) }, 0);
+                }
+            })
+        };
+        let mut buf = [0u8; 8];
+        let res = ior.submit_recv_all(rx.as_raw_fd(), &mut buf, 44);
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        signaller.join().unwrap();
+        assert!(matches!(res, Err(crate::io_uring::IoUringError::Submit(e)) if e.raw_os_error() == Some(libc::EINTR)));
+        assert_eq!((ior.sq_ready(), ior.cq_ready(), ior.in_flight()), (0, 0, 0));
+        tx.write_all(b"too late").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert_eq!(buf, [0; 8]);
+        let mut rx = rx;
+        let mut late = [0u8; 8];
+        std::io::Read::read_exact(&mut rx, &mut late).unwrap();
+        assert_eq!(&late, b"too late");
+    }
+
+    #[test]
+    fn wait_cqe_timeout_no_alloc() {
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let timeout = Some(std::time::Duration::from_micros(100));
+        assert!(ior.wait_cqe_timeout(timeout).unwrap().is_none());
+
+        let before = allocations();
+        for _ in 0..100 {
+            assert!(ior.wait_cqe_timeout(timeout).unwrap().is_none());
+        }
+        assert_eq!(allocations(), before);
+    }
 
+    #[test]
+    fn shutdown() {
+        let fstat = |fd: libc::c_int| {
+            let mut st: libc::stat = unsafe { std::mem::zeroed() };
+            match unsafe { libc::fstat(fd, &mut st) } {
+                0 => Some((st.st_dev, st.st_ino)),
+                _ => None,
+            }
+        };
+
+        let mut ior = crate::io_uring::IoUring::init(8).unwrap();
+        let pipes: Vec<_> = (0..4).map(|_| pipe()).collect();
+        for (i, (rfd, _)) in pipes.iter().enumerate() {
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_poll_add(*rfd, libc::POLLIN as u16);
+            sqe.set_data(i as u64);
+        }
+        assert_eq!(ior.submit().unwrap(), 4);
+        // queued, but never submitted
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!(ior.in_flight(), 4);
+
+        let ring_fd = ior.dump().fd;
+        let ring_file = fstat(ring_fd).unwrap();
+        ior.shutdown().unwrap();
+        // NB: another test may have reused the fd number, but not for the same file
+        assert_ne!(fstat(ring_fd), Some(ring_file));
+
+        for (rfd, wfd) in pipes {
+            unsafe {
+                libc::close(rfd);
+                libc::close(wfd);
+            }
+        }
+    }
+
+    #[test]
+    fn user_data_generations() {
+        use crate::io_uring::{Generations, UserData};
+
+        let data = UserData(UserData::new(0xabcd, 7, 0x1234_5678));
+        assert_eq!((data.tag(), data.gen(), data.index()), (0xabcd, 7, 0x1234_5678));
+        assert_eq!(UserData::from(u64::MAX).gen(), u16::MAX);
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let mut gens = Generations::new(2);
+        for index in 0..2 {
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_nop();
+            sqe.set_data(gens.user_data(1, index));
+        }
+        ior.submit_and_wait(2).unwrap();
+
+        // index 0 was reused before its completion was reaped
+        assert_eq!(gens.bump(0), 1);
+        let mut seen = vec![];
+        let nr = ior.for_each_current_completion(&gens, |_, data| seen.push(data));
+        assert_eq!(nr, 1);
+        assert_eq!(seen, vec![UserData(UserData::new(1, 0, 1))]);
+        assert_eq!(ior.cq_ready(), 0);
+        assert!(gens.is_stale(UserData(UserData::new(1, 0, 0))));
+        assert!(gens.is_stale(UserData(UserData::new(1, 0, 2))));
+    }
+
+    #[test]
+    fn fixed_at_offset() {
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (path, file) = tmpfile("fixed-at-offset");
+        let fd = file.as_raw_fd();
+
+        let mut buf = vec![0u8; 4096];
+        buf[..6].copy_from_slice(b"hello ");
+        buf[2048..2053].copy_from_slice(b"world");
+        let iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+        ior.register_buffers(&[iov]).unwrap();
+
+        // two concurrent writes from non-overlapping ranges of the buffer
+        ior.prep_write_fixed_at(fd, 0, 0, 6, 0).unwrap().set_data(1);
+        ior.prep_write_fixed_at(fd, 0, 2048, 5, 6).unwrap().set_data(2);
+        assert_eq!(ior.submit_and_wait(2).unwrap(), 2);
+        let mut res = vec![];
+        ior.for_each_completion(|cqe| res.push((cqe.user_data(), cqe.res())));
+        res.sort();
+        assert_eq!(res, vec![(1, 6), (2, 5)]);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+
+        ior.prep_read_fixed_at(fd, 0, 1024, 11, 0).unwrap();
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), 11);
+        assert_eq!(&buf[1024..1035], b"hello world");
+
+        // out of range
+        use crate::io_uring::IoUringError;
+        assert!(matches!(ior.prep_write_fixed_at(fd, 0, 4090, 7, 0), Err(IoUringError::InvalidInput(_))));
+        assert!(matches!(ior.prep_write_fixed_at(fd, 0, usize::MAX, 1, 0), Err(IoUringError::InvalidInput(_))));
+        assert!(matches!(ior.prep_write_fixed_at(fd, 1, 0, 1, 0), Err(IoUringError::InvalidInput(_))));
+        assert_eq!(ior.sq_ready(), 0);
+
+        // no free sqe
+        while ior.get_sqe().is_some() {}
+        assert!(matches!(ior.prep_read_fixed_at(fd, 0, 0, 1, 0), Err(IoUringError::SqFull)));
+        ior.submit_and_wait(ior.sq_ready()).unwrap();
+        ior.for_each_completion(|_| ());
+
+        ior.unregister_buffers().unwrap();
+        assert!(ior.prep_write_fixed_at(fd, 0, 0, 1, 0).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn splice() {
+        use crate::io_uring::SPLICE_OFF_NONE;
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (path, file) = tmpfile("splice");
+        let (rfd, wfd) = pipe();
+        assert_eq!(unsafe { libc::write(wfd, b"hello".as_ptr() as *const libc::c_void, 5) }, 5);
+
+        ior.get_sqe().unwrap().prep_splice(rfd, SPLICE_OFF_NONE, file.as_raw_fd(), 3, 5, 0);
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), 5);
+        assert_eq!(std::fs::read(&path).unwrap(), b"\0\0\0hello");
+
+        unsafe {
+            libc::close(rfd);
+            libc::close(wfd);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn restrictions() {
+        use crate::io_uring::{Builder, IoUringError, Restriction, IORING_OP_NOP};
+
+        let mut ior = Builder::new(4).disabled(true).build().unwrap();
+        ior.get_sqe().unwrap().prep_nop();
+        assert!(matches!(ior.submit(), Err(IoUringError::RingDisabled)));
+        assert!(matches!(ior.submit_and_wait(1), Err(IoUringError::RingDisabled)));
+        assert!(ior.submit().unwrap_err().to_string().contains("enable_rings()"));
+        assert!(matches!(ior.wait_only(1), Err(IoUringError::RingDisabled)));
+        assert!(matches!(ior.wait_cqe_timeout(Some(std::time::Duration::from_millis(1))), Err(IoUringError::RingDisabled)));
+        assert_eq!(ior.enter(1, 0, 0).unwrap_err().to_string(), IoUringError::RingDisabled.to_string());
+        ior.split(|_, mut cq| assert!(matches!(cq.wait(1), Err(IoUringError::RingDisabled))));
+
+        ior.register_restrictions(&[Restriction::SqeOp(IORING_OP_NOP)]).unwrap();
+        ior.enable_rings().unwrap();
+        assert!(ior.register_restrictions(&[Restriction::SqeOp(IORING_OP_NOP)]).is_err());
+
+        // the nop is still queued
+        assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+        assert_eq!(ior.wait_cqe().unwrap().res(), 0);
+
+        let f = std::fs::File::open("/dev/zero").unwrap();
+        let mut buf = [0u8; 8];
+        ior.get_sqe().unwrap().prep_read(f.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, 8, 0);
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), -libc::EACCES);
+    }
+
+    #[test]
+    fn dispatch() {
+        use crate::io_uring::UserData;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut ior = crate::io_uring::IoUring::init(8).unwrap();
+        let seen = Rc::new(RefCell::new(vec![]));
+        for tag in 1..=2 {
+            let seen = seen.clone();
+            ior.on_completion(tag, Box::new(move |cqe| {
+                seen.borrow_mut().push((tag, UserData(cqe.user_data()).index()));
+            }));
+        }
+
+        // tag 3 has no handler
+        for (tag, index) in [(1, 10), (2, 20), (1, 11), (3, 30)] {
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_nop();
+            sqe.set_data(UserData::new(tag, 0, index));
+        }
+        ior.submit_and_wait(4).unwrap();
+        assert_eq!(ior.dispatch(), 3);
+        assert_eq!(ior.cq_ready(), 0);
+
+        let mut seen = seen.borrow().clone();
+        seen.sort();
+        assert_eq!(seen, vec![(1, 10), (1, 11), (2, 20)]);
+    }
+
+    #[test]
+    fn large_offsets() {
+        use std::os::unix::fs::FileExt;
+
+        const OFF: u64 = 5 << 30; // 5GiB
+        let (path, file) = tmpfile("large-offsets");
+        // NB: this needs a filesystem with sparse files
+        if file.set_len(OFF + 4096).is_err() {
+            std::fs::remove_file(path).unwrap();
+            return;
+        }
+        file.write_all_at(b"far away", OFF).unwrap();
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let fd = file.as_raw_fd();
+        let mut buf = [0u8; 8];
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_read(fd, buf.as_mut_ptr() as *mut libc::c_void, 8, OFF);
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), 8);
+        assert_eq!(&buf, b"far away");
+
+        assert_eq!(ior.write_blocking(fd, b"further", OFF + 1024).unwrap(), 7);
+        let mut buf = [0u8; 7];
+        assert_eq!(ior.read_blocking(fd, &mut buf, OFF + 1024).unwrap(), 7);
+        assert_eq!(&buf, b"further");
+        assert_eq!(ior.submit_read_full(fd, &mut buf, OFF + 1024, 0).unwrap().res(), 7);
+
+        let mut buf = [0u8; 8];
+        file.read_exact_at(&mut buf, OFF + 1024).unwrap();
+        assert_eq!(&buf, b"further\0");
+
+        ior.get_sqe().unwrap().prep_sync_file_range(fd, OFF, 4096, libc::SYNC_FILE_RANGE_WRITE);
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn bench_roundtrip() {
+        let mut ior = crate::io_uring::IoUring::init(8).unwrap();
+        let head = ior.dump().cq_head;
+        // not a multiple of the batch size
+        ior.bench_roundtrip(1001).unwrap();
+        let d = ior.dump();
+        assert_eq!(d.cq_head.wrapping_sub(head), 1001);
+        assert_eq!(d.cq_tail, d.cq_head);
+        assert_eq!(ior.in_flight(), 0);
+
+        ior.get_sqe().unwrap().prep_nop();
+        assert!(ior.bench_roundtrip(1).is_err());
+    }
+
+    #[test]
+    fn set_nonblocking() {
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        assert!(!ior.is_nonblocking().unwrap());
+        ior.set_nonblocking(true).unwrap();
+        assert!(ior.is_nonblocking().unwrap());
+
+        // a GETEVENTS enter that does not wait returns immediately
+        let start = std::time::Instant::now();
+        assert_eq!(ior.wait_only(0).unwrap(), 0);
+        assert!(!ior.is_readable().unwrap());
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+
+        // the ring still works
+        ior.get_sqe().unwrap().prep_nop();
+        ior.submit_and_wait(1).unwrap();
+        assert!(ior.is_readable().unwrap());
+        assert_eq!(ior.wait_cqe().unwrap().res(), 0);
+
+        ior.set_nonblocking(false).unwrap();
+        assert!(!ior.is_nonblocking().unwrap());
+    }
+
+    #[test]
+    fn iovecs() {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (path, mut file) = tmpfile("iovecs");
+        let fd = file.as_raw_fd();
+
+        let bufs: [&[u8]; 3] = [b"hello", b", ", b"world"];
+        let iovecs = crate::io_uring::IoVecs::new(&bufs);
+        assert_eq!((iovecs.len(), iovecs.total_len()), (3, 12));
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_writev_iovecs(fd, &iovecs, 0);
+        sqe.set_data(1);
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), 12);
+        drop(iovecs);
+
+        // owned writev, appended to the above
+        ior.submit_writev_owned(fd, vec![b"!".to_vec(), b"\n".to_vec()], 12, 2).unwrap();
+        ior.wait_only(1).unwrap();
+        let cqe = ior.wait_cqe().unwrap();
+        assert_eq!((cqe.user_data(), cqe.res()), (2, 2));
+        assert_eq!(ior.take_owned_iovecs(&cqe).unwrap(), vec![b"!".to_vec(), b"\n".to_vec()]);
+
+        let mut out = String::new();
+        file.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello, world!\n");
+
+        // read it back with mutable iovecs
+        let (mut a, mut b) = ([0u8; 7], [0u8; 7]);
+        let mut bufs: [&mut [u8]; 2] = [&mut a, &mut b];
+        let mut iovecs = crate::io_uring::IoVecs::new_mut(&mut bufs);
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_readv_iovecs(fd, &mut iovecs, 0);
+        sqe.set_data(3);
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), 14);
+        drop(iovecs);
+        assert_eq!((&a, &b), (b"hello, ", b"world!\n"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn flush_sq() {
+        // without SQPOLL, flushed sqes are submitted by the next submit()
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!(ior.flush_sq(), 1);
+        assert_eq!(ior.flush_sq(), 0);
+        assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+        assert_eq!(ior.wait_cqe().unwrap().res(), 0);
+
+        // with SQPOLL, the (awake) poll thread picks them up without a system call
+        let mut ior = match crate::io_uring::Builder::new(8).sqpoll(2000).build() {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("skipping flush_sq SQPOLL test: {}", e);
+                return;
+            }
+        };
+        for i in 0..4 {
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_nop();
+            sqe.set_data(i);
+        }
+        assert_eq!(ior.flush_sq(), 4);
+        assert_eq!(ior.in_flight(), 4);
+
+        let mut nr = 0;
+        for _ in 0..1000 {
+            nr += ior.for_each_completion(|cqe| assert_eq!(cqe.res(), 0));
+            if nr == 4 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(nr, 4);
+        assert_eq!(ior.in_flight(), 0);
+    }
+
+    #[test]
+    fn sqpoll_idle_ms() {
+        use crate::io_uring::{Builder, IoUring};
+
+        assert_eq!(IoUring::init(4).unwrap().sqpoll_idle_ms(), 0);
+        for &(idle, expected) in &[(50, 50), (0, 1000)] {
+            let ior = match Builder::new(4).sqpoll(idle).build() {
+                Ok(x) => x,
+                Err(e) => {
+                    eprintln!("skipping sqpoll_idle_ms test: {}", e);
+                    return;
+                }
+            };
+            assert_eq!(ior.sq_thread_idle(), idle);
+            assert_eq!(ior.sqpoll_idle_ms(), expected);
+        }
+    }
+
+    #[test]
+    fn recv_bundle() {
+        use crate::io_uring::{BufRing, IoUring};
+
+        let mut ior = IoUring::init(4).unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) }, 0);
+        const BUF_SIZE: usize = 4096;
+        let br = BufRing::new(&mut ior, 7, BUF_SIZE, 8).unwrap();
+
+        let payload: Vec<u8> = (0..5 * BUF_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let ret = unsafe { libc::write(fds[1], payload.as_ptr() as *const libc::c_void, payload.len()) };
+        assert_eq!(ret as usize, payload.len());
+
+        let mut received = vec![];
+        let mut max_bufs = 0;
+        while received.len() < payload.len() {
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_recv_bundle(fds[0], br.bgid());
+            ior.submit_and_wait(1).unwrap();
+            let cqe = ior.wait_cqe().unwrap();
+            assert!(cqe.res() > 0, "recv failed: {}", cqe.res());
+            let mut left = cqe.res() as usize;
+            let mut bid = cqe.buffer_id().unwrap();
+            let nbufs = left.div_ceil(BUF_SIZE);
+            max_bufs = std::cmp::max(max_bufs, nbufs);
+            for _ in 0..nbufs {
+                let n = std::cmp::min(left, BUF_SIZE);
+                // NB: the cqe reported the buffer, and it is copied out before being recycled
+                received.extend_from_slice(unsafe { &br.buf(bid)[..n] });
+                br.recycle(bid);
+                left -= n;
+                bid = (bid + 1) % br.entries();
+            }
+        }
+        assert_eq!(received, payload);
+        // the data was queued before the recv, so a single cqe covered multiple buffers
+        assert!(max_bufs > 1);
+
+        drop(br);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn sockaddr() {
+        use crate::io_uring::SockAddr;
+        use std::os::unix::io::AsRawFd;
+
+        let v6: std::net::SocketAddr = "[::1]:8080".parse().unwrap();
+        let sa = SockAddr::from(v6);
+        assert_eq!(sa.len() as usize, std::mem::size_of::<libc::sockaddr_in6>());
+        assert_eq!(sa.to_socket_addr(), Some(v6));
+        assert!(SockAddr::empty().to_socket_addr().is_none());
+
+        // connect and accept over loopback through the ring
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = SockAddr::from(listener.local_addr().unwrap());
+        let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        assert!(sock >= 0);
+
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_connect_addr(sock, &addr);
+        sqe.set_data(1);
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), 0);
+
+        let mut peer = SockAddr::empty();
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_accept_addr(listener.as_raw_fd(), &mut peer, 0);
+        sqe.set_data(2);
+        ior.submit_and_wait(1).unwrap();
+        let fd = ior.wait_cqe().unwrap().res();
+        assert!(fd >= 0);
+
+        let mut local = SockAddr::empty();
+        let mut len = local.len();
+        assert_eq!(unsafe { libc::getsockname(sock, local.as_mut_ptr(), &mut len) }, 0);
+        assert_eq!(len, peer.len());
+        assert_eq!(peer.to_socket_addr(), local.to_socket_addr());
+        unsafe {
+            libc::close(fd);
+            libc::close(sock);
+        }
+    }
+
+    #[test]
+    fn acceptor() {
+        use std::io::{Read, Write};
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cq_entries = ior.cq_entries();
+
+        let mut acceptor = crate::io_uring::Acceptor::new(&mut ior, listener.as_raw_fd(), 0xacce).unwrap();
+        for i in 0..4u8 {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client.write_all(&[i]).unwrap();
+            let fd = acceptor.next_conn().unwrap();
+            let mut conn = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+            let mut buf = [0u8; 1];
+            conn.read_exact(&mut buf).unwrap();
+            assert_eq!(buf[0], i);
+        }
+
+        // overflowing the CQ terminates the multishot accept, which is re-armed
+        let clients: Vec<_> = (0..2 * cq_entries).map(|_| std::net::TcpStream::connect(addr).unwrap()).collect();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        for _ in 0..clients.len() {
+            let fd = acceptor.next_conn().unwrap();
+            unsafe { libc::close(fd) };
+        }
+        drop(acceptor);
+
+        // the accept was cancelled, and its cqes reaped
+        assert_eq!(ior.cq_ready(), 0);
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+        assert!(ior.wait_only(0).is_ok());
+        assert_eq!(ior.cq_ready(), 0);
+    }
+
+    #[test]
+    fn iowq_workers() {
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (bounded, unbounded) = ior.iowq_max_workers(0, 0).unwrap();
+        assert!(bounded > 0 && unbounded > 0);
+        assert_eq!(ior.iowq_max_workers(4, 0).unwrap(), (bounded, unbounded));
+        assert_eq!(ior.iowq_max_workers(0, 0).unwrap(), (4, unbounded));
+
+        // a request forced to a worker leaves the worker around for a while
+        ior.prime_workers().unwrap();
+        assert!(ior.iowq_workers().unwrap() > 0);
+
+        // only the workers of the calling thread are counted
+        std::thread::spawn(|| {
+            let ior = crate::io_uring::IoUring::init(4).unwrap();
+            assert_eq!(ior.iowq_workers().unwrap(), 0);
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn autosubmit() {
+        let mut ior = crate::io_uring::IoUring::init(8).unwrap();
+        ior.set_autosubmit(4);
+        for i in 0..2 * ior.sq_entries() {
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_nop();
+            sqe.set_data(i.into());
+            assert!(ior.sq_ready() <= 4);
+        }
+        // all but the last batch were submitted implicitly
+        assert_eq!((ior.in_flight(), ior.sq_ready()), (12, 4));
+        ior.submit_and_wait(4).unwrap();
+        let mut nr = 0;
+        while nr < 16 {
+            nr += ior.for_each_completion(|cqe| assert_eq!(cqe.res(), 0));
+            if nr < 16 {
+                ior.wait_only(1).unwrap();
+            }
+        }
+
+        // disabled: the SQ fills up
+        ior.set_autosubmit(0);
+        for _ in 0..ior.sq_entries() {
+            ior.get_sqe().unwrap().prep_nop();
+        }
+        assert!(ior.get_sqe().is_none());
+        assert_eq!(ior.in_flight(), 0);
+    }
+
+    #[test]
+    fn registered_files() {
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (rfd, wfd) = pipe();
+        assert!(ior.registered_files().is_empty());
+
+        ior.register_files(&[-1, rfd, -1, -1]).unwrap();
+        assert_eq!(ior.registered_files(), &[-1, rfd, -1, -1]);
+        assert_eq!(ior.update_files(2, &[wfd]).unwrap(), 1);
+        assert_eq!(ior.update_files(1, &[-1]).unwrap(), 1);
+        assert_eq!(ior.registered_files(), &[-1, -1, wfd, -1]);
+        let free: Vec<usize> = (0..4).filter(|i| ior.registered_files()[*i] == -1).collect();
+        assert_eq!(free, vec![0, 1, 3]);
+
+        // the fixed file at index 2 is the pipe write end
+        if ior.check_op(crate::io_uring::IORING_OP_FIXED_FD_INSTALL).is_ok() {
+            ior.get_sqe().unwrap().prep_fixed_fd_install(2, 0);
+            ior.submit_and_wait(1).unwrap();
+            let fd = ior.wait_cqe().unwrap().res();
+            assert!(fd >= 0);
+            assert_eq!(unsafe { libc::write(fd, b"fixed".as_ptr() as _, 5) }, 5);
+            let mut buf = [0u8; 5];
+            assert_eq!(unsafe { libc::read(rfd, buf.as_mut_ptr() as _, 5) }, 5);
+            assert_eq!(&buf, b"fixed");
+            unsafe { libc::close(fd) };
+        }
+
+        ior.unregister_files().unwrap();
+        assert!(ior.registered_files().is_empty());
+        unsafe {
+            libc::close(rfd);
+            libc::close(wfd);
+        }
+    }
+
+    #[test]
+    fn submit_with_deadline() {
+        use crate::io_uring::DEADLINE_USER_DATA;
+        use std::time::{Duration, Instant};
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (rfd, wfd) = pipe();
+        let poll = |sqe: &mut crate::io_uring::SQEntry| sqe.prep_poll_add(rfd, libc::POLLIN as u16);
+
+        // nothing to read: the poll is cancelled at the deadline, or (if it has passed) right away
+        for &ms in &[20, 0] {
+            let start = Instant::now();
+            let deadline = start + Duration::from_millis(ms);
+            assert_eq!(ior.submit_with_deadline(poll, deadline, 7).unwrap(), 2);
+            let mut res = [0; 2];
+            for _ in 0..2 {
+                let cqe = ior.wait_cqe().unwrap();
+                match cqe.user_data() {
+                    7 => res[0] = cqe.res(),
+                    DEADLINE_USER_DATA => res[1] = cqe.res(),
+                    x => panic!("unexpected user_data: {}", x),
+                }
+            }
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(ms) && elapsed < Duration::from_secs(1));
+            assert_eq!(res[0], -libc::ECANCELED);
+            assert!(res[1] == -libc::ETIME || res[1] == -libc::EALREADY);
+        }
+
+        // the ring's own user_data cannot be used for the operation
+        let deadline = Instant::now() + Duration::from_secs(10);
+        for &reserved in &[DEADLINE_USER_DATA, crate::io_uring::CANCEL_USER_DATA] {
+            assert!(matches!(
+                ior.submit_with_deadline(poll, deadline, reserved),
+                Err(crate::io_uring::IoUringError::InvalidInput(_))
+            ));
+        }
+        assert_eq!(ior.sq_ready(), 0);
+
+        // completing in time cancels the timeout
+        assert_eq!(unsafe { libc::write(wfd, b"x".as_ptr() as _, 1) }, 1);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        ior.submit_with_deadline(poll, deadline, 8).unwrap();
+        ior.wait_only(2).unwrap();
+        let mut cqes = ior.drain_up_to(2);
+        cqes.sort_by_key(|c| c.user_data());
+        assert_eq!((cqes[0].user_data(), cqes[0].res()), (8, libc::POLLIN as i32));
+        assert_eq!(cqes[1].res(), -libc::ECANCELED);
+
+        unsafe {
+            libc::close(rfd);
+            libc::close(wfd);
+        }
+    }
+
+    #[test]
+    fn timeout_clocks() {
+        use crate::io_uring::{KernelTimespec, TimeoutClock, IORING_TIMEOUT_ABS};
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+
+        // an absolute wall-clock deadline, 30ms from now
+        let mut now: libc::timespec = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut now) }, 0);
+        let deadline = now.tv_sec as i64 * 1_000_000_000 + now.tv_nsec as i64 + 30_000_000;
+        let ts = KernelTimespec { tv_sec: deadline / 1_000_000_000, tv_nsec: deadline % 1_000_000_000 };
+
+        let start = std::time::Instant::now();
+        ior.get_sqe().unwrap().prep_timeout(&ts, 0, IORING_TIMEOUT_ABS, TimeoutClock::Realtime);
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), -libc::ETIME);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= std::time::Duration::from_millis(20) && elapsed < std::time::Duration::from_secs(1));
+
+        // a relative boottime timeout
+        let ts = KernelTimespec::from(std::time::Duration::from_millis(1));
+        ior.get_sqe().unwrap().prep_timeout(&ts, 0, 0, TimeoutClock::Boottime);
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), -libc::ETIME);
+    }
+
+    #[test]
+    fn push_raw() {
+        use crate::io_uring::{io_uring_sqe, IORING_OP_NOP};
+
+        let mut ior = crate::io_uring::IoUring::init(2).unwrap();
+        let nop = io_uring_sqe::new(IORING_OP_NOP).with_fd(-1).with_user_data(0x1234);
+        assert_eq!(nop.as_bytes()[0], IORING_OP_NOP);
+        let copy = io_uring_sqe::from_bytes(*nop.as_bytes());
+        assert!(ior.push_raw(nop).is_ok());
+        assert!(ior.push_raw(copy).is_ok());
+
+        // the SQ is full: the sqe is returned
+        let ret = ior.push_raw(io_uring_sqe::new(IORING_OP_NOP).with_user_data(7));
+        assert_eq!(ret.err().map(|s| s.as_bytes()[32]), Some(7));
+
+        assert_eq!(ior.submit_and_wait(2).unwrap(), 2);
+        for cqe in ior.drain_up_to(2) {
+            assert_eq!((cqe.user_data(), cqe.res()), (0x1234, 0));
+        }
+    }
+
+    #[test]
+    fn cq_overflow_pending() {
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let cq_entries = ior.cq_entries();
+        assert!(!ior.cq_overflow_pending());
+
+        // overflow the CQ, without consuming any cqes
+        for _ in 0..(cq_entries + 4) / 4 {
+            for _ in 0..4 {
+                ior.get_sqe().unwrap().prep_nop();
+            }
+            ior.submit().unwrap();
+        }
+        assert_eq!(ior.cq_ready(), cq_entries);
+        assert!(ior.cq_overflow_pending());
+
+        // after making space, a plain submit flushes the overflowed cqes
+        assert_eq!(ior.for_each_completion(|_| {}), cq_entries);
+        ior.get_sqe().unwrap().prep_nop();
+        ior.submit().unwrap();
+        assert!(!ior.cq_overflow_pending());
+        assert!(ior.cq_ready() >= 4);
+    }
+
+    #[test]
+    fn cancel_owned() {
+        use crate::io_uring::CANCEL_USER_DATA;
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (rfd, wfd) = pipe();
+        assert!(ior.cancel_owned(1).is_err());
+        assert!(ior.submit_readv_owned(rfd, vec![vec![0; 16]], 0, CANCEL_USER_DATA).is_err());
+
+        // a read that will never complete, abandoned by its caller
+        ior.submit_readv_owned(rfd, vec![vec![0; 16]], 0, 1).unwrap();
+        ior.cancel_owned(1).unwrap();
+        // the buffers are retained until the read's cqe arrives
+        assert!(ior.owns_iovecs(1));
+
+        let mut read_res = None;
+        for _ in 0..2 {
+            let cqe = ior.wait_cqe().unwrap();
+            match cqe.user_data() {
+                1 => {
+                    read_res = Some(cqe.res());
+                    assert_eq!(ior.take_owned_iovecs(&cqe).unwrap(), vec![vec![0; 16]]);
+                },
+                CANCEL_USER_DATA => assert_eq!(cqe.res(), 0),
+                x => panic!("unexpected user_data: {}", x),
+            }
+        }
+        assert_eq!(read_res, Some(-libc::ECANCELED));
+        assert!(!ior.owns_iovecs(1));
+
+        // data written afterwards is not read into the (released) buffers
+        assert_eq!(unsafe { libc::write(wfd, b"late".as_ptr() as _, 4) }, 4);
+        let mut buf = [0u8; 4];
+        assert_eq!(unsafe { libc::read(rfd, buf.as_mut_ptr() as _, 4) }, 4);
+        assert_eq!(&buf, b"late");
+        unsafe {
+            libc::close(rfd);
+            libc::close(wfd);
+        }
+    }
+
+    #[test]
+    fn poll_result() {
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let (rfd, wfd) = pipe();
+
+        // a write end is writable
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_poll_add(wfd, libc::POLLOUT as u16);
+        sqe.set_data(1);
+        ior.submit_and_wait(1).unwrap();
+        let events = ior.wait_cqe().unwrap().poll_result().unwrap();
+        assert_ne!(events & libc::POLLOUT as u32, 0);
+
+        // a poll on an empty pipe, cancelled
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_poll_add(rfd, libc::POLLIN as u16);
+        sqe.set_data(2);
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_cancel(2);
+        sqe.set_data(3);
+        ior.submit_and_wait(2).unwrap();
+        let cqes = ior.drain_up_to(2);
+        let poll = cqes.iter().find(|c| c.user_data() == 2).unwrap();
+        assert_eq!(poll.poll_result().unwrap_err().raw_os_error(), Some(libc::ECANCELED));
+
+        // data makes the read end readable
+        assert_eq!(unsafe { libc::write(wfd, b"x".as_ptr() as _, 1) }, 1);
+        ior.get_sqe().unwrap().prep_poll_add(rfd, libc::POLLIN as u16);
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().poll_result().unwrap(), libc::POLLIN as u32);
+        unsafe {
+            libc::close(rfd);
+            libc::close(wfd);
+        }
+    }
+
+    #[test]
+    fn cq_size() {
+        let mut ior = crate::io_uring::Builder::new(8).cq_entries(32).build().unwrap();
+        assert_eq!((ior.sq_entries(), ior.cq_entries()), (8, 32));
+        assert!(ior.verify_offsets());
+        assert_eq!(ior.dump().cq_mask, 31);
+
+        // fill the whole CQ, four SQs worth of completions, before reaping any
+        for batch in 0..4 {
+            for i in 0..8 {
+                let mut sqe = ior.get_sqe().unwrap();
+                sqe.prep_nop();
+                sqe.set_data(batch * 8 + i);
+            }
+            assert_eq!(ior.submit_and_wait(8).unwrap(), 8);
+        }
+        assert_eq!(ior.cq_ready(), 32);
+        assert!(!ior.cq_overflow_pending());
+        let cqes = ior.drain_up_to(32);
+        assert_eq!(cqes.iter().map(|c| c.user_data()).collect::<Vec<_>>(), (0..32).collect::<Vec<_>>());
+
+        // non-power-of-two sizes are rounded up, and the CQ cannot be smaller than the SQ
+        let ior = crate::io_uring::Builder::new(8).cq_entries(20).build().unwrap();
+        assert_eq!(ior.cq_entries(), 32);
+        assert!(crate::io_uring::Builder::new(8).cq_entries(4).build().is_err());
+    }
+
+    #[test]
+    fn register_files_iter() {
+        use std::io::Write;
+        use std::os::unix::io::AsRawFd;
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let files: Vec<_> = (0..3).map(|i| {
+            let (path, mut file) = tmpfile(&format!("register_files_iter{}", i));
+            write!(file, "file{}", i).unwrap();
+            (path, file)
+        }).collect();
+
+        ior.register_files_iter(files.iter().map(|(_, f)| f.as_raw_fd())).unwrap();
+        assert_eq!(ior.registered_files().len(), 3);
+        for i in 0..3 {
+            let mut buf = [0u8; 5];
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_read(i, buf.as_mut_ptr() as *mut libc::c_void, 5, 0);
+            sqe.set_fixed_file();
+            ior.submit_and_wait(1).unwrap();
+            assert_eq!(ior.wait_cqe().unwrap().res(), 5);
+            assert_eq!(buf, format!("file{}", i).as_bytes());
+        }
+        ior.unregister_files().unwrap();
+
+        let too_many = (0..=crate::io_uring::IORING_MAX_FIXED_FILES).map(|_| -1);
+        assert!(matches!(
+            ior.register_files_iter(too_many),
+            Err(crate::io_uring::IoUringError::InvalidInput(_))
+        ));
+
+        for (path, _) in files {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn read_multishot_enobufs() {
+        use crate::io_uring::{BufRing, IoUring, MultishotRead};
+
+        let mut ior = IoUring::init(8).unwrap();
+        let (rfd, wfd) = pipe();
+        const BUF_SIZE: usize = 64;
+        let br = BufRing::new(&mut ior, 3, BUF_SIZE, 2).unwrap();
+        let arm = |ior: &mut IoUring| {
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_read_multishot(rfd, br.bgid(), 0);
+            sqe.set_data(0xbeef);
+            ior.submit().unwrap();
+        };
+
+        // more data than the group can hold
+        let payload: Vec<u8> = (0..4 * BUF_SIZE).map(|i| (i % 251) as u8).collect();
+        arm(&mut ior);
+        let ret = unsafe { libc::write(wfd, payload.as_ptr() as *const libc::c_void, payload.len()) };
+        assert_eq!(ret as usize, payload.len());
+
+        // NB: buffers are only recycled when the read terminates, so that the group is exhausted
+        let mut received = vec![];
+        let mut held = vec![];
+        let mut enobufs = 0;
+        let mut armed = true;
+        while received.len() < payload.len() {
+            if !armed {
+                for bid in held.drain(..) {
+                    br.recycle(bid);
+                }
+                arm(&mut ior);
+            }
+            let cqe = ior.wait_cqe().unwrap();
+            assert_eq!(cqe.user_data(), 0xbeef);
+            match cqe.multishot_read() {
+                MultishotRead::Data { bid, len, more } => {
+                    // NB: the cqe reported the buffer, which is only recycled after the copy
+                    received.extend_from_slice(unsafe { &br.buf(bid)[..len] });
+                    held.push(bid);
+                    armed = more;
+                }
+                MultishotRead::NeedBuffers => {
+                    enobufs += 1;
+                    armed = false;
+                }
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+        assert_eq!(received, payload);
+        assert!(enobufs > 0);
+
+        // with buffers available, the read terminates at eof
+        for bid in held.drain(..) {
+            br.recycle(bid);
+        }
+        if !armed {
+            arm(&mut ior);
+        }
+        unsafe { libc::close(wfd) };
+        loop {
+            match ior.wait_cqe().unwrap().multishot_read() {
+                MultishotRead::Eof => break,
+                MultishotRead::NeedBuffers => arm(&mut ior),
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+
+        drop(br);
+        unsafe { libc::close(rfd) };
+    }
+
+    #[test]
+    fn raw_enter() {
+        use crate::io_uring::{IoUring, IORING_ENTER_GETEVENTS};
+
+        let mut ior = IoUring::init(4).unwrap();
+        for i in 0..3 {
+            let mut sqe = ior.get_sqe().unwrap();
+            sqe.prep_nop();
+            sqe.set_data(i);
+        }
+        assert_eq!(ior.enter(3, 2, IORING_ENTER_GETEVENTS).unwrap(), 3);
+        assert!(ior.cq_ready() >= 2);
+        assert_eq!(ior.in_flight(), 3);
+
+        // nothing to submit or wait for
+        assert_eq!(ior.enter(0, 0, 0).unwrap(), 0);
+        let mut data = vec![];
+        while data.len() < 3 {
+            data.push(ior.wait_cqe().unwrap().user_data());
+        }
+        data.sort_unstable();
+        assert_eq!(data, vec![0, 1, 2]);
+        assert_eq!(ior.in_flight(), 0);
+    }
+
+    #[test]
+    fn personality() {
+        use crate::io_uring::IoUring;
+        use std::os::unix::fs::PermissionsExt;
+
+        // NB: needs root, to drop (and regain) privileges
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let (path, _file) = tmpfile("personality");
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let cpath = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+
+        // NB: the raw syscall only changes the credentials of the calling thread, so do
+        // everything in a separate thread to not affect the other tests
+        let open_results = std::thread::spawn(move || {
+            let mut ior = IoUring::init(4).unwrap();
+            let root = ior.register_personality().unwrap();
+            let seteuid = |uid: libc::uid_t| {
+                let ret = unsafe { libc::syscall(libc::SYS_setresuid, -1, uid, -1) };
+                assert_eq!(ret, 0, "setresuid: {}", std::io::Error::last_os_error());
+            };
+
+            seteuid(65534);
+            let mut results = vec![];
+            for personality in [None, Some(root)].iter() {
+                let mut sqe = ior.get_sqe().unwrap();
+                sqe.prep_openat(libc::AT_FDCWD, cpath.as_ptr(), libc::O_RDONLY, 0);
+                sqe.set_personality(*personality);
+                ior.submit_and_wait(1).unwrap();
+                results.push(ior.wait_cqe().unwrap().res());
+            }
+            seteuid(0);
+
+            ior.unregister_personality(root).unwrap();
+            assert!(ior.unregister_personality(root).is_err());
+            results
+        }).join().unwrap();
+
+        assert_eq!(open_results[0], -libc::EACCES);
+        assert!(open_results[1] >= 0, "openat failed: {}", open_results[1]);
+        unsafe { libc::close(open_results[1]) };
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "mio")]
+    fn mio_source() {
+        use mio::{Events, Interest, Poll, Token};
+
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        let mut poll = Poll::new().unwrap();
+        poll.registry().register(&mut ior, Token(7), Interest::READABLE).unwrap();
+
+        // nothing completed yet
+        let mut events = Events::with_capacity(4);
+        poll.poll(&mut events, Some(std::time::Duration::from_millis(10))).unwrap();
+        assert!(events.is_empty());
+
+        let (rfd, wfd) = pipe();
+        let mut buf = [0u8; 4];
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_read(rfd, buf.as_mut_ptr() as *mut libc::c_void, 4, 0);
+        sqe.set_data(42);
+        ior.submit().unwrap();
+        assert_eq!(unsafe { libc::write(wfd, b"ping".as_ptr() as *const libc::c_void, 4) }, 4);
+
+        poll.poll(&mut events, Some(std::time::Duration::from_secs(5))).unwrap();
+        let ev = events.iter().next().expect("no readiness event");
+        assert_eq!(ev.token(), Token(7));
+        assert!(ev.is_readable());
+        {
+            let cqe = ior.next_cqe().unwrap();
+            assert_eq!((cqe.user_data(), cqe.res()), (42, 4));
+        }
+        assert_eq!(&buf, b"ping");
+
+        poll.registry().deregister(&mut ior).unwrap();
+        unsafe {
+            libc::close(rfd);
+            libc::close(wfd);
+        }
+    }
+
+    #[test]
+    fn wait_cqe_n() {
+        use crate::io_uring::{IoUring, KernelTimespec, TimeoutClock};
+
+        extern "C" fn on_signal(_: libc::c_int) {}
+        // NB: without SA_RESTART, so that the signal interrupts io_uring_enter() with EINTR
+        unsafe {
+            let mut sa: libc::sigaction = std::mem::zeroed();
+            sa.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+            assert_eq!(libc::sigaction(libc::SIGUSR1, &sa, std::ptr::null_mut()), 0);
+        }
+
+        // nops complete inline, timeouts later
+        let mut ior = IoUring::init(8).unwrap();
+        let ts = [
+            KernelTimespec::from(std::time::Duration::from_millis(30)),
+            KernelTimespec::from(std::time::Duration::from_millis(60)),
+        ];
+        for i in 0..6 {
+            let mut sqe = ior.get_sqe().unwrap();
+            if i % 3 == 2 {
+                sqe.prep_timeout(&ts[i / 3], 0, 0, TimeoutClock::Monotonic);
+            } else {
+                sqe.prep_nop();
+            }
+            sqe.set_data(i as u64);
+        }
+        assert_eq!(ior.submit().unwrap(), 6);
+
+        let start = std::time::Instant::now();
+        let thread = unsafe { libc::pthread_self() } as usize;
+        let signaller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            assert_eq!(unsafe { libc::pthread_kill(thread as libc::pthread_t, libc::SIGUSR1) }, 0);
+        });
+        let mut data: Vec<u64> = ior.wait_cqe_n(5).unwrap().iter().map(|cqe| cqe.user_data()).collect();
+        signaller.join().unwrap();
+        assert!(start.elapsed() >= std::time::Duration::from_millis(30));
+        data.sort_unstable();
+        assert_eq!(data, vec![0, 1, 2, 3, 4]);
+
+        // the rest
+        assert_eq!(ior.wait_cqe_n(1).unwrap()[0].user_data(), 5);
+        assert_eq!(ior.wait_cqe_n(0).unwrap().len(), 0);
+        assert_eq!(ior.in_flight(), 0);
+    }
+
+    #[test]
+    fn big_sqe_cqe() {
+        use crate::io_uring::{Builder, IORING_OP_URING_CMD, URING_CMD128_MAX};
+
+        let mut ior = Builder::new(4).sqe128(true).cqe32(true).build().unwrap();
+        assert_eq!((ior.sqe_size(), ior.cqe_size()), (128, 32));
+        assert!(ior.verify_offsets());
+
+        // go around the rings a few times, so that every slot is used with the doubled stride
+        for round in 0..3u64 {
+            for i in 0..4 {
+                let mut sqe = ior.get_sqe().unwrap();
+                sqe.prep_nop();
+                sqe.set_data(round * 4 + i);
+            }
+            assert!(ior.get_sqe().is_none());
+            ior.submit_and_wait(4).unwrap();
+            for i in 0..4 {
+                let cqe = ior.next_cqe().unwrap();
+                assert_eq!((cqe.user_data(), cqe.res()), (round * 4 + i, 0));
+                assert_eq!(cqe.big_cqe(), Some([0, 0]));
+            }
+        }
+        assert!(ior.next_cqe().is_none());
+
+        // the command area covers the second half of the sqe
+        let (_path, file) = tmpfile("big-sqe");
+        let cmd: Vec<u8> = (0..URING_CMD128_MAX as u8).collect();
+        let mut sqe = ior.get_sqe().unwrap();
+        sqe.prep_uring_cmd(std::os::unix::io::AsRawFd::as_raw_fd(&file), 0, &cmd);
+        sqe.set_data(IORING_OP_URING_CMD as u64);
+        ior.submit_and_wait(1).unwrap();
+        // NB: regular files do not support uring_cmd
+        let cqe = ior.wait_cqe().unwrap();
+        assert_eq!(cqe.user_data(), IORING_OP_URING_CMD as u64);
+        assert!(cqe.res() < 0);
+
+        // 16-byte cqes have no extra data
+        let mut ior = crate::io_uring::IoUring::init(4).unwrap();
+        assert_eq!((ior.sqe_size(), ior.cqe_size()), (64, 16));
+        ior.get_sqe().unwrap().prep_nop();
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.next_cqe().unwrap().big_cqe(), None);
+    }
+
+    #[test]
+    fn split_ring() {
+        use crate::io_uring::IoUring;
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        const NR: u32 = 1000;
+        let mut ior = IoUring::init(64).unwrap();
+        let cq_entries = ior.cq_entries();
+        let consumed = AtomicU32::new(0);
+
+        let data = ior.split(|mut sq, mut cq| {
+            std::thread::scope(|s| {
+                let consumer = s.spawn(|| {
+                    let mut data = vec![];
+                    while data.len() < NR as usize {
+                        cq.wait(1).unwrap();
+                        cq.for_each(|cqe| {
+                            assert_eq!(cqe.res(), 0);
+                            data.push(cqe.user_data());
+                        });
+                        consumed.store(data.len() as u32, Ordering::Release);
+                    }
+                    data
+                });
+
+                // NB: keep at most a CQ worth of requests in flight, so that the CQ never
+                // overflows
+                let mut submitted = 0;
+                while submitted < NR {
+                    let mut queued = 0;
+                    while submitted + queued < NR
+                        && submitted + queued - consumed.load(Ordering::Acquire) < cq_entries {
+                        let mut sqe = match sq.get_sqe() {
+                            Some(sqe) => sqe,
+                            None => break,
+                        };
+                        sqe.prep_nop();
+                        sqe.set_data((submitted + queued) as u64);
+                        queued += 1;
+                    }
+                    if queued == 0 {
+                        std::thread::yield_now();
+                        continue;
+                    }
+                    assert_eq!(sq.submit().unwrap(), queued);
+                    submitted += queued;
+                }
+                consumer.join().unwrap()
+            })
+        });
+
+        assert_eq!(data, (0..NR as u64).collect::<Vec<_>>());
+        assert_eq!(ior.in_flight(), 0);
+        assert_eq!(ior.cq_ready(), 0);
+
+        // the ring is usable as a whole again
+        ior.get_sqe().unwrap().prep_nop();
+        ior.submit_and_wait(1).unwrap();
+        assert_eq!(ior.wait_cqe().unwrap().res(), 0);
+    }
+
+    #[test]
+    fn register_supported() {
+        use crate::io_uring::{IoUring, IORING_REGISTER_FILES, IORING_REGISTER_PBUF_RING, IORING_REGISTER_PROBE};
+
+        let ior = IoUring::init(4).unwrap();
+        assert!(ior.register_supported(IORING_REGISTER_FILES));
+        assert!(ior.register_supported(IORING_REGISTER_PROBE));
+        // 5.19+, used by BufRing
+        assert!(ior.register_supported(IORING_REGISTER_PBUF_RING));
+        assert!(!ior.register_supported(250));
+        assert!(!ior.register_supported(1 << 16));
+    }
+
+    #[test]
+    fn builder_syscalls() {
+        use crate::io_uring::{io_uring_params, Builder, IoUringError, LibcSyscalls, Syscalls};
+
+        // the actual system calls, except that io_uring_register() fails with EPERM
+        struct NoRegister;
+        unsafe impl Syscalls for NoRegister {
+            unsafe fn setup(&self, entries: libc::c_uint, params: *mut io_uring_params) -> libc::c_int {
+                LibcSyscalls.setup(entries, params)
+            }
+            unsafe fn enter(&self, fd: libc::c_int, to_submit: libc::c_uint, min_complete: libc::c_uint,
+                            flags: libc::c_uint, arg: *const libc::c_void, argsz: libc::size_t) -> libc::c_long {
+                LibcSyscalls.enter(fd, to_submit, min_complete, flags, arg, argsz)
+            }
+            unsafe fn register(&self, _fd: libc::c_int, _opcode: libc::c_uint, _arg: *mut libc::c_void,
+                               _nr_args: libc::c_uint) -> libc::c_long {
+                *libc::__errno_location() = libc::EPERM;
+                -1
+            }
+            unsafe fn mmap(&self, len: libc::size_t, fd: libc::c_int, off: libc::off_t, locked: bool) -> *mut libc::c_void {
+                LibcSyscalls.mmap(len, fd, off, locked)
+            }
+            unsafe fn munmap(&self, addr: *mut libc::c_void, len: libc::size_t) -> libc::c_int {
+                LibcSyscalls.munmap(addr, len)
+            }
+        }
+
+        let mut ior = Builder::new(4).syscalls(std::sync::Arc::new(NoRegister)).build().unwrap();
+        ior.get_sqe().unwrap().prep_nop();
+        assert_eq!(ior.submit_and_wait(1).unwrap(), 1);
+        assert_eq!(ior.wait_cqe().unwrap().res(), 0);
+        match ior.register_files(&[0]) {
+            Err(IoUringError::Register(e)) => assert_eq!(e.raw_os_error(), Some(libc::EPERM)),
+            r => panic!("unexpected result: {:?}", r.map(|_| ())),
+        }
+    }
 }
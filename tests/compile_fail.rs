@@ -0,0 +1,15 @@
+/*
+ * Kornilios Kourtis <kkourt@kkourt.io>
+ *
+ * vim: set expandtab softtabstop=4 tabstop=4 shiftwidth=4:
+ */
+
+// Programs that must not compile, with their expected errors in tests/ui/*.stderr
+//
+// Regenerate the .stderr files with: TRYBUILD=overwrite cargo test --test compile_fail
+
+#[test]
+fn compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,8 @@
+// An sqe cannot be accessed after it is submitted: SQEntry borrows the ring mutably
+
+fn main() {
+    let mut ior = iouring::io_uring::IoUring::init(4).unwrap();
+    let mut sqe = ior.get_sqe().unwrap();
+    ior.submit().unwrap();
+    sqe.prep_nop();
+}